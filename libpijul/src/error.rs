@@ -35,7 +35,12 @@ pub enum Error{
     NothingToDecode(Option<PathBuf>),
     InternalHashNotFound(Vec<u8>),
     PatchNotFound(PathBuf,String),
-    GPG(i32,String)
+    GPG(i32,String),
+    GPGNotFound,
+    SchemaTooNew(u32),
+    SchemaNeedsMigration(u32),
+    SchemaCorrupted,
+    UnknownPatchFormat(String)
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -48,7 +53,12 @@ impl fmt::Display for Error {
             Error::FileNotInRepo(ref path) => write!(f, "File {} not tracked", path.display()),
             Error::InternalHashNotFound(ref hash) => write!(f, "Internal hash {} not found", hash.to_hex()),
             Error::PatchNotFound(ref path,ref hash) => write!(f, "Patch {} not found in {}", hash, path.display()),
-            Error::GPG(ref code,ref s) => write!(f, "GPG returned code {:?}, {:?}", code, s)
+            Error::GPG(ref code,ref s) => write!(f, "GPG returned code {:?}, {:?}", code, s),
+            Error::GPGNotFound => write!(f, "Signed patches cannot be read without gpg, which could not be run"),
+            Error::SchemaTooNew(ref v) => write!(f, "Pristine format version {} is newer than this version of pijul (which supports up to version {}), please upgrade pijul", v, ::schema::SCHEMA_VERSION),
+            Error::SchemaNeedsMigration(ref v) => write!(f, "Pristine format version {} must be upgraded, by a command that writes to the repository", v),
+            Error::SchemaCorrupted => write!(f, "Pristine format version is unreadable"),
+            Error::UnknownPatchFormat(ref ext) => write!(f, "Unknown patch file format {:?}", ext)
         }
     }
 }
@@ -64,7 +74,12 @@ impl std::error::Error for Error {
             Error::FileNotInRepo(_) => "Operation on untracked file",
             Error::InternalHashNotFound(_) => "Internal hash not found",
             Error::PatchNotFound(_,_) => "Patch not found",
-            Error::GPG(_,_) => "GPG was unsuccessful"
+            Error::GPG(_,_) => "GPG was unsuccessful",
+            Error::GPGNotFound => "GPG not found",
+            Error::SchemaTooNew(_) => "Pristine format too recent",
+            Error::SchemaNeedsMigration(_) => "Pristine format needs migration",
+            Error::SchemaCorrupted => "Pristine format version unreadable",
+            Error::UnknownPatchFormat(_) => "Unknown patch file format"
         }
     }
 
@@ -78,7 +93,12 @@ impl std::error::Error for Error {
             Error::FileNotInRepo(_) => None,
             Error::InternalHashNotFound(_) => None,
             Error::PatchNotFound(_,_) => None,
-            Error::GPG(_,_) => None
+            Error::GPG(_,_) => None,
+            Error::GPGNotFound => None,
+            Error::SchemaTooNew(_) => None,
+            Error::SchemaNeedsMigration(_) => None,
+            Error::SchemaCorrupted => None,
+            Error::UnknownPatchFormat(_) => None
         }
     }
 }
@@ -25,7 +25,7 @@
 use std::path::{Path,PathBuf,MAIN_SEPARATOR};
 use std::fs::{metadata,create_dir_all};
 extern crate rustc_serialize;
-use self::rustc_serialize::hex::ToHex;
+use self::rustc_serialize::hex::{ToHex,FromHex};
 use std;
 
 pub const PIJUL_DIR_NAME:&'static str=".pijul";
@@ -52,10 +52,54 @@ pub fn branch_changes_file(p : &Path, b: &[u8]) -> PathBuf {
     p.join(PIJUL_DIR_NAME).join(branch_changes_base_path(b))
 }
 
+/// Lists the branches that have a changes file in the repository
+/// rooted at `p`.
+pub fn list_branches(p : &Path) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut branches=Vec::new();
+    for entry in try!(std::fs::read_dir(repo_dir(p))) {
+        let entry=try!(entry);
+        if let Some(name)=entry.file_name().to_str() {
+            if name.starts_with("changes.") {
+                if let Ok(b)=(&name["changes.".len()..]).from_hex() {
+                    branches.push(b)
+                }
+            }
+        }
+    }
+    Ok(branches)
+}
+
 pub fn meta_file(p : &Path) -> PathBuf {
     p.join(PIJUL_DIR_NAME).join("meta.toml")
 }
 
+/// The journal of the operation in progress, see module `journal`.
+pub fn journal_file(p : &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("journal")
+}
+
+/// The directory of the hooks run before and after recording and
+/// applying patches.
+pub fn hooks_dir(p : &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("hooks")
+}
+
+/// The file marking bare repositories, i.e. repositories with only a
+/// pristine and patches, and no working copy.
+pub fn bare_file(p : &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("bare")
+}
+
+/// Whether the repository rooted at `p` is bare.
+pub fn is_bare(p : &Path) -> bool {
+    metadata(bare_file(p)).is_ok()
+}
+
+/// The advisory lock file, held by commands writing to the repository.
+pub fn lock_file(p : &Path) -> PathBuf {
+    p.join(PIJUL_DIR_NAME).join("lock")
+}
+
 pub fn find_repo_root<'a>(dir : &'a Path) -> Option<PathBuf> {
     let c:Vec<&std::ffi::OsStr>=dir.iter().collect();
     let mut i=c.len();
@@ -90,6 +134,13 @@ pub fn create(dir : &Path) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Create a bare repository in `dir`, see `bare_file`.
+pub fn create_bare(dir : &Path) -> std::io::Result<()> {
+    try!(create(dir));
+    try!(std::fs::File::create(bare_file(dir)));
+    Ok(())
+}
+
 
 pub fn patch_path(root:&Path,h:&[u8])->Option<PathBuf> {
     for p in patch_path_iter(h,MAIN_SEPARATOR) {
@@ -129,6 +180,29 @@ impl<'a> Iterator for PatchPath<'a> {
     }
 }
 
+/// Returns the hash of the patch stored in a file of the patches
+/// directory called `name`, or `None` if `name` is not the name of a
+/// patch file.
+pub fn patch_hash_of_file_name(name:&str)->Option<Vec<u8>> {
+    parse_patch_file_name(name).map(|(h,_)| h)
+}
+
+/// Splits the name of a patch file into the hash of the patch and
+/// the extension giving the file's format.
+pub fn parse_patch_file_name(name:&str)->Option<(Vec<u8>,&'static str)> {
+    for ext in PATCH_EXTENSIONS.iter() {
+        if name.len() > ext.len()+1 && name.ends_with(ext) {
+            let base=&name[0..name.len()-ext.len()-1];
+            if name.as_bytes()[base.len()]==b'.' {
+                if let Ok(h)=base.from_hex() {
+                    return Some((h,*ext))
+                }
+            }
+        }
+    }
+    None
+}
+
 pub fn patch_path_iter<'a>(h:&'a[u8],sep:char)->PatchPath<'a> {
     PatchPath { h:h,i:0,sep:sep }
 }
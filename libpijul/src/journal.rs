@@ -0,0 +1,117 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Journal of the operations that touch several places on the disk.
+//!
+//! Recording or applying a patch writes to the patches directory, to
+//! the pristine, to the changes file and to the working copy, but only
+//! the pristine is updated atomically (when its transaction is
+//! committed). Before the first non-atomic write, these operations
+//! save a `Journal` in `.pijul/journal`, which `Repository::commit`
+//! removes once the transaction has been committed. A journal found
+//! on the disk thus means that an operation was interrupted, and
+//! `Repository::recover` can then use it to finish the operation (if
+//! possible) or cancel it.
+
+use std::path::Path;
+use std::fs::{File,rename,remove_file,metadata};
+use std::io::{BufReader,Write};
+use std::collections::HashSet;
+
+use error::Error;
+use patch::Patch;
+use fs_representation::journal_file;
+
+extern crate cbor;
+extern crate rustc_serialize;
+use self::rustc_serialize::Encodable;
+
+#[derive(Debug,RustcEncodable,RustcDecodable)]
+pub enum Operation {
+    /// Recording a patch. An unfinished record is cancelled, since the
+    /// changes are still in the working copy.
+    Record,
+    /// Applying patches. An unfinished apply is finished, since the
+    /// working copy might already have been partially rewritten.
+    Apply
+}
+
+#[derive(Debug,RustcEncodable,RustcDecodable)]
+pub struct Journal {
+    pub operation:Operation,
+    /// Branch the patches are applied to.
+    pub branch:Vec<u8>,
+    /// Hashes of the patches recorded or applied.
+    pub patches:Vec<Vec<u8>>,
+    /// Patches the repository had, and the patches' source didn't.
+    pub only_local:Vec<Vec<u8>>,
+    /// Changes of the working copy not yet recorded when the
+    /// operation started, to be output again if the operation is
+    /// finished by `Repository::recover`. `None` for records, and in
+    /// bare repositories.
+    pub pending:Option<Patch>
+}
+
+impl Journal {
+    pub fn only_local<'a>(&'a self)->HashSet<&'a [u8]> {
+        self.only_local.iter().map(|x| &x[..]).collect()
+    }
+}
+
+/// Save the journal of an operation that is about to start writing
+/// to the repository rooted at `r`.
+pub fn write(r:&Path, journal:&Journal)->Result<(),Error> {
+    let path=journal_file(r);
+    let tmp=path.with_extension("tmp");
+    {
+        let mut file=try!(File::create(&tmp));
+        {
+            let mut e = cbor::Encoder::from_writer(&mut file);
+            try!(journal.encode(&mut e));
+        }
+        try!(file.flush());
+        try!(file.sync_all());
+    }
+    try!(rename(&tmp,&path));
+    Ok(())
+}
+
+/// Read the journal of the repository rooted at `r`, if there is one.
+pub fn read(r:&Path)->Result<Option<Journal>,Error> {
+    let path=journal_file(r);
+    if metadata(&path).is_err() {
+        return Ok(None)
+    }
+    let file=try!(File::open(&path));
+    let mut d=cbor::Decoder::from_reader(BufReader::new(file));
+    if let Some(d)=d.decode().next() {
+        Ok(Some(try!(d)))
+    } else {
+        Err(Error::NothingToDecode(Some(path)))
+    }
+}
+
+/// Remove the journal of the repository rooted at `r`. This must be
+/// called after the transaction has been committed.
+pub fn finish(r:&Path)->Result<(),Error> {
+    let path=journal_file(r);
+    if metadata(&path).is_ok() {
+        try!(remove_file(&path))
+    }
+    Ok(())
+}
@@ -66,6 +66,12 @@ use self::repository::{RepositoryT};
 pub mod lmdb_repository;
 use self::lmdb_repository::{LmdbRepository};
 
+pub mod journal;
+use self::journal::{Journal,Operation};
+
+pub mod schema;
+use self::schema::{SCHEMA_VERSION,MIGRATIONS,VERSION_KEY};
+
 use std::collections::BTreeSet;
 #[cfg(not(windows))]
 use std::os::unix::fs::PermissionsExt;
@@ -90,6 +96,28 @@ enum Tree {
 /// The name of the default branch, "main".
 pub const DEFAULT_BRANCH:&'static str="main";
 
+/// How a patch came to be applied to a branch, kept along with the
+/// order of application.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Origin {
+    /// Applied before the pristine kept the order of application,
+    /// which is unknown.
+    Unknown,
+    /// Pulled, pushed or cloned from another repository.
+    Remote,
+    /// Recorded in this repository.
+    Local
+}
+
+impl Origin {
+    fn to_byte(&self)->u8 {
+        match *self { Origin::Unknown=>0, Origin::Remote=>1, Origin::Local=>2 }
+    }
+    fn of_byte(b:u8)->Origin {
+        match b { 1=>Origin::Remote, 2=>Origin::Local, _=>Origin::Unknown }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct InternalKey<'a> {
     pub contents : &'a[u8]
@@ -118,6 +146,33 @@ impl <'a> LineBuffer<'a> for Diff<'a> {
     }
 }
 
+/// Contents of a file of the pristine, as output to the working
+/// copy, and whether it has conflicts.
+struct Rendering {
+    contents:Vec<u8>,
+    conflict:bool
+}
+
+impl <'a> LineBuffer<'a> for Rendering {
+    fn output_line(&mut self,_:&'a[u8],c:&'a[u8]) {
+        self.contents.extend(c)
+    }
+    fn begin_conflict(&mut self) {
+        self.conflict=true;
+        self.contents.extend(b">>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>\n")
+    }
+}
+
+/// What applying patches would do to the files of the pristine, as
+/// computed by `Repository::simulate_apply`.
+#[derive(Debug)]
+pub struct Simulation {
+    /// Files that would be created, deleted, or modified.
+    pub changed:Vec<PathBuf>,
+    /// Files that would get conflicts they do not have yet.
+    pub conflicts:Vec<PathBuf>
+}
+
 impl <'a,W> LineBuffer<'a> for W where W:std::io::Write {
     fn output_line(&mut self,_:&'a[u8],c:&'a[u8]) {
         self.write(c).unwrap(); // .expect("output_line: could not write");
@@ -200,25 +255,69 @@ fn has_edge(cursor:&mut lmdb::MdbCursor,key:&[u8],flag0:u8,include_folder:bool,i
 
 
 impl <'a> Repository<'a> {
+    /// Opens the repository at `path`, in a write transaction. LMDB
+    /// allows only one write transaction at a time, so this blocks
+    /// until other writers have committed or aborted. Pristines in an
+    /// older format are upgraded in this transaction (see module
+    /// `schema`).
     pub fn new(path:&std::path::Path)->Result<Repository<'a>,Error>{
+        Repository::open_with_flags(path,0)
+    }
+
+    /// Opens the repository at `path` in a read-only transaction
+    /// (`MDB_RDONLY`), which does not take the writer lock, and can
+    /// therefore run alongside another pijul process writing to the
+    /// same repository. Operations that modify the repository fail on
+    /// the result, which must not be committed. Pristines in an older
+    /// format are not upgraded, and give `SchemaNeedsMigration`.
+    pub fn new_readonly(path:&std::path::Path)->Result<Repository<'a>,Error>{
+        Repository::open_with_flags(path,lmdb::MDB_RDONLY)
+    }
+
+    fn open_with_flags(path:&std::path::Path,flags:libc::c_uint)->Result<Repository<'a>,Error>{
         let env=try!(lmdb::Env_::new());
         let _=try!(env.reader_check());
-        try!(env.set_maxdbs(10));
+        try!(env.set_maxdbs(12));
         try!(env.set_mapsize( (1 << 30) ));
         let env=try!(env.open(path,0,0o755));
+        // Databases can only be created in write transactions.
+        let create= if flags & lmdb::MDB_RDONLY == 0 { lmdb::MDB_CREATE } else { 0 };
         unsafe {
-            let txn=try!(env.unsafe_txn(0));
-            let dbi_nodes=try!(txn.unsafe_dbi_open(b"nodes\0",lmdb::MDB_CREATE|lmdb::MDB_DUPSORT|lmdb::MDB_DUPFIXED));
-            let dbi_revdep=try!(txn.unsafe_dbi_open(b"revdep\0",lmdb::MDB_CREATE|lmdb::MDB_DUPSORT));
-            let dbi_contents=try!(txn.unsafe_dbi_open(b"contents\0",lmdb::MDB_CREATE));
-            let dbi_internal=try!(txn.unsafe_dbi_open(b"internal\0",lmdb::MDB_CREATE));
-            let dbi_external=try!(txn.unsafe_dbi_open(b"external\0",lmdb::MDB_CREATE));
-            let dbi_branches=try!(txn.unsafe_dbi_open(b"branches\0",lmdb::MDB_CREATE|lmdb::MDB_DUPSORT));
-            let dbi_tree=try!(txn.unsafe_dbi_open(b"tree\0",lmdb::MDB_CREATE));
-            let dbi_revtree=try!(txn.unsafe_dbi_open(b"revtree\0",lmdb::MDB_CREATE));
-            let dbi_inodes=try!(txn.unsafe_dbi_open(b"inodes\0",lmdb::MDB_CREATE));
-            let dbi_revinodes=try!(txn.unsafe_dbi_open(b"revinodes\0",lmdb::MDB_CREATE));
-            let repo=Repository{
+            let txn=try!(env.unsafe_txn(flags as usize));
+            let dbi_schema=match txn.unsafe_dbi_open(b"schema\0",create) {
+                Ok(dbi)=>dbi,
+                // Unversioned pristines have no schema database.
+                Err(ref e) if create==0 && e.raw_os_error()==Some(lmdb::MDB_NOTFOUND)=>
+                    return Err(Error::SchemaNeedsMigration(0)),
+                Err(e)=>return Err(Error::IO(e))
+            };
+            if create==0 {
+                // Checked before opening the other databases, since
+                // those added by newer versions are missing from older
+                // pristines.
+                let version=match try!(txn.get(dbi_schema,VERSION_KEY)) {
+                    Some(v)=>try!(schema::decode_version(v).ok_or(Error::SchemaCorrupted)),
+                    None=>0
+                };
+                if version > SCHEMA_VERSION {
+                    return Err(Error::SchemaTooNew(version))
+                }
+                if version < SCHEMA_VERSION {
+                    return Err(Error::SchemaNeedsMigration(version))
+                }
+            }
+            let dbi_nodes=try!(txn.unsafe_dbi_open(b"nodes\0",create|lmdb::MDB_DUPSORT|lmdb::MDB_DUPFIXED));
+            let dbi_revdep=try!(txn.unsafe_dbi_open(b"revdep\0",create|lmdb::MDB_DUPSORT));
+            let dbi_contents=try!(txn.unsafe_dbi_open(b"contents\0",create));
+            let dbi_internal=try!(txn.unsafe_dbi_open(b"internal\0",create));
+            let dbi_external=try!(txn.unsafe_dbi_open(b"external\0",create));
+            let dbi_branches=try!(txn.unsafe_dbi_open(b"branches\0",create|lmdb::MDB_DUPSORT));
+            let dbi_tree=try!(txn.unsafe_dbi_open(b"tree\0",create));
+            let dbi_revtree=try!(txn.unsafe_dbi_open(b"revtree\0",create));
+            let dbi_inodes=try!(txn.unsafe_dbi_open(b"inodes\0",create));
+            let dbi_revinodes=try!(txn.unsafe_dbi_open(b"revinodes\0",create));
+            let dbi_applied=try!(txn.unsafe_dbi_open(b"applied\0",create|lmdb::MDB_DUPSORT));
+            let mut repo=Repository{
                 env:env,
                 txn:txn,
                 dbi_nodes:dbi_nodes,
@@ -230,16 +329,77 @@ impl <'a> Repository<'a> {
                 dbi_tree:dbi_tree,
                 dbi_revtree:dbi_revtree,
                 dbi_inodes:dbi_inodes,
-                dbi_revinodes:dbi_revinodes
+                dbi_revinodes:dbi_revinodes,
+                dbi_applied:dbi_applied,
+                dbi_schema:dbi_schema,
+                journal:None
             };
+            if create!=0 {
+                try!(repo.check_schema());
+            }
             Ok(repo)
         }
     }
 
+    /// The version of the format of this pristine.
+    pub fn schema_version(&self)->Result<u32,Error> {
+        match try!(self.txn.get(self.dbi_schema,VERSION_KEY)) {
+            Some(v)=>schema::decode_version(v).ok_or(Error::SchemaCorrupted),
+            None=>Ok(0)
+        }
+    }
+
+    /// Overwrites the version number of this pristine, without
+    /// converting it. Version 0 removes the number, as in pristines
+    /// created before versioning. This is only meant for testing
+    /// migrations.
+    pub fn set_schema_version(&mut self,v:u32)->Result<(),Error> {
+        if v==0 {
+            try!(self.txn.del(self.dbi_schema,VERSION_KEY,None));
+        } else {
+            try!(self.txn.put(self.dbi_schema,VERSION_KEY,&schema::encode_version(v),0));
+        }
+        Ok(())
+    }
+
+    /// Refuses pristines written by a more recent version of
+    /// libpijul, and runs the migrations needed by older ones. Only
+    /// for write transactions, read-only ones are checked when opened.
+    fn check_schema(&mut self)->Result<(),Error> {
+        let version=try!(self.schema_version());
+        if version > SCHEMA_VERSION {
+            return Err(Error::SchemaTooNew(version))
+        }
+        if version < SCHEMA_VERSION {
+            for &(v,migration) in MIGRATIONS.iter() {
+                if v > version {
+                    info!("migrating pristine to version {}",v);
+                    try!(migration(self));
+                    try!(self.txn.put(self.dbi_schema,VERSION_KEY,&schema::encode_version(v),0));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits the transaction, and then removes the journal written
+    /// by the operations of this transaction, if any.
     pub fn commit(mut self)->Result<(),Error> {
         unsafe {
-            Ok(try!(self.txn.unsafe_commit()))
+            try!(self.txn.unsafe_commit())
         }
+        if let Some(r)=self.journal.take() {
+            try!(journal::finish(&r))
+        }
+        Ok(())
+    }
+
+    /// Saves `journal` before the first write outside of the
+    /// transaction, so that `commit` removes it.
+    fn write_journal(&mut self,r:&Path,journal:&Journal)->Result<(),Error> {
+        try!(journal::write(r,journal));
+        self.journal=Some(r.to_path_buf());
+        Ok(())
     }
 
     fn create_new_inode(& self,buf: &mut [u8]) {
@@ -979,12 +1139,22 @@ impl <'a> Repository<'a> {
         Ok(())
     }
      */
-    /// "intro" is the internal patch number of the patch that introduced this edge.
-    fn internal_edge(&'a self,flag:u8,to:&[u8],intro:InternalKey,result:&mut [u8])->Result<(),Error> {
+    /// The internal patch number of the patch of external key `key`.
+    /// Keys without a hash are those of nodes introduced by the patch
+    /// being applied, whose internal number is `patch`.
+    fn internal_patch_of_key<'b>(&'b self,key:&[u8],patch:InternalKey<'b>)->Result<InternalKey<'b>,Error> {
+        if key.len()==LINE_SIZE {
+            Ok(patch)
+        } else {
+            self.internal_hash(&key[0..(key.len()-LINE_SIZE)])
+        }
+    }
+    /// "intro" is the internal patch number of the patch that introduced this edge, and "patch" that of the patch being applied.
+    fn internal_edge(&'a self,flag:u8,to:&[u8],intro:InternalKey,patch:InternalKey,result:&mut [u8])->Result<(),Error> {
         debug_assert!(result.len()>=1+KEY_SIZE+HASH_SIZE);
         debug_assert!(intro.contents.len() == HASH_SIZE);
         result[0]=flag;
-        let int_to=try!(self.internal_hash(&to[0..(to.len()-LINE_SIZE)]));
+        let int_to=try!(self.internal_patch_of_key(to,patch));
         unsafe {
             copy_nonoverlapping(int_to.contents.as_ptr(),result.as_mut_ptr().offset(1),HASH_SIZE);
             copy_nonoverlapping(to.as_ptr().offset((to.len()-LINE_SIZE) as isize),
@@ -1217,6 +1387,7 @@ impl <'a> Repository<'a> {
 
     fn record_all<'b> (&self,
                   actions:&mut Vec<Change>,
+                  files:&mut Vec<PathBuf>,
                   line_num:&mut usize,
                   redundant:&mut Vec<u8>,
                   updatables:&mut HashMap<Vec<u8>,contents::OwnedInode >,
@@ -1224,10 +1395,18 @@ impl <'a> Repository<'a> {
                   parent_node:Option< &[u8] >,
                   current_inode: Inode,
                   realpath:&mut std::path::PathBuf,
-                  basename:&[u8])->Result<(),Error> {
+                  basename:&[u8],
+                  prefixes:&[PathBuf])->Result<(),Error> {
 
         if parent_inode.is_some() { realpath.push(str::from_utf8(&basename).unwrap()) }
         debug!(target:"record_all","realpath:{:?}",realpath);
+        // Only the files under one of the prefixes are recorded, their
+        // ancestors are only walked through.
+        let recorded=prefixes.is_empty() || prefixes.iter().any(|p| realpath.starts_with(p));
+        if !recorded && !prefixes.iter().any(|p| p.starts_with(&realpath)) {
+            if parent_inode.is_some() { let _=realpath.pop(); }
+            return Ok(())
+        }
         debug!(target:"record_all","inode:{:?}",current_inode.to_hex());
 
         let mut l2=[0;LINE_SIZE];
@@ -1251,7 +1430,9 @@ impl <'a> Repository<'a> {
                         };
                         debug!(target:"record_all","current_node[0]={},old_attr={},int_attr={}",
                                current_node[0],old_attr,int_attr);
-                        if !deleted && (current_node[0]==1 || old_attr!=int_attr) {
+                        if !recorded {
+                            // An ancestor of a prefix: nothing to record here.
+                        } else if !deleted && (current_node[0]==1 || old_attr!=int_attr) {
                             // file moved
 
                             // Delete all former names.
@@ -1461,6 +1642,10 @@ impl <'a> Repository<'a> {
                 Some(ROOT_KEY)
             };
         debug!(target:"record_all","current_node={:?}",current_node);
+        // The changes recorded so far for this node are in realpath.
+        while files.len()<actions.len() {
+            files.push(realpath.clone())
+        }
         match current_node {
             None => (), // we just added a file
             Some(current_node)=>{
@@ -1476,12 +1661,13 @@ impl <'a> Repository<'a> {
                         if v.len()>0 { // directories have len==0
                             debug!(target:"record_all","  child: {} + {}",&v[0..INODE_SIZE].to_hex(),
                                    std::str::from_utf8(&k[INODE_SIZE..]).unwrap());
-                            try!(self.record_all(actions, line_num,redundant,updatables,
+                            try!(self.record_all(actions, files, line_num,redundant,updatables,
                                                  Some(current_inode), // parent_inode
                                                  Some(current_node), // parent_node
                                                  Inode::from_slice(v),// current_inode
                                                  realpath,
-                                                 &k[INODE_SIZE..]));
+                                                 &k[INODE_SIZE..],
+                                                 prefixes));
                         }
                         op=lmdb::Op::MDB_NEXT;
                     }
@@ -1494,18 +1680,36 @@ impl <'a> Repository<'a> {
 
     /// Records,i.e. produce a patch and a HashMap mapping line numbers to "permissions(2 bytes)+inodes".
     pub fn record(&mut self,working_copy:&std::path::Path)->Result<(Vec<Change>,HashMap<LocalKey,OwnedInode>),Error>{
+        let (actions,_,updatables)=try!(self.record_files(working_copy));
+        Ok((actions,updatables))
+    }
+
+    /// Same as `record`, also returning the path of the file each
+    /// change is about, relative to the root of the working copy.
+    pub fn record_files(&mut self,working_copy:&std::path::Path)->Result<(Vec<Change>,Vec<PathBuf>,HashMap<LocalKey,OwnedInode>),Error>{
+        self.record_prefixes(working_copy,&[])
+    }
+
+    /// Same as `record_files`, but only walks and diffs the files and
+    /// directories under `prefixes`, which are relative to the root of
+    /// the working copy. An empty `prefixes` records everything.
+    pub fn record_prefixes(&mut self,working_copy:&std::path::Path,prefixes:&[PathBuf])->Result<(Vec<Change>,Vec<PathBuf>,HashMap<LocalKey,OwnedInode>),Error>{
+        let prefixes:Vec<PathBuf>=prefixes.iter().map(|p| working_copy.join(p)).collect();
         let mut actions:Vec<Change>=Vec::new();
+        let mut files:Vec<PathBuf>=Vec::new();
         let mut line_num=1;
         let mut updatables:HashMap<LocalKey,contents::OwnedInode>=HashMap::new();
         let mut realpath=PathBuf::from(working_copy);
         let mut redundant=vec!();
-        try!(self.record_all(&mut actions, &mut line_num,&mut redundant,&mut updatables,
+        try!(self.record_all(&mut actions, &mut files, &mut line_num,&mut redundant,&mut updatables,
                              None,None, Inode::from_owned(&OwnedInode::root()),&mut realpath,
-                             &[]));
+                             &[],&prefixes));
         debug!(target:"record","record done, {} changes", actions.len());
         self.remove_redundant_edges(&mut redundant);
         debug!("remove_redundant_edges done");
-        Ok((actions,updatables))
+        let root_len=working_copy.iter().count();
+        let files=files.into_iter().map(|f| f.iter().skip(root_len).collect()).collect();
+        Ok((actions,files,updatables))
     }
 
     /// Test whether a node has edges unknown to the patch we're applying.
@@ -1550,9 +1754,12 @@ impl <'a> Repository<'a> {
                         // First remove the deleted version of the edge
                         //debug!(target:"conflictdiff","e:{:?}",e);
                         {
-                            let p= try!(self.internal_hash(&e.introduced_by));
-                            try!(self.internal_edge(*flag^DELETED_EDGE^PARENT_EDGE,&e.from,p,&mut pu));
-                            try!(self.internal_edge(*flag^DELETED_EDGE,&e.to,p,&mut pv));
+                            // An empty introduced_by is the patch being applied.
+                            let p= if e.introduced_by.is_empty() { internal_patch_id } else {
+                                try!(self.internal_hash(&e.introduced_by))
+                            };
+                            try!(self.internal_edge(*flag^DELETED_EDGE^PARENT_EDGE,&e.from,p,internal_patch_id,&mut pu));
+                            try!(self.internal_edge(*flag^DELETED_EDGE,&e.to,p,internal_patch_id,&mut pv));
                             debug!(target:"exclusive","pu={}\npv={}",pu.to_hex(),pv.to_hex());
                         }
                         try!(self.txn.del(self.dbi_nodes,&pu[1..(1+KEY_SIZE)], Some(&pv)));
@@ -1579,8 +1786,8 @@ impl <'a> Repository<'a> {
                     parents.clear();
                     children.clear();
                     for e in edges {
-                        try!(self.internal_edge(*flag^PARENT_EDGE,&e.from,internal_patch_id,&mut pu));
-                        try!(self.internal_edge(*flag,&e.to,internal_patch_id,&mut pv));
+                        try!(self.internal_edge(*flag^PARENT_EDGE,&e.from,internal_patch_id,internal_patch_id,&mut pu));
+                        try!(self.internal_edge(*flag,&e.to,internal_patch_id,internal_patch_id,&mut pv));
                         debug!(target:"apply","new edge:\n  {}\n  {}",pu.to_hex(),pv.to_hex());
                         try!(self.txn.put(self.dbi_nodes,&pu[1..(1+KEY_SIZE)],&pv,lmdb::MDB_NODUPDATA));
                         try!(self.txn.put(self.dbi_nodes,&pv[1..(1+KEY_SIZE)],&pu,lmdb::MDB_NODUPDATA));
@@ -1834,6 +2041,10 @@ impl <'a> Repository<'a> {
 
     /// Applies a patch to a repository. "new_patches" are patches that just this repository has, and the remote repository doesn't have.
     pub fn apply<'b>(&mut self, patch:&Patch, internal: InternalKey<'b>, new_patches:&HashSet<&[u8]>)->Result<(),Error> {
+        self.apply_with_origin(patch,internal,new_patches,Origin::Remote)
+    }
+
+    fn apply_with_origin<'b>(&mut self, patch:&Patch, internal: InternalKey<'b>, new_patches:&HashSet<&[u8]>, origin:Origin)->Result<(),Error> {
         let current=self.get_current_branch().to_vec();
         {
             let curs=self.txn.cursor(self.dbi_branches).unwrap();
@@ -1843,6 +2054,7 @@ impl <'a> Repository<'a> {
             }
         }
         self.txn.put(self.dbi_branches,&current,&(internal.contents),lmdb::MDB_NODUPDATA).unwrap();
+        try!(self.record_application(&current,internal.contents,origin));
         try!(self.unsafe_apply(&patch.changes,InternalKey::new(internal.contents),&patch.dependencies));
         let cursor= unsafe {&mut *self.txn.unsafe_cursor(self.dbi_nodes).unwrap() };
         let cursor_= unsafe {&mut *self.txn.unsafe_cursor(self.dbi_nodes).unwrap() };
@@ -1900,8 +2112,8 @@ impl <'a> Repository<'a> {
                             // Untested (how to generate non-deleted Change::Edges?)
                             for e in edges {
                                 {
-                                    let int_from=try!(self.internal_hash(&e.from[0..(e.from.len()-LINE_SIZE)]));
-                                    let int_to=try!(self.internal_hash(&e.to[0..(e.to.len()-LINE_SIZE)]));
+                                    let int_from=try!(self.internal_patch_of_key(&e.from,internal));
+                                    let int_to=try!(self.internal_patch_of_key(&e.to,internal));
                                     unsafe {
                                         copy_nonoverlapping(int_from.contents.as_ptr(),u.as_mut_ptr(),HASH_SIZE);
                                         copy_nonoverlapping(e.from.as_ptr().offset((e.from.len()-LINE_SIZE) as isize),
@@ -1921,7 +2133,7 @@ impl <'a> Repository<'a> {
                                 for e in edges {
                                     {
                                         let dest= if *flag & PARENT_EDGE != 0 { &e.from } else { &e.to };
-                                        let int_dest=try!(self.internal_hash(&dest[0..(dest.len()-LINE_SIZE)]));
+                                        let int_dest=try!(self.internal_patch_of_key(dest,internal));
                                         unsafe {
                                             copy_nonoverlapping(int_dest.contents.as_ptr(),u.as_mut_ptr(),HASH_SIZE);
                                             copy_nonoverlapping(dest.as_ptr().offset((dest.len()-LINE_SIZE) as isize),
@@ -2100,6 +2312,107 @@ impl <'a> Repository<'a> {
         Ok(())
     }
 
+    /// Appends the patch of internal hash `internal` to the patches
+    /// of `branch`, in order of application. The values of the
+    /// "applied" database are an 8-byte big-endian counter, the origin
+    /// of the patch, and its internal hash.
+    pub fn record_application(&mut self,branch:&[u8],internal:&[u8],origin:Origin)->Result<(),Error> {
+        let next={
+            let curs=try!(self.txn.cursor(self.dbi_applied));
+            match curs.get(branch,None,lmdb::Op::MDB_SET).and_then(|_| curs.get(branch,None,lmdb::Op::MDB_LAST_DUP)) {
+                Ok((_,v))=>v[..8].iter().fold(0_u64,|n,&b| (n<<8)|(b as u64))+1,
+                Err(_)=>0
+            }
+        };
+        let mut value=Vec::with_capacity(9+internal.len());
+        for i in 0..8 {
+            value.push((next>>(8*(7-i))) as u8)
+        }
+        value.push(origin.to_byte());
+        value.extend(internal.iter().cloned());
+        try!(self.txn.put(self.dbi_applied,branch,&value,0));
+        Ok(())
+    }
+
+    /// Returns the external hashes of the patches of the current
+    /// branch, in the order they were applied to this repository.
+    pub fn applied_patches_in_order(&self)->Result<Vec<Vec<u8>>,Error> {
+        let mut patches=Vec::new();
+        let branch=self.get_current_branch();
+        let curs=try!(self.txn.cursor(self.dbi_applied));
+        let mut op=lmdb::Op::MDB_SET;
+        while let Ok((_,v))=curs.get(&branch,None,op) {
+            patches.push(self.external_hash(&v[9..]).to_vec());
+            op=lmdb::Op::MDB_NEXT_DUP
+        }
+        Ok(patches)
+    }
+
+    /// Returns the external hash of the patch last applied to the
+    /// current branch, and its origin.
+    pub fn last_application(&self)->Result<Option<(Vec<u8>,Origin)>,Error> {
+        let branch=self.get_current_branch();
+        let curs=try!(self.txn.cursor(self.dbi_applied));
+        match curs.get(&branch,None,lmdb::Op::MDB_SET).and_then(|_| curs.get(&branch,None,lmdb::Op::MDB_LAST_DUP)) {
+            Ok((_,v))=>Ok(Some((self.external_hash(&v[9..]).to_vec(),Origin::of_byte(v[8])))),
+            Err(_)=>Ok(None)
+        }
+    }
+
+    /// Removes the patch of internal hash `internal` from the order of
+    /// application of `branch`.
+    fn forget_application(&mut self,branch:&[u8],internal:&[u8])->Result<(),Error> {
+        let value={
+            let curs=try!(self.txn.cursor(self.dbi_applied));
+            let mut op=lmdb::Op::MDB_SET;
+            let mut value=None;
+            while let Ok((_,v))=curs.get(branch,None,op) {
+                if &v[9..]==internal {
+                    value=Some(v.to_vec());
+                    break
+                }
+                op=lmdb::Op::MDB_NEXT_DUP
+            }
+            value
+        };
+        if let Some(value)=value {
+            try!(self.txn.del(self.dbi_applied,branch,Some(&value[..])));
+        }
+        Ok(())
+    }
+
+    /// Returns the branches of this repository, and the internal
+    /// hashes of their patches.
+    pub fn branch_patches(&self)->Result<Vec<(Vec<u8>,Vec<u8>)>,Error> {
+        let mut patches=Vec::new();
+        let curs=try!(self.txn.cursor(self.dbi_branches));
+        let mut op=lmdb::Op::MDB_FIRST;
+        while let Ok((k,v))=curs.get(b"",None,op) {
+            // Key [0] holds the name of the current branch, not a patch.
+            if k!=&[0][..] {
+                patches.push((k.to_vec(),v.to_vec()))
+            }
+            op=lmdb::Op::MDB_NEXT
+        }
+        Ok(patches)
+    }
+
+    /// Returns the external hashes of all the patches applied on any
+    /// branch of this repository.
+    pub fn applied_patches(&self)->HashSet<Vec<u8>> {
+        let mut patches=HashSet::new();
+        let curs=self.txn.cursor(self.dbi_branches).unwrap();
+        let mut op=lmdb::Op::MDB_FIRST;
+        while let Ok((k,v))=curs.get(b"",None,op) {
+            // Key [0] holds the name of the current branch, not a patch.
+            if k!=&[0][..] {
+                patches.insert(self.external_hash(v).to_vec());
+            }
+            op=lmdb::Op::MDB_NEXT
+        }
+        patches
+    }
+
 
     pub fn sync_file_additions(&mut self, changes:&[Change], updates:&HashMap<LocalKey,OwnedInode>, internal_patch_id:InternalKey){
         let mut node=[0;3+KEY_SIZE];
@@ -2198,48 +2511,100 @@ impl <'a> Repository<'a> {
                          r:&Path,
                          remote_patches:&HashSet<Vec<u8>>,
                          local_patches:&HashSet<Vec<u8>>) -> Result<(), Error> {
+        let pullable:Vec<Vec<u8>>=remote_patches.difference(&local_patches).cloned().collect();
+        self.apply_patches_as_available(r,remote_patches,local_patches,pullable.into_iter().map(Ok))
+    }
+
+    /// Same as `apply_patches`, for patches that are still being
+    /// downloaded: `arrivals` yields the hash of each patch once its
+    /// file is in the patches directory, and patches are applied as
+    /// soon as their dependencies have been. If `arrivals` yields an
+    /// error, it is returned before anything is written outside of
+    /// the transaction, which must then be aborted.
+    pub fn apply_patches_as_available<I,E>(&mut self,
+                                           r:&Path,
+                                           remote_patches:&HashSet<Vec<u8>>,
+                                           local_patches:&HashSet<Vec<u8>>,
+                                           arrivals:I) -> Result<(), E>
+        where I:Iterator<Item=Result<Vec<u8>,E>>, E:From<Error> {
         debug!("local {}, remote {}",local_patches.len(),remote_patches.len());
-        let pullable=remote_patches.difference(&local_patches);
+        let pullable:HashSet<&[u8]>=remote_patches.difference(&local_patches).map(|x| &x[..]).collect();
         let only_local={
             let mut only_local:HashSet<&[u8]>=HashSet::new();
             for i in local_patches.difference(&remote_patches) { only_local.insert(&i[..]); };
             only_local
         };
-        fn apply_patches<'a>(repo:&mut Repository<'a>, branch:&[u8], repo_root:&Path, patch_hash:&[u8], patches_were_applied:&mut bool, only_local:&HashSet<&[u8]>)->Result<(),Error>{
-            if !try!(repo.has_patch(branch,patch_hash)) {
-                let patch=try!(Patch::from_repository(repo_root,patch_hash));
-                for dep in patch.dependencies.iter() {
-                    try!(apply_patches(repo,branch,repo_root,&dep,patches_were_applied, only_local))
-                }
-                let mut internal=[0;HASH_SIZE];
-                repo.new_internal(&mut internal);
-                let internal = InternalKey::new(&internal);
-                //println!("pulling and applying patch {}",to_hex(patch_hash));
-                try!(repo.apply(&patch, internal,only_local));
-                *patches_were_applied=true;
-                //repo.sync_file_additions(&patch.changes[..],&HashMap::new(), &internal);
-                repo.register_hash(internal,patch_hash);
-                Ok(())
-            } else {
-                Ok(())
-            }
-        }
         let current_branch=self.get_current_branch().to_vec();
-        let pending={
+        // Bare repositories have no working copy to record or output.
+        let pending= if is_bare(r) { None } else {
             let (changes,_)= try!(self.record(&r));
             let mut p=Patch::empty();
             p.changes=changes;
-            p
+            Some(p)
         };
-        let mut patches_were_applied=false;
-        for p in pullable {
-            try!(apply_patches(self,&current_branch,&r,p,&mut patches_were_applied,&only_local))
+        let mut applied=Vec::new();
+        // Patches that have arrived before some of their dependencies,
+        // indexed by these dependencies, and the number of dependencies
+        // each of them is waiting for.
+        let mut waiting_on:HashMap<Vec<u8>,Vec<Vec<u8>>>=HashMap::new();
+        let mut missing:HashMap<Vec<u8>,usize>=HashMap::new();
+        for h in arrivals {
+            let h=try!(h);
+            let patch=try!(Patch::from_repository(r,&h));
+            let mut n=0;
+            for d in patch.dependencies.iter() {
+                if pullable.contains(&d[..]) && !try!(self.has_patch(&current_branch,d)) {
+                    waiting_on.entry(d.clone()).or_insert(Vec::new()).push(h.clone());
+                    n+=1
+                }
+            }
+            if n>0 {
+                missing.insert(h,n);
+                continue
+            }
+            // Apply `h`, and then the patches that were only waiting
+            // for the patches just applied.
+            let mut ready=vec!(h);
+            while let Some(h)=ready.pop() {
+                let n=applied.len();
+                try!(apply_patch_and_deps(self,&current_branch,&r,&h,&mut applied,&only_local));
+                for a in applied[n..].iter() {
+                    for w in waiting_on.remove(a).unwrap_or(Vec::new()) {
+                        let done={
+                            let m=missing.get_mut(&w).unwrap();
+                            *m-=1;
+                            *m==0
+                        };
+                        if done {
+                            missing.remove(&w);
+                            ready.push(w)
+                        }
+                    }
+                }
+            }
+        }
+        // Dependencies missing from `arrivals` are looked up in the
+        // patches directory.
+        for (h,_) in missing.iter() {
+            try!(apply_patch_and_deps(self,&current_branch,&r,h,&mut applied,&only_local))
         }
-        debug!(target:"pull","patches applied? {}",patches_were_applied);
-        if patches_were_applied {
+        debug!(target:"pull","patches applied? {}",applied.len());
+        if applied.len()>0 {
+            // From now on, the changes file and the working copy are
+            // modified outside of the transaction.
+            let journal=Journal {
+                operation:Operation::Apply,
+                branch:current_branch.clone(),
+                patches:applied,
+                only_local:only_local.iter().map(|x| x.to_vec()).collect(),
+                pending:pending
+            };
+            try!(self.write_journal(r,&journal));
             try!(self.write_changes_file(&branch_changes_file(r,&current_branch)));
             debug!(target:"pull","output_repository");
-            try!(self.output_repository(&r,&pending))
+            if let Some(ref pending)=journal.pending {
+                try!(self.output_repository(&r,pending))
+            }
         }
         if cfg!(debug_assertions){
             let mut buffer = BufWriter::new(File::create(r.join("debug")).unwrap());
@@ -2255,6 +2620,134 @@ impl <'a> Repository<'a> {
 
 
 
+    /// Compute the effects of `apply_patches` on the files of the
+    /// pristine (ignoring the unrecorded changes of the working copy),
+    /// without changing anything: the patches are applied in a nested
+    /// transaction, which is then aborted. All patches must have been
+    /// downloaded.
+    pub fn simulate_apply(&mut self,
+                          r:&Path,
+                          remote_patches:&HashSet<Vec<u8>>,
+                          local_patches:&HashSet<Vec<u8>>) -> Result<Simulation,Error> {
+        let only_local:HashSet<&[u8]>=local_patches.difference(&remote_patches).map(|x| &x[..]).collect();
+        let current_branch=self.get_current_branch().to_vec();
+        let before=try!(self.render_files());
+        let parent_txn=self.txn.txn;
+        unsafe {
+            let txn=ptr::null_mut();
+            let e=lmdb::mdb_txn_begin(self.env.env,parent_txn,0,std::mem::transmute(&txn));
+            if e!=0 {
+                return Err(Error::IO(std::io::Error::from_raw_os_error(e)))
+            }
+            self.txn.txn=txn;
+        }
+        let after={
+            let mut applied=Vec::new();
+            let mut result=Ok(());
+            for h in remote_patches.difference(&local_patches) {
+                result=apply_patch_and_deps(self,&current_branch,r,h,&mut applied,&only_local);
+                if result.is_err() { break }
+            }
+            result.and_then(|_| self.render_files())
+        };
+        unsafe {
+            lmdb::mdb_txn_abort(self.txn.txn);
+            self.txn.txn=parent_txn;
+        }
+        let after=try!(after);
+        let mut changed=Vec::new();
+        let mut conflicts=Vec::new();
+        for (path,file) in after.iter() {
+            match before.get(path) {
+                Some(old) if old.contents==file.contents => {},
+                old => {
+                    changed.push(path.clone());
+                    if file.conflict && !old.map(|old| old.conflict).unwrap_or(false) {
+                        conflicts.push(path.clone())
+                    }
+                }
+            }
+        }
+        for path in before.keys() {
+            if !after.contains_key(path) {
+                changed.push(path.clone())
+            }
+        }
+        changed.sort();
+        conflicts.sort();
+        Ok(Simulation { changed:changed, conflicts:conflicts })
+    }
+
+    /// Output all the files of the pristine in memory, indexed by
+    /// their paths relative to the root of the repository.
+    fn render_files(&self)->Result<HashMap<PathBuf,Rendering>,Error> {
+        fn render<'b>(repo:&'b Repository<'b>,
+                      key:&[u8],
+                      path:&mut PathBuf,
+                      files:&mut HashMap<PathBuf,Rendering>)->Result<(),Error> {
+            for (perms,filename,cv) in try!(repo.retrieve_paths(key,FOLDER_EDGE)) {
+                path.push(&*String::from_utf8_lossy(filename));
+                if perms&DIRECTORY_FLAG==0 {
+                    let mut file=Rendering { contents:Vec::new(), conflict:false };
+                    if let Ok(graph)=repo.retrieve(cv) {
+                        let mut redundant_edges=vec!();
+                        repo.output_file(&mut file,graph,&mut redundant_edges);
+                    }
+                    files.insert(path.clone(),file);
+                } else {
+                    try!(render(repo,cv,path,files))
+                }
+                path.pop();
+            }
+            Ok(())
+        }
+        let mut files=HashMap::new();
+        try!(render(self,ROOT_KEY,&mut PathBuf::new(),&mut files));
+        Ok(files)
+    }
+
+    /// Finish or cancel the operation described by `journal`, which
+    /// was interrupted before the end (see module `journal`). The
+    /// journal is removed when the transaction is committed.
+    pub fn recover(&mut self, r:&Path, journal:&Journal)->Result<(),Error> {
+        let mut committed=true;
+        for h in journal.patches.iter() {
+            if !try!(self.has_patch(&journal.branch,h)) {
+                committed=false
+            }
+        }
+        debug!(target:"journal","recovering {:?}, committed: {}",journal.operation,committed);
+        if !committed {
+            match journal.operation {
+                Operation::Record => {
+                    // The new patch is in the patches directory, but
+                    // not in the pristine. Its changes are still in
+                    // the working copy, so they can be recorded again.
+                    for h in journal.patches.iter() {
+                        if let Some(p)=patch_path(r,h) {
+                            try!(fs::remove_file(&p))
+                        }
+                    }
+                },
+                Operation::Apply => {
+                    // All patches have been downloaded, finish applying them.
+                    let only_local=journal.only_local();
+                    let mut applied=Vec::new();
+                    for h in journal.patches.iter() {
+                        try!(apply_patch_and_deps(self,&journal.branch,r,h,&mut applied,&only_local))
+                    }
+                    if let Some(ref pending)=journal.pending {
+                        try!(self.output_repository(r,pending))
+                    }
+                }
+            }
+        }
+        // The changes file might not agree with the pristine.
+        try!(self.write_changes_file(&branch_changes_file(r,&journal.branch)));
+        self.journal=Some(r.to_path_buf());
+        Ok(())
+    }
+
     // Climp up the tree (using revtree).
     fn filename_of_inode(&self,inode:&[u8],working_copy:&mut PathBuf)->bool {
         //let mut v_inode=MDB_val{mv_data:inode.as_ptr() as *const c_void, mv_size:inode.len() as size_t};
@@ -2597,8 +3090,9 @@ impl <'a> Repository<'a> {
     }
 
     /// Apply a patch from a local record: register it, give it a hash, and then apply.
+    /// Returns the hash of the patch.
     pub fn apply_local_patch(&mut self, location: &Path, patch: Patch, inode_updates:&HashMap<LocalKey,OwnedInode>)
-                           -> Result<(), Error>{
+                           -> Result<Vec<u8>, Error>{
         info!("registering a patch with {} changes", patch.changes.len());
         let patch = Arc::new(patch);
         let child_patch = patch.clone();
@@ -2616,7 +3110,7 @@ impl <'a> Repository<'a> {
         self.new_internal(&mut internal);
         let internal = InternalKey::new( &internal );
         debug!(target:"pijul", "applying patch");
-        try!(self.apply(&patch, internal, &HashSet::new()));
+        try!(self.apply_with_origin(&patch, internal, &HashSet::new(), Origin::Local));
         debug!(target:"pijul", "synchronizing tree");
         //unimplemented!(); // synchronize here, by iterating on inode_updates.
         {
@@ -2650,10 +3144,18 @@ impl <'a> Repository<'a> {
             Ok(Ok(hash))=> {
                 self.register_hash(internal,&hash[..]);
                 debug!(target:"record","hash={}, local={}",hash.to_hex(),internal.to_hex());
+                // The changes file is written outside of the transaction.
+                try!(self.write_journal(location,&Journal {
+                    operation:Operation::Record,
+                    branch:self.get_current_branch().to_vec(),
+                    patches:vec!(hash.clone()),
+                    only_local:Vec::new(),
+                    pending:None
+                }));
                 self.write_changes_file(&branch_changes_file(location,self.get_current_branch())).unwrap();
                 let t3=time::precise_time_s();
                 info!("changes files took {}s to write", t3-t2);
-                Ok(())
+                Ok(hash)
             },
             Ok(Err(x)) => {
                 Err(x)
@@ -2664,6 +3166,126 @@ impl <'a> Repository<'a> {
         }
     }
 
+    /// The external hashes of the patches that depend on the patch of
+    /// external hash `hash`.
+    pub fn dependents(&self, hash:&[u8])->Result<Vec<Vec<u8>>,Error> {
+        let internal=try!(self.internal_hash(hash)).contents.to_vec();
+        let mut dependents=Vec::new();
+        let curs=try!(self.txn.cursor(self.dbi_revdep));
+        let mut op=lmdb::Op::MDB_SET;
+        while let Ok((_,v))=curs.get(&internal,None,op) {
+            dependents.push(self.external_hash(v).to_vec());
+            op=lmdb::Op::MDB_NEXT_DUP
+        }
+        Ok(dependents)
+    }
+
+    /// Remove the patch of external hash `hash`, whose contents are
+    /// `patch`, from the current branch: the edges it deleted are
+    /// restored, and its nodes and the edges it introduced are
+    /// deleted. No other patch may depend on it, and since branches
+    /// share the graph, no other branch may have it. The working copy
+    /// is left untouched; the inodes of the files added by the patch
+    /// are detached from the pristine, and inserted in `inodes` under
+    /// their local key in `patch`, so that a patch adding the same
+    /// nodes can take them over (see `apply_local_patch`).
+    pub fn unapply(&mut self, hash:&[u8], patch:&Patch, inodes:&mut HashMap<LocalKey,OwnedInode>)->Result<(),Error> {
+        let internal=try!(self.internal_hash(hash)).contents.to_vec();
+        let mut pu:[u8;1+KEY_SIZE+HASH_SIZE]=[0;1+KEY_SIZE+HASH_SIZE];
+        let mut pv:[u8;1+KEY_SIZE+HASH_SIZE]=[0;1+KEY_SIZE+HASH_SIZE];
+        // Restore the edges replaced by the patch, as computed by
+        // `unsafe_apply`. Those introduced by the patch itself are
+        // removed below, along with its other edges.
+        for ch in patch.changes.iter().rev() {
+            if let Change::Edges { ref flag, ref edges }=*ch {
+                for e in edges.iter().filter(|e| !e.introduced_by.is_empty()) {
+                    {
+                        let p=try!(self.internal_hash(&e.introduced_by));
+                        let internal=InternalKey::new(&internal);
+                        try!(self.internal_edge(*flag^DELETED_EDGE^PARENT_EDGE,&e.from,p,internal,&mut pu));
+                        try!(self.internal_edge(*flag^DELETED_EDGE,&e.to,p,internal,&mut pv));
+                    }
+                    try!(self.txn.put(self.dbi_nodes,&pu[1..(1+KEY_SIZE)],&pv,0));
+                    try!(self.txn.put(self.dbi_nodes,&pv[1..(1+KEY_SIZE)],&pu,0));
+                }
+            }
+        }
+        // Remove the edges from, to, or introduced by the patch.
+        let mut obsolete=Vec::new();
+        {
+            let curs=try!(self.txn.cursor(self.dbi_nodes));
+            let mut op=lmdb::Op::MDB_FIRST;
+            while let Ok((k,v))=curs.get(b"",None,op) {
+                if &k[..HASH_SIZE]==&internal[..] || &v[1..(1+HASH_SIZE)]==&internal[..]
+                    || &v[(1+KEY_SIZE)..]==&internal[..] {
+                    obsolete.push((k.to_vec(),v.to_vec()))
+                }
+                op=lmdb::Op::MDB_NEXT
+            }
+        }
+        for &(ref k,ref v) in obsolete.iter() {
+            try!(self.txn.del(self.dbi_nodes,k,Some(&v[..])));
+        }
+        // Remove the contents of its nodes.
+        let mut key=[0;KEY_SIZE];
+        unsafe { copy_nonoverlapping(internal.as_ptr(),key.as_mut_ptr(),HASH_SIZE) }
+        for ch in patch.changes.iter() {
+            if let Change::NewNodes { ref line_num, ref nodes, .. }=*ch {
+                for i in 0..nodes.len() {
+                    let mut l=*line_num as usize+i;
+                    for j in 0..LINE_SIZE { key[HASH_SIZE+j]=(l & 0xff) as u8; l>>=8 }
+                    try!(self.txn.del(self.dbi_contents,&key[..],None));
+                }
+            }
+        }
+        // Detach the inodes of its files.
+        let mut detached=Vec::new();
+        {
+            let curs=try!(self.txn.cursor(self.dbi_inodes));
+            let mut op=lmdb::Op::MDB_FIRST;
+            while let Ok((inode,v))=curs.get(b"",None,op) {
+                if v.len()==3+KEY_SIZE && &v[3..(3+HASH_SIZE)]==&internal[..] {
+                    detached.push((inode.to_vec(),v.to_vec()))
+                }
+                op=lmdb::Op::MDB_NEXT
+            }
+        }
+        for (inode,v) in detached {
+            try!(self.txn.del(self.dbi_inodes,&inode,Some(&v[..])));
+            try!(self.txn.del(self.dbi_revinodes,&v[3..],Some(&inode[..])));
+            let mut local_key=v[1..3].to_vec();
+            local_key.extend(&v[(3+HASH_SIZE)..]);
+            inodes.insert(local_key,OwnedInode { inode_contents:inode });
+        }
+        for dep in patch.dependencies.iter() {
+            let dep_internal=try!(self.internal_hash(dep)).contents.to_vec();
+            try!(self.txn.del(self.dbi_revdep,&dep_internal,Some(&internal[..])));
+        }
+        let branch=self.get_current_branch().to_vec();
+        try!(self.txn.del(self.dbi_branches,&branch,Some(&internal[..])));
+        try!(self.forget_application(&branch,&internal));
+        try!(self.txn.del(self.dbi_external,&internal,None));
+        try!(self.txn.del(self.dbi_internal,hash,None));
+        Ok(())
+    }
+
+    /// Replace `old`, a patch of the current branch only, that no
+    /// other patch depends on, by `patch`, recorded on top of it, and
+    /// return the hash of `patch`. `old` is unapplied, and `patch`
+    /// applied as a local patch, with the inodes of the files added
+    /// by `old`, and those of `inode_updates`. The other patches and
+    /// branches are kept, and since `patch` has the changes of `old`
+    /// and those of the working copy, the working copy is not output.
+    pub fn replace_patch(&mut self, r:&Path, old:&[u8], patch:Patch, inode_updates:&HashMap<LocalKey,OwnedInode>)->Result<Vec<u8>,Error> {
+        let old_patch=try!(Patch::from_repository(r,old));
+        let mut inodes=HashMap::new();
+        for (k,v) in inode_updates.iter() {
+            inodes.insert(k.clone(),OwnedInode { inode_contents:v.inode_contents.clone() });
+        }
+        try!(self.unapply(old,&old_patch,&mut inodes));
+        self.apply_local_patch(r,patch,&inodes)
+    }
+
     pub fn output_repository(&mut self, working_copy:&Path, pending:&Patch) -> Result<(),Error>{
         debug!(target:"output_repository","begin output repository");
         // First output the repository to change the trees/inodes tables (and their revs).
@@ -2728,6 +3350,30 @@ impl <'a> Repository<'a> {
         w.write(b"}\n").unwrap();
     }
 }
+/// Apply the patch with hash `patch_hash`, found in the patches
+/// directory of `repo_root`, after its dependencies, if it is not
+/// applied on `branch` yet. The hashes of the newly applied patches
+/// are pushed onto `applied`.
+fn apply_patch_and_deps<'a>(repo:&mut Repository<'a>, branch:&[u8], repo_root:&Path, patch_hash:&[u8], applied:&mut Vec<Vec<u8>>, only_local:&HashSet<&[u8]>)->Result<(),Error>{
+    if !try!(repo.has_patch(branch,patch_hash)) {
+        let patch=try!(Patch::from_repository(repo_root,patch_hash));
+        for dep in patch.dependencies.iter() {
+            try!(apply_patch_and_deps(repo,branch,repo_root,&dep,applied, only_local))
+        }
+        let mut internal=[0;HASH_SIZE];
+        repo.new_internal(&mut internal);
+        let internal = InternalKey::new(&internal);
+        //println!("pulling and applying patch {}",to_hex(patch_hash));
+        try!(repo.apply(&patch, internal,only_local));
+        //repo.sync_file_additions(&patch.changes[..],&HashMap::new(), &internal);
+        repo.register_hash(internal,patch_hash);
+        applied.push(patch_hash.to_vec());
+        Ok(())
+    } else {
+        Ok(())
+    }
+}
+
 /*
 fn dump_table(txn:&lmdb::Txn,dbi:lmdb::Dbi){
     println!("dumping table");
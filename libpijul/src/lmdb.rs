@@ -354,6 +354,7 @@ pub const MDB_DUPFIXED:c_uint=0x10;
 pub const MDB_INTEGERDUP:c_uint=0x20;
 pub const MDB_REVERSEDUP:c_uint=0x40;
 pub const MDB_CREATE:c_uint=0x40000;
+pub const MDB_RDONLY:c_uint=0x20000;
 
 pub const MDB_NOTFOUND: c_int = -30798;
 pub const MDB_KEYEXIST: c_int = -30799;
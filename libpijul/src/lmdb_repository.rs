@@ -15,7 +15,15 @@ pub struct LmdbRepository<'a> {
     pub dbi_tree : lmdb::Dbi,
     pub dbi_revtree : lmdb::Dbi,
     pub dbi_inodes : lmdb::Dbi,
-    pub dbi_revinodes : lmdb::Dbi
+    pub dbi_revinodes : lmdb::Dbi,
+    /// For each branch, its patches in the order they were applied:
+    /// values are a big-endian counter on 8 bytes, followed by the
+    /// internal hash of the patch.
+    pub dbi_applied : lmdb::Dbi,
+    pub dbi_schema : lmdb::Dbi,
+    /// Root of the repository whose journal was written by this
+    /// transaction, and must be removed once it is committed.
+    pub journal : Option<std::path::PathBuf>
 }
 
 impl <'a>Drop for LmdbRepository<'a> {
@@ -51,7 +51,7 @@ extern crate cbor;
 
 use std::collections::BTreeMap;
 use super::fs_representation::{patch_path};
-use std::process::{Command,Stdio};
+use std::process::{Command,Stdio,Child};
 
 pub type FileIndex = HashMap<LocalKey, OwnedInode >;
 
@@ -113,27 +113,20 @@ impl Patch {
         match p.extension().and_then(|x| x.to_str()) {
             Some("gpg") => {
                 debug!("starting gpg");
-                let mut gpg=
-                    try!(Command::new("gpg")
-                         .arg("--yes")
-                         .arg("--status-fd").arg("2") // report error on stderr.
-                         .arg("-d")
-                         .arg(p)
-                         .stdout(Stdio::piped())
-                         .stderr(Stdio::piped())
-                         .spawn());
+                let mut gpg=try!(gpg_decode(p));
                 debug!("gpg started");
+                // Read the output before waiting, so that gpg is not
+                // blocked on a full pipe.
+                let patch=Patch::from_reader(gpg.stdout.take().unwrap(),Some(p));
                 let stat=try!(gpg.wait());
-                let stdout = gpg.stdout.take().unwrap();
-                let patch=try!(Patch::from_reader(stdout,Some(p)));
                 debug!("gpg done");
                 if stat.success() {
-                    Ok(patch)
+                    patch
                 } else {
                     let mut stderr = gpg.stderr.take().unwrap();
                     let mut buf=String::new();
                     try!(stderr.read_to_string(&mut buf));
-                    Err(Error::GPG(stat.code().unwrap(),buf))
+                    Err(Error::GPG(stat.code().unwrap_or(-1),buf))
                 }
             },
             Some("cbor") => {
@@ -173,13 +166,7 @@ impl Patch {
     }
     pub fn save(&self,dir:&Path)->Result<Vec<u8>,Error>{
         debug!("saving patch");
-        let mut name:[u8;20]=[0;20]; // random name initially
-        fn make_name(dir:&Path,name:&mut [u8])->std::path::PathBuf{
-            for i in 0..name.len() { let r:u8=rand::random(); name[i] = 97 + (r%26) }
-            let tmp=dir.join(std::str::from_utf8(&name[..]).unwrap());
-            if std::fs::metadata(&tmp).is_err() { tmp } else { make_name(dir,name) }
-        }
-        let tmp=make_name(&dir,&mut name);
+        let tmp=tmp_file_path(&dir); // random name initially
         {
             let mut buffer = BufWriter::new(try!(File::create(&tmp)));
             try!(self.to_writer(&mut buffer));
@@ -195,21 +182,7 @@ impl Patch {
             .spawn();
 
         // hash
-        let mut hasher = Sha512::new();
-        {
-            let mut buffer = BufReader::new(try!(File::open(&tmp)));
-            loop {
-                let len= {
-                    let buf=try!(buffer.fill_buf());
-                    if buf.len()==0 { break } else {
-                        hasher.input(buf);buf.len()
-                    }
-                };
-                buffer.consume(len)
-            }
-        }
-        let mut hash=vec![0;hasher.output_bytes()];
-        hasher.result(&mut hash);
+        let hash=try!(hash_reader(try!(File::open(&tmp))));
         if let Ok(true)=gpg.and_then(|mut gpg| {
             let stat=try!(gpg.wait());
             Ok(stat.success())
@@ -229,6 +202,135 @@ impl Patch {
 }
 
 
+/// Length of the random names given to patch files while `Patch::save`
+/// is writing them.
+pub const TMP_NAME_LEN:usize=20;
+
+/// A path in `dir` with a random name of `TMP_NAME_LEN` lowercase
+/// letters, not used by any file yet.
+pub fn tmp_file_path(dir:&Path)->PathBuf {
+    let mut name:[u8;TMP_NAME_LEN]=[0;TMP_NAME_LEN];
+    loop {
+        for i in 0..name.len() { let r:u8=rand::random(); name[i] = 97 + (r%26) }
+        let tmp=dir.join(std::str::from_utf8(&name[..]).unwrap());
+        if std::fs::metadata(&tmp).is_err() { return tmp }
+    }
+}
+
+fn hash_reader<R:Read>(r:R)->Result<Vec<u8>,Error> {
+    let mut hasher = Sha512::new();
+    let mut buffer = BufReader::new(r);
+    loop {
+        let len= {
+            let buf=try!(buffer.fill_buf());
+            if buf.len()==0 { break } else {
+                hasher.input(buf);buf.len()
+            }
+        };
+        buffer.consume(len)
+    }
+    let mut hash=vec![0;hasher.output_bytes()];
+    hasher.result(&mut hash);
+    Ok(hash)
+}
+
+/// Starts gpg, writing the contents of the signed patch file `file`
+/// to its standard output. Signatures are not checked, so that
+/// patches signed with keys missing from the user's keyring can be
+/// read; see `verify_signature`.
+fn gpg_decode(file:&Path)->Result<Child,Error> {
+    let gpg=Command::new("gpg")
+        .arg("--batch")
+        .arg("--yes")
+        .arg("--status-fd").arg("2") // report error on stderr.
+        .arg("--skip-verify")
+        .arg("-d")
+        .arg(file)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    match gpg {
+        Ok(gpg)=>Ok(gpg),
+        Err(ref e) if e.kind()==std::io::ErrorKind::NotFound=>Err(Error::GPGNotFound),
+        Err(e)=>Err(Error::IO(e))
+    }
+}
+
+/// Computes the hash of the patch stored in `file`, in the format
+/// of patch files with extension `extension` (see
+/// `fs_representation::parse_patch_file_name`). This is the hash of
+/// the patch's CBOR encoding, as in `Patch::save`, so that the result
+/// can be compared to the name the patch file was downloaded under.
+/// The signature of signed patches is not checked.
+pub fn hash_patch_file(file:&Path,extension:&str)->Result<Vec<u8>,Error> {
+    let mut child=match extension {
+        "cbor" => return hash_reader(try!(File::open(file))),
+        "cbor.gpg" => try!(gpg_decode(file)),
+        "cbor.gz" => try!(Command::new("gzip")
+                          .arg("-dc")
+                          .arg(file)
+                          .stdout(Stdio::piped())
+                          .stderr(Stdio::piped())
+                          .spawn()),
+        _ => return Err(Error::UnknownPatchFormat(extension.to_string()))
+    };
+    let hash=try!(hash_reader(child.stdout.take().unwrap()));
+    let stat=try!(child.wait());
+    if stat.success() {
+        Ok(hash)
+    } else {
+        let mut buf=String::new();
+        try!(child.stderr.take().unwrap().read_to_string(&mut buf));
+        if extension=="cbor.gpg" {
+            Err(Error::GPG(stat.code().unwrap_or(-1),buf))
+        } else {
+            Err(Error::IO(std::io::Error::new(std::io::ErrorKind::Other,format!("gzip: {}",buf))))
+        }
+    }
+}
+
+/// Checks the signature of the signed patch file `file` with the keys
+/// of the user's keyring, and returns the fingerprint of the key that
+/// made it.
+pub fn verify_signature(file:&Path)->Result<String,Error> {
+    let output=match Command::new("gpg")
+        .arg("--batch")
+        .arg("--status-fd").arg("1")
+        .arg("--verify")
+        .arg(file)
+        .output() {
+            Ok(output)=>output,
+            Err(ref e) if e.kind()==std::io::ErrorKind::NotFound=>return Err(Error::GPGNotFound),
+            Err(e)=>return Err(Error::IO(e))
+        };
+    let status=String::from_utf8_lossy(&output.stdout);
+    let fingerprint=status.lines()
+        .filter_map(|l| {
+            let mut words=l.split_whitespace();
+            match (words.next(),words.next(),words.next()) {
+                (Some("[GNUPG:]"),Some("VALIDSIG"),Some(fpr))=>Some(fpr.to_string()),
+                _=>None
+            }
+        })
+        .next();
+    match fingerprint {
+        Some(fpr)=>if output.status.success() { Ok(fpr) } else { Err(gpg_error(&output)) },
+        None=>Err(gpg_error(&output))
+    }
+}
+
+fn gpg_error(output:&std::process::Output)->Error {
+    Error::GPG(output.status.code().unwrap_or(-1),String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+/// Tests whether a file name in the patches directory looks like one
+/// of the temporary files of `Patch::save`, i.e. `TMP_NAME_LEN`
+/// lowercase letters, optionally followed by ".gpg".
+pub fn is_tmp_file_name(name:&str)->bool {
+    let base= if name.ends_with(".gpg") { &name[0..name.len()-4] } else { name };
+    base.len()==TMP_NAME_LEN && base.bytes().all(|c| c>=b'a' && c<=b'z')
+}
+
 pub fn write_changes(patches:&HashSet<&[u8]>,changes_file:&Path)->Result<(),Error>{
     let file=try!(File::create(changes_file));
     let mut buffer = BufWriter::new(file);
@@ -284,6 +386,66 @@ pub fn dependencies(changes:&[Change])->HashSet<ExternalHash> {
     deps
 }
 
+/// Append `new_changes`, recorded on top of the patch of hash `hash`,
+/// to `changes`, the changes of that patch, so that the result can
+/// replace it as a single patch: the keys and edges of the patch
+/// lose their hash, like the nodes of a patch refer to each other,
+/// and the nodes of `new_changes` are numbered after those of
+/// `changes`. Returns the offset added to the line numbers of
+/// `new_changes`.
+pub fn append_changes(hash:&[u8],changes:&mut Vec<Change>,new_changes:Vec<Change>)->usize {
+    let next_line=changes.iter().map(|c| match *c {
+        Change::NewNodes { line_num, ref nodes, .. }=>line_num as usize+nodes.len(),
+        Change::Edges { .. }=>1
+    }).max().unwrap_or(1);
+    let offset=next_line-1;
+    let rewrite=|key:&mut Vec<u8>| {
+        if key.len()==LINE_SIZE {
+            let mut l=0;
+            for i in 0..LINE_SIZE { l|=(key[i] as usize) << (8*i) }
+            l+=offset;
+            for i in 0..LINE_SIZE { key[i]=(l & 0xff) as u8; l>>=8 }
+        } else if &key[0..key.len()-LINE_SIZE]==hash {
+            let line=key.split_off(key.len()-LINE_SIZE);
+            *key=line
+        }
+    };
+    for mut c in new_changes {
+        match c {
+            Change::NewNodes { ref mut up_context, ref mut down_context, ref mut line_num, .. }=>{
+                for k in up_context.iter_mut().chain(down_context.iter_mut()) {
+                    rewrite(k)
+                }
+                *line_num+=offset as u32
+            },
+            Change::Edges { ref mut edges, .. }=>{
+                for e in edges.iter_mut() {
+                    rewrite(&mut e.from);
+                    rewrite(&mut e.to);
+                    if &e.introduced_by[..]==hash {
+                        e.introduced_by.clear()
+                    }
+                }
+            }
+        }
+        changes.push(c)
+    }
+    offset
+}
+
+/// Add `offset` to the line numbers of the keys of `index`, as
+/// `append_changes` does for the nodes the keys refer to.
+pub fn shift_file_index(index:FileIndex,offset:usize)->FileIndex {
+    index.into_iter().map(|(mut key,inode)| {
+        let n=key.len()-LINE_SIZE;
+        let mut l=0;
+        for i in 0..LINE_SIZE { l|=(key[n+i] as usize) << (8*i) }
+        l+=offset;
+        for i in 0..LINE_SIZE { key[n+i]=(l & 0xff) as u8; l>>=8 }
+        (key,inode)
+    }).collect()
+}
+
 pub const HASH_SIZE:usize=20; // pub temporaire
 pub const LINE_SIZE:usize=4;
 pub const KEY_SIZE:usize=HASH_SIZE+LINE_SIZE;
@@ -0,0 +1,80 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Versioning of the pristine's on-disk format.
+//!
+//! The layout of the pristine (the names of its databases, and the
+//! sizes of keys such as `KEY_SIZE`, `EDGE_SIZE` and `INODE_SIZE`)
+//! has a version number, stored under key `VERSION_KEY` of the
+//! "schema" database. Pristines created before versioning have no
+//! such key, and are at version 0.
+//!
+//! When the format changes, increase `SCHEMA_VERSION`, and add a
+//! function converting a pristine at the previous version to
+//! `MIGRATIONS`. Migrations run in the write transaction that opens
+//! the pristine, and are hence committed along with the first
+//! operation on the upgraded repository, or not at all.
+
+use error::Error;
+use {Repository, Origin};
+
+/// The version of the format written by this version of libpijul.
+pub const SCHEMA_VERSION:u32=2;
+
+pub const VERSION_KEY:&'static [u8]=b"version";
+
+pub type Migration=fn(&mut Repository)->Result<(),Error>;
+
+/// Migrations, in order. `(v,f)` means that `f` converts a pristine
+/// at version `v-1` to version `v`.
+pub const MIGRATIONS:[(u32,Migration);2]=[
+    (1,migrate_to_1),
+    (2,migrate_to_2)
+];
+
+/// Version 1 is the format of unversioned pristines, only the
+/// version number needs to be written.
+fn migrate_to_1(_:&mut Repository)->Result<(),Error> {
+    Ok(())
+}
+
+/// Version 2 adds the "applied" database, with the order in which
+/// patches were applied, and whether they were recorded locally.
+/// Both are unknown for the patches of older pristines, which are
+/// entered in the order of their internal hashes, with origin
+/// `Origin::Unknown`. The database is emptied first, in case the
+/// pristine was only marked as older.
+fn migrate_to_2(repo:&mut Repository)->Result<(),Error> {
+    try!(repo.txn.drop(repo.dbi_applied,false));
+    for (branch,internal) in try!(repo.branch_patches()) {
+        try!(repo.record_application(&branch,&internal,Origin::Unknown))
+    }
+    Ok(())
+}
+
+pub fn encode_version(v:u32)->[u8;4] {
+    [ v as u8, (v>>8) as u8, (v>>16) as u8, (v>>24) as u8 ]
+}
+
+pub fn decode_version(v:&[u8])->Option<u32> {
+    if v.len()==4 {
+        Some((v[0] as u32) | ((v[1] as u32)<<8) | ((v[2] as u32)<<16) | ((v[3] as u32)<<24))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,210 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Replacing the last patch by a new one, made of its changes and of
+//! the changes of the working copy, possibly with a new name,
+//! description or authors.
+//!
+//! The last patch is the last one applied to the current branch, and
+//! it must have been recorded in this repository. No other patch may
+//! depend on it, and it must not be on another branch, since branches
+//! share the graph of the pristine. It must not have been pushed to a
+//! known remote either: the default pull and push remotes, and the
+//! named remotes, are asked for the patches of the current branch.
+//! If a remote cannot be reached, amend fails, unless `--force` is
+//! given.
+//!
+//! The old patch is unapplied, and the new one applied in its place.
+//! The file of the old patch is left in the patches directory, until
+//! `pijul gc` deletes it.
+
+extern crate clap;
+use clap::{SubCommand, ArgMatches, Arg};
+
+extern crate libpijul;
+use commands::StaticSubcommand;
+use self::libpijul::{Repository, Origin};
+use self::libpijul::patch::{Patch,append_changes,shift_file_index};
+use self::libpijul::fs_representation::{pristine_dir, find_repo_root, list_branches};
+
+extern crate time;
+extern crate rustc_serialize;
+use self::rustc_serialize::hex::ToHex;
+
+use commands::error::Error;
+use std::path::Path;
+use std::io::{Write,stderr};
+
+use super::super::meta::{Meta, Repository as RemoteRepository};
+use super::{get_wd, check_not_bare};
+use super::lock::RepositoryLock;
+use super::remote::parse_remote;
+use super::record::authors_of_args;
+use super::hooks;
+use super::hooks::Hook;
+
+pub fn invocation() -> StaticSubcommand {
+    return
+        SubCommand::with_name("amend")
+        .about("Replace the last patch by a new one, with the changes of the working copy")
+        .arg(Arg::with_name("repository")
+             .long("repository")
+             .help("The repository where to amend, defaults to the current directory.")
+             .takes_value(true))
+        .arg(Arg::with_name("message")
+             .short("m")
+             .long("name")
+             .help("New name of the patch.")
+             .takes_value(true))
+        .arg(Arg::with_name("description")
+             .short("d")
+             .long("description")
+             .help("New description of the patch.")
+             .takes_value(true))
+        .arg(Arg::with_name("author")
+             .short("A")
+             .long("author")
+             .help("New author of the patch (multiple occurrences allowed)")
+             .multiple(true)
+             .takes_value(true))
+        .arg(Arg::with_name("force")
+             .long("force")
+             .help("Amend even if some remotes cannot be reached to check that the patch was not pushed."))
+}
+
+pub struct Params<'a> {
+    pub repository : Option<&'a Path>,
+    pub patch_name : Option<&'a str>,
+    pub description : Option<&'a str>,
+    pub authors : Option<Vec<&'a str>>,
+    pub force : bool
+}
+
+pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
+    Params { repository : args.value_of("repository").and_then(|x| { Some(Path::new(x)) }),
+             patch_name : args.value_of("message"),
+             description : args.value_of("description"),
+             authors : args.values_of("author"),
+             force : args.is_present("force") }
+}
+
+/// The patch last applied to the current branch of the repository
+/// rooted at `r`, and its hash. Fails if that patch was not recorded
+/// in this repository.
+fn last_patch(r:&Path, repo:&Repository)->Result<(Vec<u8>,Patch),Error> {
+    match try!(repo.last_application()) {
+        Some((h,Origin::Local))=>{
+            let patch=try!(Patch::from_repository(r,&h));
+            Ok((h,patch))
+        },
+        Some((_,Origin::Remote))=>Err(Error::CannotAmend("the last patch was not recorded in this repository".to_string())),
+        Some((_,Origin::Unknown))=>Err(Error::CannotAmend("the last patch is unknown, the patches were applied by an older version of pijul".to_string())),
+        None=>Err(Error::CannotAmend("there is no patch".to_string()))
+    }
+}
+
+/// Whether the patch `hash` is on branch `branch` of the remote at `url`.
+fn pushed_to(r:&Path, url:&str, port:Option<u64>, branch:&[u8], hash:&[u8])->Result<bool,Error> {
+    let remote=parse_remote(url,port,Some(r));
+    let mut session=try!(remote.session());
+    Ok(try!(session.changes(branch)).contains(hash))
+}
+
+/// Fail if the patch `hash` is on branch `branch` of one of the
+/// remotes known to the repository rooted at `r`, or if one of them
+/// cannot be reached, unless `force` is set.
+fn check_not_pushed(r:&Path, meta:&Meta, branch:&[u8], hash:&[u8], force:bool)->Result<(),Error> {
+    let mut remotes:Vec<(&str,Option<u64>)>=Vec::new();
+    for repository in meta.pull.iter().chain(meta.push.iter()) {
+        remotes.push(match *repository {
+            RemoteRepository::SSH { ref address, port }=>meta.resolve_remote(address,Some(port)),
+            RemoteRepository::String(ref host)=>meta.resolve_remote(host,None)
+        })
+    }
+    if let Some(ref named)=meta.remotes {
+        for remote in named.values() {
+            remotes.push((&remote.url[..],remote.port))
+        }
+    }
+    remotes.sort();
+    remotes.dedup();
+    for &(url,port) in remotes.iter() {
+        debug!("amend: looking for {} in {}",hash.to_hex(),url);
+        match pushed_to(r,url,port,branch,hash) {
+            Ok(true)=>return Err(Error::CannotAmend(format!("it has been pushed to {}",url))),
+            Ok(false)=>{},
+            Err(e)=>if force {
+                let _=writeln!(stderr(),"Warning: could not check whether the patch was pushed to {} ({})",url,e);
+            } else {
+                return Err(Error::CannotAmend(format!("could not check whether it was pushed to {} ({}), use --force to amend anyway",url,e)))
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn run(args : &Params) -> Result<Option<()>, Error> {
+    let wd=try!(get_wd(args.repository));
+    match find_repo_root(&wd){
+        None => return Err(Error::NotInARepository),
+        Some(ref r) =>
+        {
+            try!(check_not_bare(r));
+            let _lock=try!(RepositoryLock::acquire(r));
+            let (branch,hash,patch)={
+                let repo=try!(Repository::new_readonly(&pristine_dir(r)));
+                let (hash,patch)=try!(last_patch(r,&repo));
+                if let Some(d)=try!(repo.dependents(&hash)).first() {
+                    return Err(Error::CannotAmend(format!("patch {} depends on it",d.to_hex())))
+                }
+                let branch=repo.get_current_branch().to_vec();
+                for b in try!(list_branches(r)) {
+                    if b!=branch && try!(repo.has_patch(&b,&hash)) {
+                        return Err(Error::CannotAmend(format!("it is also on branch {}",String::from_utf8_lossy(&b))))
+                    }
+                }
+                (branch,hash,patch)
+            };
+            let meta=Meta::load(r).unwrap_or(Meta::new());
+            try!(check_not_pushed(r,&meta,&branch,&hash,args.force));
+
+            let mut repo=try!(Repository::new(&pristine_dir(r)));
+            let (new_changes,syncs)=try!(repo.record(r));
+            if new_changes.is_empty() && args.patch_name.is_none()
+                && args.description.is_none() && args.authors.is_none() {
+                println!("Nothing to amend");
+                return Ok(None)
+            }
+            let Patch { authors, name, description, mut changes, .. }=patch;
+            let offset=append_changes(&hash,&mut changes,new_changes);
+            let patch=Patch::new(match args.authors {
+                                     Some(ref authors)=>authors_of_args(authors),
+                                     None=>authors
+                                 },
+                                 args.patch_name.map(|x| x.to_string()).unwrap_or(name),
+                                 args.description.map(|x| x.to_string()).or(description),
+                                 self::time::now().to_timespec().sec,
+                                 changes);
+            try!(hooks::run(r,Hook::PreRecord,&[],hooks::summary(&patch).as_bytes()));
+            let new_hash=try!(repo.replace_patch(r,&hash,patch,&shift_file_index(syncs,offset)));
+            try!(repo.commit());
+            try!(hooks::run_with_hashes(r,Hook::PostRecord,[new_hash].iter()));
+            Ok(Some(()))
+        }
+    }
+}
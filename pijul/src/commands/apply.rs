@@ -22,11 +22,14 @@ use clap::{SubCommand, ArgMatches, Arg};
 extern crate libpijul;
 use libpijul::{Repository};
 use commands::StaticSubcommand;
-use self::libpijul::{DEFAULT_BRANCH};
 use self::libpijul::patch::{read_changes_from_file};
 use self::libpijul::fs_representation::{pristine_dir, find_repo_root, branch_changes_file};
 
 use commands::error::Error;
+use super::lock::RepositoryLock;
+use super::hooks;
+use super::policy;
+use super::hooks::Hook;
 use std::collections::{HashSet};
 
 use std::path::{Path};
@@ -77,16 +80,55 @@ pub fn run(params : &Params) -> Result<Option<()>, Error> {
                 h
             };
             debug!("remote={:?}",remote);
-            let local:HashSet<Vec<u8>>={
-                let changes_file=branch_changes_file(target,DEFAULT_BRANCH.as_bytes());
-                read_changes_from_file(&changes_file).unwrap_or(HashSet::new())
-            };
-            debug!("local={:?}",local);
-            let repo_dir=pristine_dir(target);
-            let mut repo = try!(Repository::new(&repo_dir));
-            try!(repo.apply_patches(target,&remote,&local));
-            try!(repo.commit());
+            let branch=try!(current_branch(target));
+            try!(apply_patches(target,&branch,&remote));
             Ok(Some(()))
         }
     }
 }
+
+/// The current branch of the repository rooted at `target`, the only
+/// one patches can be applied to: all branches share the graph of the
+/// pristine, which is the graph of the current branch, and the other
+/// branches are only lists of patches. Pulls and pushes to another
+/// branch are therefore refused with `Error::NotCurrentBranch`, until
+/// the pristine keeps a graph per branch.
+pub fn current_branch(target:&Path)->Result<Vec<u8>,Error> {
+    let repo = try!(Repository::new_readonly(&pristine_dir(target)));
+    Ok(repo.get_current_branch().to_vec())
+}
+
+/// Fail unless `branch` is the current branch of `target`.
+pub fn check_current_branch(target:&Path, branch:&[u8])->Result<(),Error> {
+    let current=try!(current_branch(target));
+    if &current[..]!=branch {
+        Err(Error::NotCurrentBranch(String::from_utf8_lossy(branch).into_owned(),
+                                    String::from_utf8_lossy(&current).into_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Apply the patches in `remote`, which must already be in the
+/// patches directory of `target`, to `branch`, the current branch of
+/// the repository rooted at `target`. The new patches are checked
+/// first (see module `policy`), and nothing is applied if one of them
+/// is rejected.
+pub fn apply_patches(target:&Path, branch:&[u8], remote:&HashSet<Vec<u8>>)->Result<(),Error> {
+    let _lock=try!(RepositoryLock::acquire(target));
+    try!(check_current_branch(target,branch));
+    let local:HashSet<Vec<u8>>={
+        let changes_file=branch_changes_file(target,branch);
+        read_changes_from_file(&changes_file).unwrap_or(HashSet::new())
+    };
+    debug!("local={:?}",local);
+    let repo_dir=pristine_dir(target);
+    let mut repo = try!(Repository::new(&repo_dir));
+    try!(policy::check_patches(target,&mut repo,remote,&local));
+    let applied:Vec<Vec<u8>>=remote.difference(&local).cloned().collect();
+    try!(hooks::run_with_hashes(target,Hook::PreApply,applied.iter()));
+    try!(repo.apply_patches(target,remote,&local));
+    try!(repo.commit());
+    try!(hooks::run_with_hashes(target,Hook::PostApply,applied.iter()));
+    Ok(())
+}
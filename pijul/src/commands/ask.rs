@@ -10,6 +10,9 @@ extern crate time;
 use self::time::{Duration};
 //use std::path::Path;
 use std::io::{stdout};
+use std::env;
+use std::process;
+use std::path::PathBuf;
 use std::collections::{HashMap,HashSet,BTreeMap};
 #[cfg(not(windows))]
 extern crate termios;
@@ -117,28 +120,126 @@ fn print_patch_descr(hash:&[u8],patch:&Patch) {
     match patch.description { Some(ref d)=>println!("  {}",d), None=>{} };
 }
 
-/// Patches might have a dummy "changes" field here.
-pub fn ask_apply<'a>(command_name:Command,
-                     patches:&'a [(&'a[u8],Patch)])->Result<HashSet<Vec<u8>>,Error> {
+/// Print the description of `patches`, each followed by the patches
+/// of `patches` it depends on, directly or not.
+pub fn print_patches(patches:&[(&[u8],Patch)]) {
+    let dependencies:HashMap<&[u8],&Patch>=patches.iter().map(|&(h,ref p)| (h,p)).collect();
+    for &(hash,ref patch) in patches {
+        print_patch_descr(hash,patch);
+        let mut closure=HashSet::new();
+        let mut stack:Vec<&[u8]>=patch.dependencies.iter().map(|d| &d[..]).collect();
+        while let Some(d)=stack.pop() {
+            if let Some(dep)=dependencies.get(d) {
+                if closure.insert(d) {
+                    stack.extend(dep.dependencies.iter().map(|d| &d[..]))
+                }
+            }
+        }
+        if closure.len()>0 {
+            println!("  Depends on:");
+            for d in closure {
+                println!("    {}",d.to_hex())
+            }
+        }
+        println!("");
+    }
+}
+
+/// Show `text` in the pager given by the `PAGER` environment
+/// variable, or print it if the pager cannot be run.
+fn page(text:&[u8])->Result<(),Error> {
+    if let Ok(mut child)=pager_command().stdin(process::Stdio::piped()).spawn() {
+        if let Some(mut stdin)=child.stdin.take() {
+            // The pager might quit before reading everything.
+            let _=stdin.write_all(text);
+        }
+        if let Ok(status)=child.wait() {
+            if status.success() { return Ok(()) }
+        }
+    }
+    try!(stdout().write_all(text));
+    Ok(())
+}
+#[cfg(not(windows))]
+fn pager_command()->process::Command {
+    let mut cmd=process::Command::new("sh");
+    cmd.arg("-c").arg(env::var("PAGER").unwrap_or("less".to_string()));
+    cmd
+}
+#[cfg(windows)]
+fn pager_command()->process::Command {
+    process::Command::new(env::var("PAGER").unwrap_or("more".to_string()))
+}
+
+/// The changes of `patch`, rendered as in `record`.
+pub fn patch_text<'a>(repo:&Repository<'a>,
+                      patches:&HashMap<&[u8],&Patch>,
+                      hash:&[u8],
+                      patch:&Patch)->Result<Vec<u8>,Error> {
+    let mut text=Vec::new();
+    try!(writeln!(text,"Hash: {}",hash.to_hex()));
+    try!(writeln!(text,"  * {}\n",patch.name));
+    for c in patch.changes.iter() {
+        try!(write_change(&mut text,repo,patches,Some(patch),c))
+    }
+    Ok(text)
+}
+
+/// Show the changes of `patch` in the pager.
+fn view_patch<'a>(repo:&Repository<'a>,
+                  patches:&HashMap<&[u8],&Patch>,
+                  hash:&[u8],
+                  patch:&Patch)->Result<(),Error> {
+    page(&try!(patch_text(repo,patches,hash,patch)))
+}
+
+fn print_apply_help(command_name:Command) {
+    let verb=match command_name { Command::Push=>"push", Command::Pull=>"pull" };
+    println!("y: {} this patch",verb);
+    println!("n: do not {} this patch",verb);
+    println!("k: go back to the previous patch");
+    println!("a: {} this patch and all the remaining ones",verb);
+    println!("d: do not {} this patch, nor any of the remaining ones",verb);
+    println!("v: view the changes of this patch");
+    println!("?: show this help");
+    println!("Patches are selected along with the patches they depend on.");
+}
+
+/// Ask which of `patches` to push or pull. Their dependencies in the
+/// pristine of `repository` are used to show their changes.
+pub fn ask_apply<'a,'b>(command_name:Command,
+                        repository:&Repository<'b>,
+                        patches:&'a [(&'a[u8],Patch)])->Result<HashSet<Vec<u8>>,Error> {
     //let patches_path=patches_dir(repo_path);
     try!(init_getch());
     let mut i=0;
     let mut choices=HashMap::new();
     let mut rev_dependencies:HashMap<&[u8],Vec<&[u8]>>=HashMap::new();
+    let by_hash:HashMap<&[u8],&Patch>=patches.iter().map(|&(h,ref p)| (h,p)).collect();
     let mut final_decision=None;
     while i < patches.len() {
         let (ref a,ref b)=patches[i];
         let decision= {
-            if match rev_dependencies.get(*a) { Some(x)=>x.iter().any(|y| *(choices.get(y).unwrap_or(&false))), None=>false } {
+            let selected_by=rev_dependencies.get(*a).and_then(|x| x.iter().find(|y| *(choices.get(*y).unwrap_or(&false))));
+            let unselected_dep=b.dependencies.iter().find(|x| { ! *(choices.get(&x[..]).unwrap_or(&true)) });
+            if let Some(x)=selected_by {
                 // First case: this patch is a dependency of a selected patch.
                 // We must select it.
 
                 // x is the list of patches that depend on a.
                 // if any of these is selected, select a.
+                if final_decision.is_none() {
+                    println!("Selecting \"{}\", since \"{}\" depends on it.",
+                             b.name,by_hash.get(*x).map(|p| &p.name[..]).unwrap_or(""));
+                }
                 Some(true)
-            } else if b.dependencies.iter().any(|x| { ! *(choices.get(&x[..]).unwrap_or(&true)) }) {
+            } else if let Some(x)=unselected_dep {
                 // Second case: this patch dependends on an unselected patch.
                 // We must unselect it.
+                if final_decision.is_none() {
+                    println!("Skipping \"{}\", since it depends on \"{}\", which is not selected.",
+                             b.name,by_hash.get(&x[..]).map(|p| &p.name[..]).unwrap_or(""));
+                }
                 Some(false)
             } else {
                 None
@@ -151,7 +252,7 @@ pub fn ask_apply<'a>(command_name:Command,
                 match final_decision {
                     None => {
                         print_patch_descr(a,b);
-                        print!("{} [ynkad] ",
+                        print!("{} [ynkadv?] ",
                                match command_name {
                                    Command::Push => "Shall I push this patch?",
                                    Command::Pull => "Shall I pull this patch?"
@@ -195,6 +296,12 @@ pub fn ask_apply<'a>(command_name:Command,
                 choices.remove(a);
                 i-=1
             },
+            'V' => {
+                try!(end_getch());
+                try!(view_patch(repository,&by_hash,a,b));
+                try!(init_getch());
+            },
+            '?' => print_apply_help(command_name),
             _=>{}
         }
     }
@@ -254,16 +361,76 @@ fn change_deps(id:usize,c:&Change,provided_by:&mut HashMap<u32,usize>)->HashSet<
     s
 }
 
-fn print_change<'a>(repo:&Repository<'a>,c:&Change)->Result<(),Error> {
+/// The line number in an external key.
+fn line_of_key(key:&[u8])->u32 {
+    let l=&key[key.len()-LINE_SIZE..];
+    (l[0] as u32) | ((l[1] as u32) << 8) | ((l[2] as u32) << 16) | ((l[3] as u32) << 24)
+}
+
+/// The contents of the line whose external key is `key`, looked up in
+/// `patches` if it was introduced by one of them, else in the
+/// pristine. Lines introduced by `patch` itself have no hash in its
+/// keys. `None` if the line is in none of them.
+fn line_contents<'a>(repo:&Repository<'a>,
+                     patches:&HashMap<&[u8],&Patch>,
+                     patch:Option<&Patch>,
+                     key:&[u8])->Result<Option<Vec<u8>>,Error> {
+    if key.len()<LINE_SIZE {
+        return Ok(None)
+    }
+    let hash=&key[0..key.len()-LINE_SIZE];
+    let source= if hash.len()==0 {
+        match patch {
+            Some(patch)=>Some(patch),
+            // Lines of a record that is not a patch yet.
+            None=>return Ok(None)
+        }
+    } else {
+        patches.get(hash).map(|p| *p)
+    };
+    if let Some(source)=source {
+        let line=line_of_key(key);
+        for c in source.changes.iter() {
+            if let Change::NewNodes { ref line_num, ref nodes, .. }=*c {
+                if line >= *line_num && line < *line_num+nodes.len() as u32 {
+                    return Ok(Some(nodes[(line - *line_num) as usize].clone()))
+                }
+            }
+        }
+        return Ok(None)
+    }
+    let int=match repo.internal_hash(hash) {
+        Ok(int)=>int,
+        Err(libpijul::error::Error::InternalHashNotFound(_))=>return Ok(None),
+        Err(e)=>return Err(Error::Repository(e))
+    };
+    let mut internal=[0;KEY_SIZE];
+    unsafe {
+        copy_nonoverlapping(int.contents.as_ptr(),internal.as_mut_ptr(),HASH_SIZE);
+        copy_nonoverlapping(key.as_ptr().offset((key.len() - LINE_SIZE) as isize),
+                            internal.as_mut_ptr().offset(HASH_SIZE as isize),
+                            LINE_SIZE)
+    };
+    Ok(Some(repo.contents(&internal[..]).to_vec()))
+}
+
+/// Write change `c` of `patch` (or of the patch being recorded, if
+/// `patch` is `None`) to `w`. The lines it deletes are looked up with
+/// `line_contents`.
+pub fn write_change<'a,W:Write>(w:&mut W,
+                            repo:&Repository<'a>,
+                            patches:&HashMap<&[u8],&Patch>,
+                            patch:Option<&Patch>,
+                            c:&Change)->Result<(),Error> {
     match *c {
         Change::NewNodes{/*ref up_context,ref down_context,ref line_num,*/ref flag,ref nodes,..}=>{
             for n in nodes {
                 if *flag & FOLDER_EDGE != 0 {
                     if n.len()>=2 {
-                        println!("new file {}",str::from_utf8(&n[2..]).unwrap_or(""));
+                        try!(writeln!(w,"new file {}",str::from_utf8(&n[2..]).unwrap_or("")));
                     }
                 } else {
-                    print!("+ {}",str::from_utf8(n).unwrap_or(""));
+                    try!(write!(w,"+ {}",str::from_utf8(n).unwrap_or("")));
                 }
             }
             Ok(())
@@ -278,15 +445,12 @@ fn print_change<'a>(repo:&Repository<'a>,c:&Change)->Result<(),Error> {
                         if h_targets.insert(&e.from) { Some(&e.from) } else { None }
                     };
                 if let Some(target)=target {
-                    let int=try!(repo.internal_hash(&target[0..target.len()-LINE_SIZE]));
-                    let mut internal=[0;KEY_SIZE];
-                    unsafe {
-                        copy_nonoverlapping(int.contents.as_ptr(),internal.as_mut_ptr(),HASH_SIZE);
-                        copy_nonoverlapping(target.as_ptr().offset((target.len() - LINE_SIZE) as isize),
-                                            internal.as_mut_ptr().offset(HASH_SIZE as isize),
-                                            LINE_SIZE)
-                    };
-                    print!("- {}",str::from_utf8(repo.contents(&internal[..])).unwrap_or(""));
+                    match try!(line_contents(repo,patches,patch,target)) {
+                        Some(line)=>try!(write!(w,"- {}",str::from_utf8(&line).unwrap_or(""))),
+                        None=>try!(writeln!(w,"- (line {} of patch {})",
+                                            line_of_key(target),
+                                            target[0..target.len()-LINE_SIZE].to_hex()))
+                    }
                 }
             }
             Ok(())
@@ -294,11 +458,69 @@ fn print_change<'a>(repo:&Repository<'a>,c:&Change)->Result<(),Error> {
     }
 }
 
-pub fn ask_record<'a>(repository:&Repository<'a>,changes:&[Change])->Result<HashMap<usize,bool>,Error> {
-    try!(init_getch());
-    let mut i=0;
-    let mut choices:HashMap<usize,bool>=HashMap::new();
-    let mut final_decision=None;
+/// The contents of the lines with keys `keys`, when they are known
+/// and not empty.
+fn context_lines<'a>(repo:&Repository<'a>,keys:&[Vec<u8>])->Result<Vec<Vec<u8>>,Error> {
+    let mut lines=Vec::new();
+    for k in keys {
+        if let Some(line)=try!(line_contents(repo,&HashMap::new(),None,k)) {
+            if line.len()>0 {
+                lines.push(line)
+            }
+        }
+    }
+    Ok(lines)
+}
+
+/// Print a change of the patch being recorded, along with the lines
+/// around it if it adds lines to a file.
+fn print_change<'a>(repo:&Repository<'a>,c:&Change)->Result<(),Error> {
+    match *c {
+        Change::NewNodes { ref up_context, ref down_context, ref flag, .. } if *flag & FOLDER_EDGE == 0 => {
+            for l in try!(context_lines(repo,up_context)) {
+                print!("  {}",str::from_utf8(&l).unwrap_or(""))
+            }
+            try!(write_change(&mut stdout(),repo,&HashMap::new(),None,c));
+            for l in try!(context_lines(repo,down_context)) {
+                print!("  {}",str::from_utf8(&l).unwrap_or(""))
+            }
+            Ok(())
+        },
+        _=>write_change(&mut stdout(),repo,&HashMap::new(),None,c)
+    }
+}
+
+/// Split the lines added by `c` in two halves, if it adds at least
+/// two lines to a file. The second half starts after the last line of
+/// the first one, and hence depends on it. Only the second half keeps
+/// the down context of `c`, so that recording both halves does not add
+/// a redundant edge from the first half to that context.
+pub fn split_change(c:&Change)->Option<(Change,Change)> {
+    match *c {
+        Change::NewNodes { ref up_context, ref down_context, ref flag, ref line_num, ref nodes }
+        if *flag & FOLDER_EDGE == 0 && nodes.len()>1 => {
+            let half=nodes.len()/2;
+            let mut last=*line_num+half as u32-1;
+            let mut key=Vec::with_capacity(LINE_SIZE);
+            for _ in 0..LINE_SIZE { key.push((last & 0xff) as u8); last>>=8 }
+            Some((Change::NewNodes { up_context:up_context.clone(),
+                                     down_context:vec!(),
+                                     flag:*flag,
+                                     line_num:*line_num,
+                                     nodes:nodes[0..half].to_vec() },
+                  Change::NewNodes { up_context:vec!(key),
+                                     down_context:down_context.clone(),
+                                     flag:*flag,
+                                     line_num:*line_num+half as u32,
+                                     nodes:nodes[half..].to_vec() }))
+        },
+        _=>None
+    }
+}
+
+/// For each change, the changes it depends on, and the changes that
+/// depend on it.
+pub fn record_dependencies(changes:&[Change])->(HashMap<usize,Vec<usize>>,HashMap<usize,Vec<usize>>) {
     let mut provided_by=HashMap::new();
     let mut line_deps=Vec::with_capacity(changes.len());
     for i in 0..changes.len() {
@@ -319,6 +541,36 @@ pub fn ask_record<'a>(repository:&Repository<'a>,changes:&[Change])->Result<Hash
             e.push(i);
         }
     }
+    (deps,rev_deps)
+}
+
+fn print_record_help() {
+    println!("y: record this change");
+    println!("n: do not record this change");
+    println!("k: go back to the previous change");
+    println!("f: record this change and the remaining ones in the same file");
+    println!("s: skip this change and the remaining ones in the same file");
+    println!("c: split this change in two");
+    println!("a: record this change and all the remaining ones");
+    println!("d: do not record this change, nor any of the remaining ones");
+    println!("?: show this help");
+}
+
+/// Ask which of `changes` to record, `files[i]` being the file of
+/// `changes[i]` (see `Repository::record_files`). Changes split by
+/// the user are replaced by their parts in `changes` and `files`, and
+/// the result is indexed by the final positions of the changes.
+pub fn ask_record<'a>(repository:&Repository<'a>,
+                      changes:&mut Vec<Change>,
+                      files:&mut Vec<PathBuf>)->Result<HashMap<usize,bool>,Error> {
+    try!(init_getch());
+    let mut i=0;
+    let mut choices:HashMap<usize,bool>=HashMap::new();
+    let mut final_decision=None;
+    // Decision for the remaining changes of a file, with 'f' or 's'.
+    let mut file_decision:Option<(PathBuf,char)>=None;
+    let mut current_file:Option<PathBuf>=None;
+    let (mut deps,mut rev_deps)=record_dependencies(changes);
     let empty_deps=Vec::new();
     while i < changes.len() {
         let decision=
@@ -331,14 +583,22 @@ pub fn ask_record<'a>(repository:&Repository<'a>,changes:&[Change])->Result<Hash
             } else {
                 None
             };
+        let file_decision_here=match file_decision {
+            Some((ref f,d)) if *f==files[i] => Some(d),
+            _=>None
+        };
         let e=match decision {
             Some(true)=>'Y',
             Some(false)=>'N',
             None=>{
-                match final_decision {
+                match final_decision.or(file_decision_here) {
                     None => {
+                        if current_file.as_ref()!=Some(&files[i]) {
+                            println!("In file {}:",files[i].display());
+                            current_file=Some(files[i].clone())
+                        }
                         try!(print_change(repository,&changes[i]));
-                        print!("Shall I record this change? [ynkad] ");
+                        print!("Shall I record this change? [ynkfscad?] ");
                         try!(stdout().flush());
                         match getch() {
                             Ok(e)=> {
@@ -348,6 +608,8 @@ pub fn ask_record<'a>(repository:&Repository<'a>,changes:&[Change])->Result<Hash
                                 match e {
                                     'A'=> { final_decision=Some('Y'); 'Y' },
                                     'D'=> { final_decision=Some('N'); 'N' },
+                                    'F'=> { file_decision=Some((files[i].clone(),'Y')); 'Y' },
+                                    'S'=> { file_decision=Some((files[i].clone(),'N')); 'N' },
                                     e=>e
                                 }
                             },
@@ -371,8 +633,26 @@ pub fn ask_record<'a>(repository:&Repository<'a>,changes:&[Change])->Result<Hash
             },
             'K' if i>0 => {
                 choices.remove(&i);
+                file_decision=None;
+                current_file=None;
                 i-=1
             },
+            'C' => {
+                if let Some((a,b))=split_change(&changes[i]) {
+                    changes[i]=a;
+                    changes.insert(i+1,b);
+                    let file=files[i].clone();
+                    files.insert(i+1,file);
+                    // Choices made after going back with 'k' move too.
+                    choices=choices.into_iter().map(|(j,c)| if j>i { (j+1,c) } else { (j,c) }).collect();
+                    let (d,r)=record_dependencies(changes);
+                    deps=d;
+                    rev_deps=r;
+                } else {
+                    println!("This change cannot be split.")
+                }
+            },
+            '?' => print_record_help(),
             _=>{}
         }
     }
@@ -400,3 +680,24 @@ pub fn ask_patch_name()->Result<String,Error> {
     if let Some(c)=input.pop() { if c!='\n' { input.push(c) } }
     Ok(input)
 }
+
+/// Whether the user can answer questions on the standard input.
+#[cfg(not(windows))]
+pub fn stdin_is_terminal()->bool {
+    self::termios::Termios::from_fd(0).is_ok()
+}
+#[cfg(windows)]
+pub fn stdin_is_terminal()->bool {
+    true
+}
+
+pub fn ask_trust_host(host:&str,fingerprint:&str)->Result<bool,Error> {
+    println!("The authenticity of host {} can't be established.",host);
+    println!("Its key fingerprint is {}.",fingerprint);
+    print!("Do you want to trust this host and add its key to known_hosts? [yes/no] ");
+    try!(std::io::stdout().flush());
+    let mut input = String::new();
+    try!(stdin().read_line(&mut input));
+    let input=input.trim().to_lowercase();
+    Ok(input=="yes" || input=="y")
+}
@@ -50,7 +50,7 @@ pub fn run(args: &Params) -> Result<(),Error> {
     let wd=try!(get_wd(args.repository));
     match find_repo_root(&wd) {
         Some(ref repo_base) => {
-            let _repository = Repository::new(&pristine_dir(&repo_base)).unwrap(); //.expect("Repository error");
+            let _repository = try!(Repository::new_readonly(&pristine_dir(&repo_base)));
             println!("Your repo looks alright Ma'am/Sir");
             Ok(())
         },
@@ -22,9 +22,15 @@ use clap::{SubCommand, ArgMatches,Arg};
 use super::StaticSubcommand;
 
 use super::init;
+use super::lock::RepositoryLock;
 
 use super::error::Error;
-use super::remote::{Remote,parse_remote};
+use super::remote::{Remote,parse_remote,DEFAULT_JOBS};
+use super::get_wd;
+use super::super::meta::Meta;
+extern crate libpijul;
+use self::libpijul::fs_representation::find_repo_root;
+use self::libpijul::DEFAULT_BRANCH;
 extern crate regex;
 use self::regex::Regex;
 
@@ -33,7 +39,7 @@ pub fn invocation() -> StaticSubcommand {
         SubCommand::with_name("clone")
         .about("clone a remote repository")
         .arg(Arg::with_name("from")
-             .help("Repository to clone.")
+             .help("Repository to clone, or the name of a remote of the current repository.")
              .required(true)
              )
         .arg(Arg::with_name("to")
@@ -49,53 +55,80 @@ pub fn invocation() -> StaticSubcommand {
                                           Err(_)=>Err(val) }
              })
              )
+        .arg(Arg::with_name("jobs")
+             .short("j")
+             .long("jobs")
+             .help("Number of patches downloaded in parallel (default 4).")
+             .takes_value(true)
+             .validator(|val| { let x:Result<usize,_>=val.parse();
+                                match x { Ok(n) if n>0 =>Ok(()),
+                                          _=>Err(val) }
+             })
+             )
+        .arg(Arg::with_name("bare")
+             .long("bare")
+             .help("Create a bare repository, without a working copy.")
+             )
 }
 #[derive(Debug)]
 pub struct Params<'a> {
-    pub from:Remote<'a>,
-    pub to:Remote<'a>
+    pub from:&'a str,
+    pub to:Option<&'a str>,
+    pub port:Option<u64>,
+    pub jobs:usize,
+    pub bare:bool
 }
 
 pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
+    Params { from:args.value_of("from").unwrap(),
+             to:args.value_of("to"),
+             port:args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) }),
+             jobs:args.value_of("jobs").and_then(|x| { Some(x.parse().unwrap()) }).unwrap_or(DEFAULT_JOBS),
+             bare:args.is_present("bare") }
+}
+
+
+
+pub fn run<'a>(args : &Params<'a>) -> Result<(), Error> {
+    debug!("{:?}",args);
+    // Names of remotes are those of the repository we are in, if any.
+    let meta=match find_repo_root(&try!(get_wd(None))) {
+        Some(r)=>Meta::load(&r).unwrap_or(Meta::new()),
+        None=>Meta::new()
+    };
+    let (from_url,port)=meta.resolve_remote(args.from,args.port);
+    let from_branch=meta.default_branch(args.from).unwrap_or(DEFAULT_BRANCH).as_bytes();
     // At least one must not use its "port" argument
-    let from = parse_remote(args.value_of("from").unwrap(),args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) }),
-                            None);
+    let from=parse_remote(from_url,port,None);
     let to =
-        if let Some(to)=args.value_of("to") {
-            parse_remote(to,args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) }), None)
+        if let Some(to)=args.to {
+            parse_remote(to,port,None)
         } else {
             let basename=Regex::new(r"([^/:]*)").unwrap();
-            let from=args.value_of("from").unwrap();
-            if let Some(to)=basename.captures_iter(from).last().and_then(|to| { to.at(1) }) {
-                parse_remote(to,args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) }), None)
+            if let Some(to)=basename.captures_iter(from_url).last().and_then(|to| { to.at(1) }) {
+                parse_remote(to,port,None)
             } else {
                 panic!("Could not parse target")
             }
         };
-    Params { from:from, to:to }
-}
-
-
-
-pub fn run<'a>(args : &Params<'a>) -> Result<(), Error> {
-    debug!("{:?}",args);
-    match args.from {
+    match from {
         Remote::Local{ref path}=>{
-            let mut to_session=try!(args.to.session());
+            let mut to_session=try!(to.session());
             debug!("remote init");
-            try!(to_session.remote_init());
+            try!(to_session.remote_init(args.bare));
             debug!("pushable?");
-            let pushable=try!(to_session.pushable_patches(path));
+            let pushable=try!(to_session.pushable_patches(path,from_branch,DEFAULT_BRANCH.as_bytes()));
             debug!("pushable = {:?}",pushable);
-            to_session.push(path,&pushable)
+            to_session.push(path,DEFAULT_BRANCH.as_bytes(),&pushable)
         },
-        _=>match args.to {
+        _=>match to {
             Remote::Local{ref path} =>{
                 // This is "darcs get"
-                try!(init::run(&init::Params { location:path, allow_nested:false }));
-                let mut session=try!(args.from.session());
-                let pullable=try!(session.pullable_patches(path));
-                session.pull(path,&pullable)
+                try!(init::run(&init::Params { location:path, allow_nested:false, bare:args.bare }));
+                let _lock=try!(RepositoryLock::acquire(path));
+                let mut session=try!(from.session());
+                let pullable=try!(session.pullable_patches(path,from_branch,DEFAULT_BRANCH.as_bytes()));
+                session.pull(path,&pullable,args.jobs)
             },
             _=>unimplemented!()
         }
@@ -0,0 +1,240 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Editing records and patch messages in a text editor, given by the
+//! caller, or else by the `VISUAL` or `EDITOR` environment variables
+//! (`vi` by default).
+
+extern crate libpijul;
+use self::libpijul::Repository;
+use self::libpijul::patch::{Change,Value};
+use self::libpijul::fs_representation::repo_dir;
+
+use std::path::{Path,PathBuf};
+use std::fs::{File,remove_file};
+use std::io::{Read,Write};
+use std::env;
+use std::process::Command;
+use std::collections::{HashMap,HashSet,BTreeMap};
+
+use super::error::Error;
+use super::ask::{write_change,record_dependencies};
+
+/// Name of the file edited in the `.pijul` directory.
+const EDIT_FILE_NAME:&'static str="edit";
+
+/// The editor given by the environment.
+pub fn default_editor()->String {
+    env::var("VISUAL").or(env::var("EDITOR")).unwrap_or("vi".to_string())
+}
+
+/// Let the user edit `text` with the shell command `editor`, in a file
+/// of the `.pijul` directory of the repository rooted at `repo_root`,
+/// and return the result.
+pub fn edit_with(editor:&str, repo_root:&Path, text:&str)->Result<String,Error> {
+    let path=repo_dir(repo_root).join(EDIT_FILE_NAME);
+    try!(try!(File::create(&path)).write_all(text.as_bytes()));
+    let status=try!(Command::new("sh").arg("-c").arg(format!("{} \"$1\"",editor)).arg("sh").arg(&path).status());
+    if !status.success() {
+        return Err(Error::EditorFailed(editor.to_string(),status.code()))
+    }
+    let mut result=String::new();
+    try!(try!(File::open(&path)).read_to_string(&mut result));
+    try!(remove_file(&path));
+    Ok(result)
+}
+
+/// The result of `edit_record`.
+pub struct EditedRecord {
+    /// Indices of the changes to record.
+    pub selected:HashSet<usize>,
+    pub name:Option<String>,
+    pub authors:Vec<BTreeMap<String,Value>>,
+    pub description:Option<String>
+}
+
+const RECORD_HELP:&'static str=
+"# Edit the patch to record, then save and quit.
+# The name of the patch, its authors (one \"author:\" line each) and its
+# description come first. The description goes on until the first hunk.
+# Remove a hunk, from its \"@@\" line to the next one, to leave it out of
+# the patch. The contents of the hunks cannot be changed.
+# Lines starting with '#' are ignored. Remove everything to cancel.
+";
+
+/// Write the name, authors and description of a patch, followed by
+/// `changes`, one hunk per block.
+fn record_text<'a>(repo:&Repository<'a>,
+                   changes:&[Change],
+                   files:&[PathBuf],
+                   name:Option<&str>,
+                   authors:&[BTreeMap<String,Value>],
+                   description:Option<&str>)->Result<Vec<u8>,Error> {
+    let mut text=Vec::new();
+    try!(write!(text,"{}",RECORD_HELP));
+    try!(writeln!(text,"name: {}",name.unwrap_or("")));
+    for a in authors {
+        if let Some(&Value::String(ref n))=a.get("name") {
+            try!(writeln!(text,"author: {}",n))
+        }
+    }
+    try!(writeln!(text,"description:"));
+    if let Some(d)=description {
+        try!(writeln!(text,"{}",d))
+    }
+    for (i,c) in changes.iter().enumerate() {
+        try!(writeln!(text,"\n@@ {} {}",i+1,files[i].display()));
+        try!(write_change(&mut text,repo,&HashMap::new(),None,c));
+        if text.last()!=Some(&b'\n') {
+            text.push(b'\n')
+        }
+    }
+    Ok(text)
+}
+
+/// Parse the file written by `record_text` and edited by the user.
+/// Returns `None` if nothing but comments is left.
+fn parse_record(text:&str,n_changes:usize)->Option<EditedRecord> {
+    let mut record=EditedRecord { selected:HashSet::new(), name:None, authors:Vec::new(), description:None };
+    let mut description=String::new();
+    let mut in_description=false;
+    let mut in_hunks=false;
+    let mut empty=true;
+    for line in text.lines() {
+        if line.starts_with("#") {
+            continue
+        }
+        if line.trim().len()>0 {
+            empty=false
+        }
+        if line.starts_with("@@ ") {
+            in_hunks=true;
+            if let Some(Ok(i))=(&line[3..]).split(' ').next().map(|i| i.parse::<usize>()) {
+                if i>=1 && i<=n_changes {
+                    record.selected.insert(i-1);
+                }
+            }
+        } else if in_hunks {
+            continue
+        } else if in_description {
+            description.push_str(line);
+            description.push('\n');
+        } else if line.starts_with("name:") {
+            let name=line["name:".len()..].trim();
+            if name.len()>0 {
+                record.name=Some(name.to_string())
+            }
+        } else if line.starts_with("author:") {
+            let author=line["author:".len()..].trim();
+            if author.len()>0 {
+                let mut a=BTreeMap::new();
+                a.insert("name".to_string(),Value::String(author.to_string()));
+                record.authors.push(a)
+            }
+        } else if line.starts_with("description:") {
+            in_description=true;
+            description.push_str(line["description:".len()..].trim());
+            description.push('\n');
+        }
+    }
+    if empty {
+        return None
+    }
+    let description=description.trim();
+    if description.len()>0 {
+        record.description=Some(description.to_string())
+    }
+    Some(record)
+}
+
+/// Let the user edit the patch made of `changes`, where `files[i]` is
+/// the file of `changes[i]`: its name, authors and description, and
+/// the hunks to record. The changes a selected one depends on are
+/// selected too. The authors whose names are left unchanged keep
+/// their other fields. Returns `None` if the user cancelled.
+pub fn edit_record<'a>(editor:&str,
+                       repo_root:&Path,
+                       repo:&Repository<'a>,
+                       changes:&[Change],
+                       files:&[PathBuf],
+                       name:Option<&str>,
+                       authors:&[BTreeMap<String,Value>],
+                       description:Option<&str>)->Result<Option<EditedRecord>,Error> {
+    let text=try!(record_text(repo,changes,files,name,authors,description));
+    let edited=try!(edit_with(editor,repo_root,&String::from_utf8_lossy(&text)));
+    let mut record=match parse_record(&edited,changes.len()) {
+        Some(record)=>record,
+        None=>return Ok(None)
+    };
+    record.authors=record.authors.into_iter().map(|a| {
+        match authors.iter().find(|b| b.get("name")==a.get("name")) {
+            Some(b)=>b.clone(),
+            None=>a
+        }
+    }).collect();
+    let (deps,_)=record_dependencies(changes);
+    let mut stack:Vec<usize>=record.selected.iter().cloned().collect();
+    while let Some(i)=stack.pop() {
+        if let Some(d)=deps.get(&i) {
+            for &j in d {
+                if record.selected.insert(j) {
+                    println!("Also recording hunk {}, needed by hunk {}",j+1,i+1);
+                    stack.push(j)
+                }
+            }
+        }
+    }
+    Ok(Some(record))
+}
+
+const MESSAGE_HELP:&'static str=
+"# Write the name of the patch on the first line, and its description
+# on the following ones. Lines starting with '#' are ignored, and an
+# empty message cancels the record.
+";
+
+/// Split a patch message into the name of the patch, which is its
+/// first line, and its description, which is the rest. Lines
+/// starting with '#' are ignored. Returns `None` if the message is
+/// empty.
+pub fn parse_message(text:&str)->Option<(String,Option<String>)> {
+    let mut lines=text.lines().filter(|l| !l.starts_with("#")).skip_while(|l| l.trim().len()==0);
+    let name=match lines.next() {
+        Some(name)=>name.trim().to_string(),
+        None=>return None
+    };
+    let mut description=String::new();
+    for l in lines {
+        description.push_str(l);
+        description.push('\n')
+    }
+    let description=description.trim();
+    Some((name, if description.len()>0 { Some(description.to_string()) } else { None }))
+}
+
+/// Ask for the message of a patch in `editor`, starting from
+/// `template`. Returns `None` if the user cancelled.
+pub fn edit_message(editor:&str, repo_root:&Path, template:Option<&str>)->Result<Option<(String,Option<String>)>,Error> {
+    let mut text=template.unwrap_or("").to_string();
+    if !text.ends_with("\n") {
+        text.push('\n')
+    }
+    text.push_str(MESSAGE_HELP);
+    let edited=try!(edit_with(editor,repo_root,&text));
+    Ok(parse_message(&edited))
+}
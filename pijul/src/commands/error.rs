@@ -21,10 +21,12 @@ use std::io;
 use std::error;
 use std::fmt;
 use std::string;
+use std::path::PathBuf;
 extern crate ssh;
 extern crate rustc_serialize;
 extern crate hyper;
 extern crate toml;
+extern crate cbor;
 #[derive(Debug)]
 pub enum Error{
     NotInARepository,
@@ -44,6 +46,26 @@ pub enum Error{
     RemoteApplyFailed(String,i32,String),
     RemoteInitFailed(String,i32,String),
     InvalidPath(String),
+    RepositoryLocked(PathBuf,String),
+    HTTPStatus(String,hyper::status::StatusCode,String),
+    Cbor(cbor::CborError),
+    RemoteProtocol(String,String),
+    PatchHashMismatch(String,String),
+    UnknownRemote(String),
+    RemoteExists(String),
+    NotCurrentBranch(String,String),
+    HTTPUnauthorized(String),
+    HTTPForbidden(String),
+    InvalidCredentials(PathBuf),
+    CredentialHelper(String,String),
+    ProxyCredentials(String),
+    SSHHostKeyChanged(String,String),
+    SSHUntrustedHost(String,String),
+    HookFailed(String,Option<i32>),
+    PatchesRejected(Vec<String>),
+    BareRepository(PathBuf),
+    EditorFailed(String,Option<i32>),
+    CannotAmend(String),
 }
 
 impl fmt::Display for Error {
@@ -66,6 +88,34 @@ impl fmt::Display for Error {
             Error::RemoteApplyFailed(ref id,ref code,ref msg) => write!(f, "Remote apply to {} failed with code {} and message:\n{} ", id, code, msg),
             Error::RemoteInitFailed(ref id,ref code,ref msg) => write!(f, "Remote apply to {} failed with code {} and message:\n{} ", id, code, msg),
             Error::InvalidPath(ref p) => write!(f, "Invalid path {}", p),
+            Error::RepositoryLocked(ref path,ref owner) => write!(f, "Repository locked by {}. If this process is not running anymore, remove {}", owner, path.display()),
+            Error::HTTPStatus(ref uri,ref status,ref msg) => write!(f, "HTTP request to {} failed with status {} and message:\n{}", uri, status, msg),
+            Error::Cbor(ref err) => write!(f, "Cbor error {}", err),
+            Error::RemoteProtocol(ref id,ref msg) => write!(f, "Remote {}: {}", id, msg),
+            Error::PatchHashMismatch(ref name,ref hash) => write!(f, "Patch file {} does not match its name, its hash is {}", name, hash),
+            Error::UnknownRemote(ref name) => write!(f, "No remote named {}", name),
+            Error::RemoteExists(ref name) => write!(f, "There is already a remote named {}", name),
+            Error::NotCurrentBranch(ref branch,ref current) => write!(f, "Cannot apply patches to branch {}: only the current branch ({}) can be pulled or pushed to", branch, current),
+            Error::HTTPUnauthorized(ref uri) => write!(f, "{} requires authentication. Add credentials to ~/.pijul/credentials.toml, or set PIJUL_HTTP_TOKEN and PIJUL_HTTP_TOKEN_URL, or PIJUL_CREDENTIAL_HELPER", uri),
+            Error::HTTPForbidden(ref uri) => write!(f, "Access to {} is forbidden with these credentials", uri),
+            Error::InvalidCredentials(ref path) => write!(f, "Could not read credentials from {}", path.display()),
+            Error::CredentialHelper(ref helper,ref msg) => write!(f, "Credential helper {} failed:\n{}", helper, msg),
+            Error::ProxyCredentials(ref uri) => write!(f, "Cannot send the proxy credentials of https_proxy for {}, since the proxy would forward them to the remote", uri),
+            Error::SSHHostKeyChanged(ref host,ref fingerprint) => write!(f, "SSH: the key of {} has changed (its fingerprint is now {}), refusing to connect. If this change is expected, remove the old key from known_hosts", host, fingerprint),
+            Error::HookFailed(ref hook,Some(code)) => write!(f, "Hook {} failed with exit code {}", hook, code),
+            Error::HookFailed(ref hook,None) => write!(f, "Hook {} was killed", hook),
+            Error::SSHUntrustedHost(ref host,ref fingerprint) => write!(f, "SSH: host {} is not in known_hosts (key fingerprint {}). Run pijul interactively, without PIJUL_STRICT_HOST_KEY_CHECKING, to trust it", host, fingerprint),
+            Error::PatchesRejected(ref reasons) => {
+                try!(write!(f, "Patches rejected:"));
+                for r in reasons {
+                    try!(write!(f, "\n  {}", r))
+                }
+                Ok(())
+            },
+            Error::BareRepository(ref path) => write!(f, "{} is a bare repository, it has no working copy", path.display()),
+            Error::EditorFailed(ref editor,Some(code)) => write!(f, "Editor {} failed with exit code {}", editor, code),
+            Error::EditorFailed(ref editor,None) => write!(f, "Editor {} was killed", editor),
+            Error::CannotAmend(ref reason) => write!(f, "Cannot amend the last patch: {}", reason),
         }
     }
 }
@@ -90,6 +140,26 @@ impl error::Error for Error {
             Error::RemoteApplyFailed(_,_,_) => "Remote apply failed",
             Error::RemoteInitFailed(_,_,_) => "Remote init failed",
             Error::InvalidPath(_) => "Invalid path",
+            Error::RepositoryLocked(_,_) => "Repository locked by another process",
+            Error::HTTPStatus(_,_,_) => "HTTP request failed",
+            Error::Cbor(ref err) => err.description(),
+            Error::RemoteProtocol(_,_) => "Remote protocol error",
+            Error::PatchHashMismatch(_,_) => "Patch does not match its hash",
+            Error::UnknownRemote(_) => "Unknown remote",
+            Error::RemoteExists(_) => "Remote already exists",
+            Error::NotCurrentBranch(_,_) => "Patches can only be applied to the current branch",
+            Error::HTTPUnauthorized(_) => "HTTP authentication required",
+            Error::HTTPForbidden(_) => "HTTP access forbidden",
+            Error::InvalidCredentials(_) => "Invalid credentials file",
+            Error::CredentialHelper(_,_) => "Credential helper failed",
+            Error::ProxyCredentials(_) => "Proxy credentials for an https remote",
+            Error::SSHHostKeyChanged(_,_) => "SSH: host key changed",
+            Error::SSHUntrustedHost(_,_) => "SSH: host not trusted",
+            Error::HookFailed(_,_) => "Hook failed",
+            Error::PatchesRejected(_) => "Patches rejected",
+            Error::BareRepository(_) => "Bare repository",
+            Error::EditorFailed(_,_) => "Editor failed",
+            Error::CannotAmend(_) => "Cannot amend the last patch",
         }
     }
 
@@ -112,6 +182,26 @@ impl error::Error for Error {
             Error::RemoteApplyFailed(_,_,_) => None,
             Error::RemoteInitFailed(_,_,_) => None,
             Error::InvalidPath(_) => None,
+            Error::RepositoryLocked(_,_) => None,
+            Error::HTTPStatus(_,_,_) => None,
+            Error::Cbor(ref err) => Some(err),
+            Error::RemoteProtocol(_,_) => None,
+            Error::PatchHashMismatch(_,_) => None,
+            Error::UnknownRemote(_) => None,
+            Error::RemoteExists(_) => None,
+            Error::NotCurrentBranch(_,_) => None,
+            Error::HTTPUnauthorized(_) => None,
+            Error::HTTPForbidden(_) => None,
+            Error::InvalidCredentials(_) => None,
+            Error::CredentialHelper(_,_) => None,
+            Error::ProxyCredentials(_) => None,
+            Error::SSHHostKeyChanged(_,_) => None,
+            Error::SSHUntrustedHost(_,_) => None,
+            Error::HookFailed(_,_) => None,
+            Error::PatchesRejected(_) => None,
+            Error::BareRepository(_) => None,
+            Error::EditorFailed(_,_) => None,
+            Error::CannotAmend(_) => None,
         }
     }
 }
@@ -146,3 +236,8 @@ impl From<hyper::error::Error> for Error {
         Error::Hyper(err)
     }
 }
+impl From<cbor::CborError> for Error {
+    fn from(err:cbor::CborError) -> Error {
+        Error::Cbor(err)
+    }
+}
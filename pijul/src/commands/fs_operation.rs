@@ -7,7 +7,8 @@ use self::libpijul::fs_representation::{repo_dir, pristine_dir, find_repo_root};
 use std::path::{Path};
 use std::fs::{metadata,rename,canonicalize};
 use commands::error;
-use super::get_wd;
+use super::{get_wd, check_not_bare};
+use super::lock::RepositoryLock;
 #[derive(Debug)]
 pub struct Params<'a> {
     pub touched_files : Vec<&'a Path>,
@@ -40,6 +41,8 @@ pub fn run<'a>(args : &Params<'a>, op : Operation)
         Some(ref r) =>
         {
             debug!(target:"mv","repo {:?}",r);
+            try!(check_not_bare(r));
+            let _lock=try!(RepositoryLock::acquire(r));
             let repo_dir=pristine_dir(r);
             let mut repo = try!(Repository::new(&repo_dir).map_err(error::Error::Repository));
             match op {
@@ -0,0 +1,140 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+extern crate clap;
+use clap::{SubCommand, ArgMatches, Arg};
+
+extern crate libpijul;
+use commands::StaticSubcommand;
+use self::libpijul::{Repository};
+use self::libpijul::patch::{read_changes_from_file,is_tmp_file_name};
+use self::libpijul::fs_representation::{pristine_dir, patches_dir, find_repo_root,
+                                        branch_changes_file, list_branches, patch_hash_of_file_name};
+
+use commands::error::Error;
+use std::path::{Path};
+use std::fs::{read_dir,remove_file};
+use std::time::{SystemTime,Duration};
+use super::get_wd;
+use super::lock::RepositoryLock;
+
+/// Files modified less than this many seconds ago are kept by default,
+/// since another process might still be writing or applying them.
+pub const DEFAULT_GRACE:u64=3600;
+
+pub fn invocation() -> StaticSubcommand {
+    return
+        SubCommand::with_name("gc")
+        .about("Delete patch files that no branch references")
+        .arg(Arg::with_name("repository")
+             .long("repository")
+             .help("The repository to clean, defaults to the current directory.")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("dry-run")
+             .short("n")
+             .long("dry-run")
+             .help("Only print the files that would be deleted.")
+             .takes_value(false))
+        .arg(Arg::with_name("grace")
+             .long("grace")
+             .help("Keep files modified less than this many seconds ago (one hour by default).")
+             .takes_value(true)
+             .validator(|val| { let x:Result<u64,_>=val.parse();
+                                match x { Ok(_)=>Ok(()),
+                                          Err(_)=>Err(val) }
+             }))
+}
+
+pub struct Params<'a> {
+    pub repository : Option<&'a Path>,
+    pub dry_run : bool,
+    pub grace : Option<u64>
+}
+
+pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
+    Params { repository : args.value_of("repository").and_then(|x| { Some(Path::new(x)) }),
+             dry_run : args.is_present("dry-run"),
+             grace : args.value_of("grace").and_then(|x| { Some(x.parse().unwrap()) }) }
+}
+
+pub fn run(args : &Params) -> Result<(), Error> {
+    let wd=try!(get_wd(args.repository));
+    match find_repo_root(&wd){
+        None => return Err(Error::NotInARepository),
+        Some(ref r) => {
+            let _lock=try!(RepositoryLock::acquire(r));
+            // A patch is referenced if it is applied on a branch of
+            // the pristine, or listed in one of the changes files
+            // (which should agree with the pristine, unless a
+            // previous command was interrupted).
+            let mut referenced={
+                let repo = try!(Repository::new_readonly(&pristine_dir(r)));
+                repo.applied_patches()
+            };
+            for branch in try!(list_branches(r)) {
+                if let Ok(changes)=read_changes_from_file(&branch_changes_file(r,&branch)) {
+                    referenced.extend(changes.into_iter())
+                }
+            }
+            let now=SystemTime::now();
+            let grace=Duration::from_secs(args.grace.unwrap_or(DEFAULT_GRACE));
+            let mut removed=0;
+            let mut freed=0;
+            for entry in try!(read_dir(patches_dir(r))) {
+                let entry=try!(entry);
+                let path=entry.path();
+                let collectable= match entry.file_name().to_str() {
+                    Some(name)=>{
+                        match patch_hash_of_file_name(name) {
+                            Some(hash)=> !referenced.contains(&hash),
+                            None=>is_tmp_file_name(name)
+                        }
+                    },
+                    None=>false
+                };
+                if !collectable {
+                    continue
+                }
+                let meta=try!(entry.metadata());
+                let young=meta.modified().ok()
+                    .and_then(|m| now.duration_since(m).ok())
+                    .map(|age| age < grace)
+                    .unwrap_or(true);
+                if young {
+                    debug!("gc: keeping {:?}, too recent",path);
+                    continue
+                }
+                if args.dry_run {
+                    println!("Would remove {}",path.display());
+                } else {
+                    debug!("gc: removing {:?}",path);
+                    try!(remove_file(&path));
+                }
+                removed+=1;
+                freed+=meta.len();
+            }
+            if args.dry_run {
+                println!("{} files would be removed ({} bytes)",removed,freed);
+            } else {
+                println!("Removed {} files ({} bytes)",removed,freed);
+            }
+            Ok(())
+        }
+    }
+}
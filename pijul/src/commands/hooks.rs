@@ -0,0 +1,165 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Repository hooks: executables in `.pijul/hooks`, run in the root
+//! of the repository, with `PIJUL_REPOSITORY` set to its path.
+//!
+//! - `pre-record` runs before a patch is recorded, with a summary of
+//!   the patch on its standard input.
+//! - `post-record` runs after a patch has been recorded, with its hash
+//!   as argument.
+//! - `pre-apply` runs before patches are applied (by `pull`, `apply`,
+//!   or when a remote pushes to this repository), with their hashes
+//!   as arguments.
+//! - `post-apply` runs after patches have been applied, with their
+//!   hashes as arguments.
+//!
+//! If a `pre-` hook fails, the operation is cancelled before anything
+//! is written to the repository. Failures of `post-` hooks are only
+//! reported, since the operation is finished when they run.
+//!
+//! The standard output of hooks is sent to the standard error of
+//! pijul, whose standard output carries the protocol when a remote
+//! pushes over SSH (see `pijul protocol`).
+
+extern crate libpijul;
+use self::libpijul::fs_representation::hooks_dir;
+use self::libpijul::patch::{Patch,Change};
+use self::libpijul::contents::FOLDER_EDGE;
+
+use std::path::Path;
+use std::fs::metadata;
+use std::io::{Write,stderr,copy,ErrorKind};
+use std::process::{Command,Stdio};
+use std::str;
+use std::thread;
+#[cfg(not(windows))]
+use std::os::unix::fs::PermissionsExt;
+
+use super::error::Error;
+
+extern crate rustc_serialize;
+use self::rustc_serialize::hex::ToHex;
+
+#[derive(Debug,Clone,Copy)]
+pub enum Hook {
+    PreRecord,
+    PostRecord,
+    PreApply,
+    PostApply
+}
+
+impl Hook {
+    pub fn name(&self)->&'static str {
+        match *self {
+            Hook::PreRecord=>"pre-record",
+            Hook::PostRecord=>"post-record",
+            Hook::PreApply=>"pre-apply",
+            Hook::PostApply=>"post-apply"
+        }
+    }
+    fn is_pre(&self)->bool {
+        match *self {
+            Hook::PreRecord | Hook::PreApply=>true,
+            Hook::PostRecord | Hook::PostApply=>false
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn is_executable(path:&Path)->bool {
+    metadata(path).map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+#[cfg(windows)]
+fn is_executable(path:&Path)->bool {
+    metadata(path).map(|m| m.is_file()).unwrap_or(false)
+}
+
+/// Run `hook` in the repository rooted at `repo_root`, if it exists,
+/// with arguments `args` and standard input `input`.
+pub fn run(repo_root:&Path, hook:Hook, args:&[String], input:&[u8])->Result<(),Error> {
+    let path=hooks_dir(repo_root).join(hook.name());
+    if !is_executable(&path) {
+        return Ok(())
+    }
+    debug!("hooks: running {:?} {:?}",path,args);
+    let mut child=try!(Command::new(&path)
+                       .args(args)
+                       .current_dir(repo_root)
+                       .env("PIJUL_REPOSITORY",repo_root)
+                       .stdin(Stdio::piped())
+                       .stdout(Stdio::piped())
+                       .spawn());
+    let output=child.stdout.take().map(|mut out| thread::spawn(move || {
+        let _=copy(&mut out,&mut stderr());
+    }));
+    if let Some(mut stdin)=child.stdin.take() {
+        // The hook might not read its input.
+        match stdin.write_all(input) {
+            Err(ref e) if e.kind()==ErrorKind::BrokenPipe => {},
+            r=>try!(r)
+        }
+    }
+    let status=try!(child.wait());
+    if let Some(output)=output {
+        let _=output.join();
+    }
+    if status.success() {
+        Ok(())
+    } else if hook.is_pre() {
+        Err(Error::HookFailed(hook.name().to_string(),status.code()))
+    } else {
+        try!(writeln!(stderr(),"Warning: hook {} failed ({})",hook.name(),status));
+        Ok(())
+    }
+}
+
+/// Run `hook` with the hexadecimal hashes of `patches` as arguments.
+pub fn run_with_hashes<'a,I:Iterator<Item=&'a Vec<u8>>>(repo_root:&Path, hook:Hook, patches:I)->Result<(),Error> {
+    let args:Vec<String>=patches.map(|h| h.to_hex()).collect();
+    if args.len()>0 {
+        run(repo_root,hook,&args,&[])
+    } else {
+        Ok(())
+    }
+}
+
+/// The summary of a patch given to the `pre-record` hook.
+pub fn summary(patch:&Patch)->String {
+    let mut s=format!("name: {}\n",patch.name);
+    let mut added=0;
+    let mut edges=0;
+    for c in patch.changes.iter() {
+        match *c {
+            Change::NewNodes { ref flag, ref nodes, .. }=>{
+                if *flag & FOLDER_EDGE != 0 {
+                    for n in nodes {
+                        if n.len()>=2 {
+                            s.push_str(&format!("new file {}\n",str::from_utf8(&n[2..]).unwrap_or("")))
+                        }
+                    }
+                } else {
+                    added+=nodes.len()
+                }
+            },
+            Change::Edges { edges:ref e, .. }=>edges+=e.len()
+        }
+    }
+    s.push_str(&format!("added lines: {}\nchanged edges: {}\n",added,edges));
+    s
+}
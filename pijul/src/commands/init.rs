@@ -22,7 +22,7 @@ use std::path::Path;
 use commands::error::Error;
 use commands::StaticSubcommand;
 extern crate libpijul;
-use self::libpijul::fs_representation;
+use self::libpijul::{fs_representation, Repository};
 
 pub fn invocation() -> StaticSubcommand {
     return
@@ -32,21 +32,39 @@ pub fn invocation() -> StaticSubcommand {
              .index(1)
              .help("Where to create the repository, defaults to the current repository.")
              .required(false)
+             )
+        .arg(Arg::with_name("bare")
+             .long("bare")
+             .help("Create a bare repository, without a working copy, for instance to push to.")
              );
 }
 
 pub struct Params<'a> {
     pub location : &'a Path,
-    pub allow_nested : bool
+    pub allow_nested : bool,
+    pub bare : bool
 }
 
 pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a>
 {
     Params {location : Path::new(args.value_of("directory").unwrap_or(".")),
-            allow_nested : false
+            allow_nested : false,
+            bare : args.is_present("bare")
     }
 }
 
+fn create(dir : &Path, bare : bool) -> Result<(), Error> {
+    if bare {
+        try!(fs_representation::create_bare(dir))
+    } else {
+        try!(fs_representation::create(dir))
+    }
+    // Creates the databases of the pristine, which read-only
+    // transactions cannot do.
+    try!(try!(Repository::new(&fs_representation::pristine_dir(dir))).commit());
+    Ok(())
+}
+
 pub fn run (p : &Params) -> Result<(), Error> {
     let dir = p.location;
     match fs_representation::find_repo_root(&dir) {
@@ -54,7 +72,7 @@ pub fn run (p : &Params) -> Result<(), Error> {
             {
                 if p.allow_nested
                 {
-                    try!(fs_representation::create(&dir));
+                    try!(create(&dir,p.bare));
                     Ok(())
                 }
                 else
@@ -64,7 +82,7 @@ pub fn run (p : &Params) -> Result<(), Error> {
             }
         None =>
         {
-            try!(fs_representation::create(&dir));
+            try!(create(&dir,p.bare));
             Ok(())
         }
     }
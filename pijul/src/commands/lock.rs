@@ -0,0 +1,185 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Advisory lock on a repository. Commands that modify the working
+//! copy, the changes files or the pristine hold it for their whole
+//! duration, so that two pijul processes never interleave their
+//! writes. Read-only commands don't need it.
+//!
+//! Since the lock holder is the only process writing to the
+//! repository, this is also where operations interrupted by a crash
+//! are recovered from their journal. A lock whose holder is no longer
+//! running (according to the pid on the first line of the lock file)
+//! is broken. The pid is only meaningful on the machine that took the
+//! lock, so repositories shared between machines over a network file
+//! system should not rely on this.
+
+extern crate libpijul;
+extern crate time;
+use self::libpijul::Repository;
+use self::libpijul::journal;
+use self::libpijul::fs_representation::{lock_file,pristine_dir};
+use super::error::Error;
+
+use std::fs::{OpenOptions,File,remove_file};
+use std::io::{Read,Write,ErrorKind,stderr};
+use std::path::{Path,PathBuf};
+use std::time::Duration;
+use std::{thread,env,process};
+#[cfg(all(unix,not(target_os="linux")))]
+use std::process::{Command,Stdio};
+
+/// Seconds to wait for another process to release the lock, unless
+/// overridden by the `PIJUL_LOCK_TIMEOUT` environment variable.
+pub const DEFAULT_LOCK_TIMEOUT:u64=30;
+
+pub struct RepositoryLock {
+    path:PathBuf
+}
+
+impl RepositoryLock {
+    pub fn acquire(repo_root:&Path)->Result<RepositoryLock,Error> {
+        let timeout=
+            env::var("PIJUL_LOCK_TIMEOUT").ok()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(DEFAULT_LOCK_TIMEOUT);
+        RepositoryLock::acquire_with_timeout(repo_root,timeout)
+    }
+
+    /// Take the lock of the repository at `repo_root`, waiting at most
+    /// `timeout` seconds for its current holder to release it.
+    pub fn acquire_with_timeout(repo_root:&Path,timeout:u64)->Result<RepositoryLock,Error> {
+        let path=lock_file(repo_root);
+        let t0=time::precise_time_s();
+        let mut waiting=false;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut f)=>{
+                    let command:Vec<String>=env::args().collect();
+                    try!(write!(f,"{}\n{}\n",process::id(),command.join(" ")));
+                    debug!("lock: acquired {:?}",path);
+                    let lock=RepositoryLock { path:path };
+                    try!(recover(repo_root));
+                    return Ok(lock)
+                },
+                Err(ref e) if e.kind()==ErrorKind::AlreadyExists => {
+                    if try!(break_stale_lock(&path)) {
+                        continue
+                    }
+                    if time::precise_time_s()-t0 >= timeout as f64 {
+                        return Err(Error::RepositoryLocked(path.clone(),lock_owner(&path)))
+                    }
+                    if !waiting {
+                        // Not on stdout, which `pijul protocol` uses.
+                        let _=writeln!(stderr(),"Waiting for {} to release the repository lock", lock_owner(&path));
+                        waiting=true
+                    }
+                    thread::sleep(Duration::from_millis(100))
+                },
+                Err(e)=>return Err(Error::IO(e))
+            }
+        }
+    }
+}
+
+/// Finish or cancel the operation left unfinished in the repository
+/// at `repo_root`, if any.
+fn recover(repo_root:&Path)->Result<(),Error> {
+    if let Some(j)=try!(journal::read(repo_root)) {
+        let _=writeln!(stderr(),"Recovering from an interrupted {}",
+                 match j.operation { journal::Operation::Record=>"record",
+                                     journal::Operation::Apply=>"apply" });
+        let mut repo = try!(Repository::new(&pristine_dir(repo_root)));
+        try!(repo.recover(repo_root,&j));
+        try!(repo.commit());
+    }
+    Ok(())
+}
+
+/// Remove the lock file at `path` if the process that created it is
+/// not running anymore. Returns whether the lock was removed.
+fn break_stale_lock(path:&Path)->Result<bool,Error> {
+    let contents=match read_lock(path) { Some(c)=>c, None=>return Ok(false) };
+    let pid:u32=match contents.lines().next().and_then(|pid| pid.parse().ok()) {
+        Some(pid)=>pid,
+        // Being written, or not ours.
+        None=>return Ok(false)
+    };
+    if process_is_running(pid) {
+        return Ok(false)
+    }
+    // Another process might have broken this lock and taken a new one
+    // since we read it.
+    if read_lock(path).as_ref()!=Some(&contents) {
+        return Ok(false)
+    }
+    let _=writeln!(stderr(),"Breaking the repository lock of process {}, which is not running anymore",pid);
+    match remove_file(path) {
+        Ok(())=>Ok(true),
+        Err(ref e) if e.kind()==ErrorKind::NotFound=>Ok(true),
+        Err(e)=>Err(Error::IO(e))
+    }
+}
+
+fn read_lock(path:&Path)->Option<String> {
+    let mut s=String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_)=>Some(s),
+        Err(_)=>None
+    }
+}
+
+#[cfg(target_os="linux")]
+fn process_is_running(pid:u32)->bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(all(unix,not(target_os="linux")))]
+fn process_is_running(pid:u32)->bool {
+    // Unlike `kill -0`, `ps` also sees the processes of other users.
+    match Command::new("ps").arg("-p").arg(pid.to_string())
+        .stdout(Stdio::null()).stderr(Stdio::null()).status() {
+        Ok(stat)=>stat.success(),
+        Err(_)=>true
+    }
+}
+
+/// Without a way to tell, locks are never considered stale.
+#[cfg(not(unix))]
+fn process_is_running(_:u32)->bool {
+    true
+}
+
+/// Describe the process holding the lock, from the contents of the
+/// lock file.
+fn lock_owner(path:&Path)->String {
+    let s=read_lock(path).unwrap_or(String::new());
+    let mut lines=s.lines();
+    match (lines.next(),lines.next()) {
+        (Some(pid),Some(command))=>format!("process {} ({})",pid,command),
+        (Some(pid),None)=>format!("process {}",pid),
+        _=>"another process".to_string()
+    }
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        debug!("lock: releasing {:?}",self.path);
+        let _=remove_file(&self.path);
+    }
+}
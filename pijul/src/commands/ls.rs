@@ -54,7 +54,7 @@ pub fn run<'a>(args : &Params<'a>) -> Result<(), error::Error> {
         Some(ref r) =>
         {
             let repo_dir=pristine_dir(r);
-            let repo = try!(Repository::new(&repo_dir).map_err(error::Error::Repository));
+            let repo = try!(Repository::new_readonly(&repo_dir).map_err(error::Error::Repository));
             let files=repo.list_files();
             for f in files {
                 println!("{:?}",f)
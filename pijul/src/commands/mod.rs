@@ -22,13 +22,19 @@ pub type StaticSubcommand = clap::App<'static, 'static, 'static, 'static, 'stati
 
 mod fs_operation;
 mod remote;
+mod http;
+mod hooks;
+mod policy;
 mod escape;
 mod ask;
+mod editor;
+mod lock;
 
 pub mod info;
 pub mod check;
 pub mod init;
 pub mod record;
+pub mod amend;
 pub mod add;
 pub mod pull;
 pub mod push;
@@ -38,6 +44,10 @@ pub mod remove;
 pub mod mv;
 pub mod ls;
 pub mod revert;
+pub mod gc;
+pub mod serve;
+pub mod protocol;
+pub mod remotes;
 #[cfg(test)]
 mod test;
 pub mod error;
@@ -46,6 +56,7 @@ use std::path::{Path,PathBuf};
 use std::env::{current_dir};
 
 extern crate libpijul;
+use self::libpijul::fs_representation::is_bare;
 use self::error::Error;
 
 pub fn all_command_invocations() -> Vec<StaticSubcommand> {
@@ -54,6 +65,7 @@ pub fn all_command_invocations() -> Vec<StaticSubcommand> {
         info::invocation(),
         init::invocation(),
         record::invocation(),
+        amend::invocation(),
         add::invocation(),
         pull::invocation(),
         push::invocation(),
@@ -62,10 +74,24 @@ pub fn all_command_invocations() -> Vec<StaticSubcommand> {
         remove::invocation(),
         mv::invocation(),
         ls::invocation(),
-        revert::invocation()
+        revert::invocation(),
+        gc::invocation(),
+        serve::invocation(),
+        protocol::invocation(),
+        remotes::invocation()
         ];
 }
 
+/// Fail if the repository rooted at `r` is bare, for commands that
+/// need a working copy.
+pub fn check_not_bare(r:&Path)->Result<(),Error> {
+    if is_bare(r) {
+        Err(Error::BareRepository(r.to_path_buf()))
+    } else {
+        Ok(())
+    }
+}
+
 pub fn get_wd(repository_path:Option<&Path>)->Result<PathBuf,Error> {
     match repository_path {
         None =>{
@@ -0,0 +1,151 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Validation of patches before they are applied, in particular of
+//! those pushed by remotes. Each new patch is decoded and its hash is
+//! checked against the name of its file, its dependencies must be
+//! either applied already or applied along with it, and the checks
+//! of the `[policy]` section of `meta.toml` are run (see
+//! `meta::Policy`).
+
+extern crate libpijul;
+use self::libpijul::Repository;
+use self::libpijul::patch::{Patch,hash_patch_file,verify_signature};
+use self::libpijul::fs_representation::{patch_path, parse_patch_file_name};
+
+use std::path::Path;
+use std::fs::metadata;
+use std::collections::HashSet;
+
+use super::error::Error;
+use super::super::meta::{Meta,Policy};
+
+extern crate rustc_serialize;
+use self::rustc_serialize::hex::ToHex;
+
+/// Check the patches of `remote` that are not in `local`, before
+/// they are applied to the repository rooted at `target`, whose
+/// pristine `repo` is open for writing. All the reasons to reject
+/// them are returned in `Error::PatchesRejected`. If meta.toml exists
+/// but cannot be read, nothing is accepted.
+pub fn check_patches(target:&Path, repo:&mut Repository, remote:&HashSet<Vec<u8>>, local:&HashSet<Vec<u8>>)->Result<(),Error> {
+    let policy=try!(Meta::load_if_exists(target)).and_then(|m| m.policy);
+    let mut reasons=Vec::new();
+    for h in remote.difference(local) {
+        check_patch(target,policy.as_ref(),h,remote,local,&mut reasons)
+    }
+    if reasons.len()==0 {
+        if let Some(ref protected)=policy.as_ref().and_then(|p| p.protected_paths.as_ref()) {
+            try!(check_protected_paths(target,repo,protected,remote,local,&mut reasons))
+        }
+    }
+    if reasons.len()>0 {
+        Err(Error::PatchesRejected(reasons))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_patch(target:&Path, policy:Option<&Policy>, h:&[u8],
+               remote:&HashSet<Vec<u8>>, local:&HashSet<Vec<u8>>, reasons:&mut Vec<String>) {
+    let hex=h.to_hex();
+    let file=match patch_path(target,h) {
+        Some(file)=>file,
+        None=>{
+            reasons.push(format!("{}: patch not found",hex));
+            return
+        }
+    };
+    let extension=match file.file_name().and_then(|x| x.to_str()).and_then(parse_patch_file_name) {
+        Some((_,extension))=>extension,
+        None=>{
+            reasons.push(format!("{}: invalid patch file name",hex));
+            return
+        }
+    };
+    if let Some(max)=policy.and_then(|p| p.max_patch_size) {
+        match metadata(&file) {
+            Ok(m)=>if m.len()>max {
+                reasons.push(format!("{}: patch is {} bytes, larger than the maximum of {}",hex,m.len(),max))
+            },
+            Err(e)=>reasons.push(format!("{}: {}",hex,e))
+        }
+    }
+    let allowed_signers=policy.and_then(|p| p.allowed_signers.as_ref());
+    if policy.and_then(|p| p.require_signature).unwrap_or(false) || allowed_signers.is_some() {
+        if extension!="cbor.gpg" {
+            reasons.push(format!("{}: patch is not signed",hex))
+        } else {
+            match verify_signature(&file) {
+                Ok(fingerprint)=>if let Some(allowed)=allowed_signers {
+                    if !allowed.iter().any(|k| same_fingerprint(k,&fingerprint)) {
+                        reasons.push(format!("{}: signed by {}, which is not an allowed signer",hex,fingerprint))
+                    }
+                },
+                Err(e)=>reasons.push(format!("{}: invalid signature: {}",hex,e))
+            }
+        }
+    }
+    match hash_patch_file(&file,extension) {
+        Ok(ref hash) if &hash[..]==h => {},
+        Ok(hash)=>{
+            reasons.push(format!("{}: patch does not match its hash, its hash is {}",hex,hash.to_hex()));
+            return
+        },
+        Err(e)=>{
+            reasons.push(format!("{}: {}",hex,e));
+            return
+        }
+    }
+    let patch=match Patch::from_repository(target,h) {
+        Ok(patch)=>patch,
+        Err(e)=>{
+            reasons.push(format!("{}: could not decode patch: {}",hex,e));
+            return
+        }
+    };
+    for d in patch.dependencies.iter() {
+        if !local.contains(d) && !remote.contains(d) {
+            reasons.push(format!("{}: depends on {}, which is neither applied nor pushed",hex,d.to_hex()))
+        }
+    }
+}
+
+/// Compares fingerprints, ignoring case and spaces.
+fn same_fingerprint(a:&str,b:&str)->bool {
+    let normalize=|x:&str| x.chars().filter(|c| !c.is_whitespace()).flat_map(|c| c.to_uppercase()).collect::<String>();
+    normalize(a)==normalize(b)
+}
+
+/// Reject the patches if applying them would change a file under one
+/// of the `protected` paths.
+fn check_protected_paths(target:&Path, repo:&mut Repository, protected:&[String],
+                         remote:&HashSet<Vec<u8>>, local:&HashSet<Vec<u8>>,
+                         reasons:&mut Vec<String>)->Result<(),Error> {
+    // Simulated in a nested transaction of `repo`, which is aborted.
+    let simulation=try!(repo.simulate_apply(target,remote,local));
+    for path in simulation.changed.iter() {
+        for p in protected {
+            if path.starts_with(p) {
+                reasons.push(format!("{}: protected path",path.display()));
+                break
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,310 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! The protocol spoken by `pijul protocol` on its standard input and
+//! output, used by SSH remotes. The client sends a sequence of
+//! `Request`s, encoded in CBOR, and the server answers each of them
+//! with exactly one `Response`, until `Request::Quit` or the end of
+//! its input. Each response is preceded by its length (see
+//! `write_response`), so that clients can handle responses one at a
+//! time, as they arrive. A request that fails gets a
+//! `Response::Error`, and does not stop the requests that follow it.
+//!
+//! To find which patches two repositories do not have in common
+//! without transferring their whole histories, each side splits its
+//! patches in `SUMMARY_BUCKETS` buckets according to the first byte
+//! of their hashes, and summarizes each bucket by its size and a
+//! digest of its hashes (see `summarize`). Only the hashes of the
+//! buckets whose summaries differ are then exchanged.
+
+extern crate clap;
+use clap::{SubCommand, ArgMatches, Arg};
+
+extern crate libpijul;
+use commands::StaticSubcommand;
+use self::libpijul::patch::{read_changes_from_file};
+use self::libpijul::fs_representation::{find_repo_root, patch_path, branch_changes_file,
+                                        list_branches, patch_hash_of_file_name};
+
+use commands::error::Error;
+use std::path::{Path};
+use std::fs::{File};
+use std::io::{self,Read,Write,stdin,stdout};
+use std::collections::HashSet;
+use super::get_wd;
+use super::apply;
+use super::remote::store_patch;
+
+extern crate cbor;
+extern crate crypto;
+use self::crypto::digest::Digest;
+use self::crypto::sha2::Sha512;
+extern crate rustc_serialize;
+use self::rustc_serialize::Encodable;
+use self::rustc_serialize::hex::{ToHex};
+
+pub fn invocation() -> StaticSubcommand {
+    return
+        SubCommand::with_name("protocol")
+        .about("Answer requests of a remote pijul on stdin and stdout (used over SSH)")
+        .arg(Arg::with_name("repository")
+             .long("repository")
+             .help("The repository to serve, defaults to the current directory.")
+             .takes_value(true)
+             .required(false))
+}
+
+pub struct Params<'a> {
+    pub repository : Option<&'a Path>
+}
+
+pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
+    Params { repository : args.value_of("repository").and_then(|x| { Some(Path::new(x)) }) }
+}
+
+#[derive(Debug,RustcEncodable,RustcDecodable)]
+pub enum Request {
+    /// List the branches of the repository.
+    ListBranches,
+    /// Get the hashes of the patches applied on a branch.
+    Changes(Vec<u8>),
+    /// Get the `Summary` of a branch.
+    Summary(Vec<u8>),
+    /// Get the hashes of the patches applied on a branch, in the
+    /// given buckets only.
+    Hashes(Vec<u8>,Vec<u8>),
+    /// Get the file of a patch, given its hash.
+    GetPatch(Vec<u8>),
+    /// Upload a patch file, given its file name and contents.
+    PutPatch(String,Vec<u8>),
+    /// Apply uploaded patches to a branch, given their hashes. The
+    /// branch must be the current one.
+    Apply(Vec<u8>,Vec<Vec<u8>>),
+    Quit
+}
+
+#[derive(Debug,RustcEncodable,RustcDecodable)]
+pub enum Response {
+    Branches(Vec<Vec<u8>>),
+    Changes(Vec<Vec<u8>>),
+    Summary(Summary),
+    /// File name and contents of a patch, if the server has it.
+    Patch(Option<(String,Vec<u8>)>),
+    Ok,
+    Error(String)
+}
+
+/// Number of buckets in a `Summary`.
+pub const SUMMARY_BUCKETS:usize=256;
+
+/// Length of the digests of buckets, in bytes.
+const BUCKET_DIGEST_LEN:usize=16;
+
+/// For each bucket, the number of patches it contains, and a
+/// (truncated) SHA-512 of their sorted hashes.
+pub type Summary=Vec<(u64,Vec<u8>)>;
+
+pub fn summarize(hashes:&HashSet<Vec<u8>>)->Summary {
+    let mut buckets:Vec<Vec<&[u8]>>=(0..SUMMARY_BUCKETS).map(|_| Vec::new()).collect();
+    for h in hashes.iter() {
+        if h.len()>0 {
+            buckets[h[0] as usize].push(&h[..])
+        }
+    }
+    buckets.into_iter().map(|mut bucket| {
+        bucket.sort();
+        let mut hasher = Sha512::new();
+        for h in bucket.iter() {
+            hasher.input(h)
+        }
+        let mut digest=vec![0;hasher.output_bytes()];
+        hasher.result(&mut digest);
+        digest.truncate(BUCKET_DIGEST_LEN);
+        (bucket.len() as u64,digest)
+    }).collect()
+}
+
+/// The buckets whose summaries differ.
+pub fn differing_buckets(a:&Summary,b:&Summary)->Vec<u8> {
+    (0..SUMMARY_BUCKETS).filter(|&i| a.get(i)!=b.get(i)).map(|i| i as u8).collect()
+}
+
+/// The hashes of `hashes` that are in one of `buckets`.
+pub fn in_buckets(hashes:&HashSet<Vec<u8>>,buckets:&[u8])->HashSet<Vec<u8>> {
+    let mut selected=[false;SUMMARY_BUCKETS];
+    for &b in buckets {
+        selected[b as usize]=true
+    }
+    hashes.iter().filter(|h| h.len()>0 && selected[h[0] as usize]).cloned().collect()
+}
+
+pub fn run(args : &Params) -> Result<(), Error> {
+    let wd=try!(get_wd(args.repository));
+    match find_repo_root(&wd){
+        None => return Err(Error::NotInARepository),
+        Some(ref r) => {
+            let stdin=stdin();
+            let stdout=stdout();
+            serve(r,stdin.lock(),stdout.lock())
+        }
+    }
+}
+
+/// Answer the requests read from `input` about the repository rooted
+/// at `root`, writing the responses to `output`.
+pub fn serve<R:Read,W:Write>(root:&Path, input:R, output:W)->Result<(),Error> {
+    let mut decoder=cbor::Decoder::from_reader(input);
+    let mut output=output;
+    loop {
+        let request:Request=match decoder.decode().next() {
+            Some(request)=>try!(request),
+            None=>return Ok(())
+        };
+        debug!("protocol: {:?}",request);
+        let response=match request {
+            Request::Quit=>return Ok(()),
+            request=>match answer(root,request) {
+                Ok(response)=>response,
+                Err(e)=>Response::Error(e.to_string())
+            }
+        };
+        try!(write_response(&mut output,&response));
+        try!(output.flush());
+    }
+}
+
+/// Write `response` to `w`, preceded by the length of its encoding,
+/// in bytes, as a big-endian number on 8 bytes.
+pub fn write_response<W:Write>(w:&mut W, response:&Response)->Result<(),Error> {
+    let buf=try!(to_cbor(response));
+    let len=buf.len() as u64;
+    let mut header=[0;8];
+    for i in 0..8 {
+        header[i]=(len>>(8*(7-i))) as u8
+    }
+    try!(w.write_all(&header));
+    try!(w.write_all(&buf));
+    Ok(())
+}
+
+/// Read the next response written by `write_response` from `r`, or
+/// `None` if `r` ends before it.
+pub fn read_response<R:Read>(r:&mut R)->Result<Option<Response>,Error> {
+    let mut header=[0;8];
+    let mut n=0;
+    while n<header.len() {
+        match try!(r.read(&mut header[n..])) {
+            0 if n==0=>return Ok(None),
+            0=>return Err(Error::IO(io::Error::new(io::ErrorKind::UnexpectedEof,"truncated response"))),
+            k=>n+=k
+        }
+    }
+    let len=header.iter().fold(0_u64,|len,&b| (len<<8)|(b as u64));
+    let mut buf=Vec::new();
+    try!(r.take(len).read_to_end(&mut buf));
+    if (buf.len() as u64)<len {
+        return Err(Error::IO(io::Error::new(io::ErrorKind::UnexpectedEof,"truncated response")))
+    }
+    let mut decoder=cbor::Decoder::from_reader(&buf[..]);
+    match decoder.decode().next() {
+        Some(response)=>Ok(Some(try!(response))),
+        None=>Err(Error::IO(io::Error::new(io::ErrorKind::InvalidData,"empty response")))
+    }
+}
+
+fn answer(root:&Path, request:Request)->Result<Response,Error> {
+    match request {
+        Request::ListBranches=>Ok(Response::Branches(try!(list_branches(root)))),
+        Request::Changes(branch)=>{
+            let changes=read_changes_from_file(&branch_changes_file(root,&branch)).unwrap_or(HashSet::new());
+            Ok(Response::Changes(changes.into_iter().collect()))
+        },
+        Request::Summary(branch)=>{
+            let changes=read_changes_from_file(&branch_changes_file(root,&branch)).unwrap_or(HashSet::new());
+            Ok(Response::Summary(summarize(&changes)))
+        },
+        Request::Hashes(branch,buckets)=>{
+            let changes=read_changes_from_file(&branch_changes_file(root,&branch)).unwrap_or(HashSet::new());
+            Ok(Response::Changes(in_buckets(&changes,&buckets).into_iter().collect()))
+        },
+        Request::GetPatch(hash)=>{
+            if let Some(path)=patch_path(root,&hash) {
+                let mut contents=Vec::new();
+                try!(try!(File::open(&path)).read_to_end(&mut contents));
+                let name=path.file_name().unwrap().to_string_lossy().into_owned();
+                Ok(Response::Patch(Some((name,contents))))
+            } else {
+                Ok(Response::Patch(None))
+            }
+        },
+        Request::PutPatch(name,contents)=>{
+            if patch_hash_of_file_name(&name).is_none() {
+                return Ok(Response::Error(format!("Invalid patch file name {:?}",name)))
+            }
+            try!(store_patch(root,&name,&contents));
+            Ok(Response::Ok)
+        },
+        Request::Apply(branch,hashes)=>{
+            let mut patches=HashSet::new();
+            for h in hashes {
+                if patch_path(root,&h).is_none() {
+                    return Ok(Response::Error(format!("Patch {} was not uploaded",h.to_hex())))
+                }
+                patches.insert(h);
+            }
+            try!(apply::apply_patches(root,&branch,&patches));
+            Ok(Response::Ok)
+        },
+        Request::Quit=>Ok(Response::Ok)
+    }
+}
+
+/// Encode a single value in CBOR.
+pub fn to_cbor<T:Encodable>(t:&T)->Result<Vec<u8>,Error> {
+    let mut buf=Vec::new();
+    {
+        let mut encoder=cbor::Encoder::from_writer(&mut buf);
+        try!(t.encode(&mut encoder));
+        try!(encoder.flush());
+    }
+    Ok(buf)
+}
+
+/// Encode `requests` as the input of `pijul protocol`.
+pub fn encode_requests(requests:&[Request])->Result<Vec<u8>,Error> {
+    let mut buf=Vec::new();
+    {
+        let mut encoder=cbor::Encoder::from_writer(&mut buf);
+        for r in requests {
+            try!(r.encode(&mut encoder));
+        }
+        try!(Request::Quit.encode(&mut encoder));
+        try!(encoder.flush());
+    }
+    Ok(buf)
+}
+
+/// Decode the output of `pijul protocol`.
+pub fn decode_responses(output:&[u8])->Result<Vec<Response>,Error> {
+    let mut output=output;
+    let mut responses=Vec::new();
+    while let Some(r)=try!(read_response(&mut output)) {
+        responses.push(r)
+    }
+    Ok(responses)
+}
@@ -24,13 +24,16 @@ use super::error::Error;
 use std::path::Path;
 
 extern crate libpijul;
-use self::libpijul::fs_representation::{find_repo_root};
+use self::libpijul::fs_representation::{find_repo_root, pristine_dir};
 use self::libpijul::patch::{Patch};
+use self::libpijul::DEFAULT_BRANCH;
 
 use super::remote;
+use super::apply;
 use std::fs::File;
-use super::ask::{ask_apply,Command};
+use super::ask::{ask_apply,print_patches,Command};
 use super::get_wd;
+use super::lock::RepositoryLock;
 
 use super::super::meta::{Meta,Repository};
 
@@ -39,7 +42,7 @@ pub fn invocation() -> StaticSubcommand {
         SubCommand::with_name("pull")
         .about("pull from a remote repository")
         .arg(Arg::with_name("remote")
-             .help("Repository from which to pull.")
+             .help("Repository from which to pull, or the name of a remote.")
              )
         .arg(Arg::with_name("repository")
              .help("Local repository.")
@@ -53,6 +56,20 @@ pub fn invocation() -> StaticSubcommand {
         .arg(Arg::with_name("set-default")
              .long("set-default")
              )
+        .arg(Arg::with_name("from-branch")
+             .long("from-branch")
+             .help("Branch of the remote repository to pull from (defaults to the remote's default branch, or main).")
+             .takes_value(true)
+             )
+        .arg(Arg::with_name("to-branch")
+             .long("to-branch")
+             .help("Local branch to pull to, which must be the current one.")
+             .takes_value(true)
+             )
+        .arg(Arg::with_name("dry-run")
+             .long("dry-run")
+             .help("Show the patches that would be pulled and the files they would change, without pulling.")
+             )
         .arg(Arg::with_name("port")
              .short("p")
              .long("port")
@@ -63,6 +80,16 @@ pub fn invocation() -> StaticSubcommand {
                                           Err(_)=>Err(val) }
              })
              )
+        .arg(Arg::with_name("jobs")
+             .short("j")
+             .long("jobs")
+             .help("Number of patches downloaded in parallel (default 4).")
+             .takes_value(true)
+             .validator(|val| { let x:Result<usize,_>=val.parse();
+                                match x { Ok(n) if n>0 =>Ok(()),
+                                          _=>Err(val) }
+             })
+             )
 }
 
 #[derive(Debug)]
@@ -71,7 +98,11 @@ pub struct Params<'a> {
     pub remote_id : Option<&'a str>,
     pub yes_to_all : bool,
     pub set_default : bool,
-    pub port : Option<u64>
+    pub dry_run : bool,
+    pub from_branch : Option<&'a str>,
+    pub to_branch : Option<&'a str>,
+    pub port : Option<u64>,
+    pub jobs : usize
 }
 
 pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
@@ -82,7 +113,11 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
              remote_id : remote_id,
              yes_to_all : args.is_present("all"),
              set_default : args.is_present("set-default"),
-             port : args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) }) }
+             dry_run : args.is_present("dry-run"),
+             from_branch : args.value_of("from-branch"),
+             to_branch : args.value_of("to-branch"),
+             port : args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) }),
+             jobs : args.value_of("jobs").and_then(|x| { Some(x.parse().unwrap()) }).unwrap_or(remote::DEFAULT_JOBS) }
 }
 
 pub fn run<'a>(args : &Params<'a>) -> Result<(), Error> {
@@ -99,22 +134,54 @@ pub fn run<'a>(args : &Params<'a>) -> Result<(), Error> {
             */
             let meta = match Meta::load(r) { Ok(m)=>m, Err(_)=> { Meta::new() } };
             let mut savable=false;
-            let remote={
+            let (remote,remote_name)={
                 if let Some(remote_id)=args.remote_id {
                     savable=true;
-                    remote::parse_remote(remote_id,args.port,None)
+                    let (url,port)=meta.resolve_remote(remote_id,args.port);
+                    (remote::parse_remote(url,port,None),remote_id)
                 } else {
                     match meta.pull {
-                        Some(Repository::SSH{ref address,ref port}) => remote::parse_remote(address,Some(*port as u64),Some(r)),
-                        Some(Repository::String(ref host)) => remote::parse_remote(host,None,Some(r)),
+                        Some(Repository::SSH{ref address,ref port}) => {
+                            let (url,port)=meta.resolve_remote(address,Some(*port as u64));
+                            (remote::parse_remote(url,port,Some(r)),&address[..])
+                        },
+                        Some(Repository::String(ref host)) => {
+                            let (url,port)=meta.resolve_remote(host,None);
+                            (remote::parse_remote(url,port,Some(r)),&host[..])
+                        },
                         None=>{
                             return Err(Error::MissingRemoteRepository)
                         }
                     }
                 }
             };
+            let from_branch=args.from_branch.or(meta.default_branch(remote_name)).unwrap_or(DEFAULT_BRANCH);
+            let to_branch=match args.to_branch {
+                Some(b)=>{
+                    try!(apply::check_current_branch(r,b.as_bytes()));
+                    b.as_bytes().to_vec()
+                },
+                None=>try!(apply::current_branch(r))
+            };
             let mut session=try!(remote.session());
-            let mut pullable=try!(session.pullable_patches(r));
+            let mut pullable=try!(session.pullable_patches(r,from_branch.as_bytes(),&to_branch));
+            if args.dry_run {
+                // Nothing is written to the repository.
+                let (downloaded,simulation)=try!(session.simulate_pull(r,&pullable,args.jobs));
+                let (hashes,patches):(Vec<Vec<u8>>,Vec<Patch>)=downloaded.into_iter().unzip();
+                let patches:Vec<(&[u8],Patch)>=hashes.iter().map(|h| &h[..]).zip(patches.into_iter()).collect();
+                print_patches(&patches);
+                for f in simulation.changed.iter() {
+                    println!("Would change {}",f.display())
+                }
+                for f in simulation.conflicts.iter() {
+                    println!("Would conflict {}",f.display())
+                }
+                return Ok(())
+            }
+            // Downloaded patches must not be collected or amended away
+            // before they are applied.
+            let _lock=try!(RepositoryLock::acquire(r));
             // Loading a patch's dependencies
             if !args.yes_to_all {
                 let selected={
@@ -127,12 +194,13 @@ pub fn run<'a>(args : &Params<'a>) -> Result<(), Error> {
                         };
                         patches.push((&i[..],patch));
                     }
-                    try!(ask_apply(Command::Pull,&patches))
+                    // Deleted lines are shown from the pristine.
+                    let repo=try!(libpijul::Repository::new_readonly(&pristine_dir(r)));
+                    try!(ask_apply(Command::Pull,&repo,&patches))
                 };
                 pullable.remote=selected;
             }
-            // Pulling and applying
-            try!(session.pull(r,&pullable));
+            try!(session.pull(r,&pullable,args.jobs));
             if args.set_default && savable {
                 let mut meta = match Meta::load(r) { Ok(m)=>m, Err(_)=> { Meta::new() } };
                 if let Some(remote_id)=args.remote_id {
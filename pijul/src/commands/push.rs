@@ -22,10 +22,12 @@ use clap::{SubCommand, ArgMatches,Arg};
 use super::StaticSubcommand;
 use super::error::Error;
 use super::remote;
+use super::apply;
 use std::path::Path;
 extern crate libpijul;
-use self::libpijul::fs_representation::{find_repo_root};
+use self::libpijul::fs_representation::{find_repo_root, pristine_dir};
 use self::libpijul::patch::{Patch};
+use self::libpijul::DEFAULT_BRANCH;
 
 use super::get_wd;
 use super::super::meta::{Meta,Repository};
@@ -35,7 +37,7 @@ pub fn invocation() -> StaticSubcommand {
         SubCommand::with_name("push")
         .about("push to a remote repository")
         .arg(Arg::with_name("remote")
-             .help("Repository to push to.")
+             .help("Repository to push to, or the name of a remote.")
              )
         .arg(Arg::with_name("repository")
              .help("Local repository.")
@@ -49,6 +51,20 @@ pub fn invocation() -> StaticSubcommand {
         .arg(Arg::with_name("set-default")
              .long("set-default")
              )
+        .arg(Arg::with_name("from-branch")
+             .long("from-branch")
+             .help("Local branch to push (defaults to the current one).")
+             .takes_value(true)
+             )
+        .arg(Arg::with_name("to-branch")
+             .long("to-branch")
+             .help("Branch of the remote repository to push to, which must be its current branch (defaults to the remote's default branch, or main).")
+             .takes_value(true)
+             )
+        .arg(Arg::with_name("dry-run")
+             .long("dry-run")
+             .help("Show the patches that would be pushed, without pushing.")
+             )
         .arg(Arg::with_name("port")
              .short("p")
              .long("port")
@@ -67,6 +83,9 @@ pub struct Params<'a> {
     pub remote_id : Option<&'a str>,
     pub yes_to_all : bool,
     pub set_default : bool,
+    pub dry_run : bool,
+    pub from_branch : Option<&'a str>,
+    pub to_branch : Option<&'a str>,
     pub port : Option<u64>
 }
 
@@ -77,6 +96,9 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
              remote_id : remote_id,
              yes_to_all : args.is_present("all"),
              set_default : args.is_present("set-default"),
+             dry_run : args.is_present("dry-run"),
+             from_branch : args.value_of("from-branch"),
+             to_branch : args.value_of("to-branch"),
              port : args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) }) }
 }
 
@@ -87,22 +109,42 @@ pub fn run<'a>(args : &Params<'a>) -> Result<(), Error> {
         Some(ref r) => {
             let meta = match Meta::load(r) { Ok(m)=>m, Err(_)=> { Meta::new() } };
             let mut savable=false;
-            let remote={
+            let (remote,remote_name)={
                 if let Some(remote_id)=args.remote_id {
                     savable=true;
-                    remote::parse_remote(remote_id,args.port,None)
+                    let (url,port)=meta.resolve_remote(remote_id,args.port);
+                    (remote::parse_remote(url,port,None),remote_id)
                 } else {
-                    match meta.pull {
-                        Some(Repository::SSH{ref address,ref port}) => remote::parse_remote(address,Some(*port as u64),Some(r)),
-                        Some(Repository::String(ref host)) => remote::parse_remote(host,None,Some(r)),
+                    match meta.push {
+                        Some(Repository::SSH{ref address,ref port}) => {
+                            let (url,port)=meta.resolve_remote(address,Some(*port as u64));
+                            (remote::parse_remote(url,port,Some(r)),&address[..])
+                        },
+                        Some(Repository::String(ref host)) => {
+                            let (url,port)=meta.resolve_remote(host,None);
+                            (remote::parse_remote(url,port,Some(r)),&host[..])
+                        },
                         None=>{
                             return Err(Error::MissingRemoteRepository)
                         }
                     }
                 }
             };
+            let from_branch=match args.from_branch {
+                Some(b)=>b.as_bytes().to_vec(),
+                None=>try!(apply::current_branch(r))
+            };
+            let to_branch=args.to_branch.or(meta.default_branch(remote_name)).unwrap_or(DEFAULT_BRANCH);
             let mut session=try!(remote.session());
-            let mut pushable=try!(session.pushable_patches(r));
+            let mut pushable=try!(session.pushable_patches(r,&from_branch,to_branch.as_bytes()));
+            if args.dry_run {
+                let mut patches=Vec::new();
+                for i in pushable.iter() {
+                    patches.push((&i[..],try!(Patch::from_repository(r,i))));
+                }
+                super::ask::print_patches(&patches);
+                return Ok(())
+            }
             if !args.yes_to_all {
                 let selected={
                     let mut patches=Vec::new();
@@ -110,12 +152,13 @@ pub fn run<'a>(args : &Params<'a>) -> Result<(), Error> {
                         let patch=try!(Patch::from_repository(r,i));
                         patches.push((&i[..],patch));
                     }
-                    try!(super::ask::ask_apply(super::ask::Command::Push,&patches))
+                    let repo=try!(libpijul::Repository::new_readonly(&pristine_dir(r)));
+                    try!(super::ask::ask_apply(super::ask::Command::Push,&repo,&patches))
                 };
                 pushable=selected;
             }
 
-            try!(session.push(r,&pushable));
+            try!(session.push(r,to_branch.as_bytes(),&pushable));
             if args.set_default && savable {
                 let mut meta = match Meta::load(r) { Ok(m)=>m, Err(_)=> { Meta::new() } };
                 if let Some(remote_id)=args.remote_id {
@@ -29,11 +29,17 @@ extern crate time;
 use commands::error::Error;
 
 extern crate rand;
-use std::path::{Path};
+use std::path::{Path,PathBuf};
+use std::fs::{File,canonicalize};
+use std::io::{Read,stdin};
 
 use super::super::meta::{Meta};
 use super::ask;
-use super::get_wd;
+use super::editor;
+use super::{get_wd, check_not_bare};
+use super::lock::RepositoryLock;
+use super::hooks;
+use super::hooks::Hook;
 use std::collections::BTreeMap;
 
 pub fn invocation() -> StaticSubcommand {
@@ -53,9 +59,27 @@ pub fn invocation() -> StaticSubcommand {
         .arg(Arg::with_name("message")
              .short("m")
              .long("name")
-             .help("Answer 'y' to all questions")
+             .help("Name of the patch. Without this option, the name and description are asked in an editor.")
+             .takes_value(true)
+             )
+        .arg(Arg::with_name("description")
+             .short("d")
+             .long("description")
+             .help("Description of the patch.")
              .takes_value(true)
              )
+        .arg(Arg::with_name("message-file")
+             .short("F")
+             .long("message-file")
+             .help("Read the name of the patch (on the first line) and its description (on the next ones) from this file, or from the standard input if it is \"-\".")
+             .takes_value(true)
+             )
+        .arg(Arg::with_name("edit")
+             .short("e")
+             .long("edit")
+             .help("Choose the changes to record, and the name, authors and description of the patch, in a text editor.")
+             .takes_value(false)
+             )
         .arg(Arg::with_name("author")
              .short("A")
              .long("author")
@@ -63,22 +87,79 @@ pub fn invocation() -> StaticSubcommand {
              .multiple(true)
              .takes_value(true)
              )
+        .arg(Arg::with_name("prefix")
+             .multiple(true)
+             .help("Only record the changes to these files and directories (everything by default).")
+             )
 }
 
 pub struct Params<'a> {
     pub repository : Option<&'a Path>,
     pub patch_name : Option<&'a str>,
+    pub description : Option<&'a str>,
+    pub message_file : Option<&'a str>,
     pub authors : Option<Vec<&'a str>>,
-    pub yes_to_all : bool
+    pub yes_to_all : bool,
+    pub edit : bool,
+    /// Shell command of the editor, the environment's one by default.
+    pub editor : Option<&'a str>,
+    pub prefixes : Vec<&'a Path>
 }
 
 pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a>
 {
     Params { repository : args.value_of("repository").and_then(|x| { Some(Path::new(x)) }),
              yes_to_all : args.is_present("all"),
+             edit : args.is_present("edit"),
+             editor : None,
              authors : args.values_of("author"),
-             patch_name : args.value_of("message")
+             patch_name : args.value_of("message"),
+             description : args.value_of("description"),
+             message_file : args.value_of("message-file"),
+             prefixes : match args.values_of("prefix") {
+                 Some(l) => l.iter().map(|&p| { Path::new(p) }).collect(),
+                 None => vec!()
+             }
+    }
+}
+
+/// Read a patch message from `file`, or from the standard input if
+/// `file` is "-".
+fn read_message_file(file:&str)->Result<String,Error> {
+    let mut message=String::new();
+    if file=="-" {
+        try!(stdin().read_to_string(&mut message));
+    } else {
+        try!(try!(File::open(file)).read_to_string(&mut message));
     }
+    Ok(message)
+}
+
+/// The path of `path`, relative to the working directory `wd`, from
+/// the root `repo_root` of the repository. `path` might have been
+/// deleted, in which case only its parent must exist.
+fn repo_relative_path(wd:&Path,repo_root:&Path,path:&Path)->Result<PathBuf,Error> {
+    let p=wd.join(path);
+    let p=match canonicalize(&p) {
+        Ok(p)=>p,
+        Err(e)=>match (p.parent(),p.file_name()) {
+            (Some(parent),Some(name))=>try!(canonicalize(parent)).join(name),
+            _=>return Err(Error::IO(e))
+        }
+    };
+    if p.starts_with(repo_root) {
+        Ok(p.iter().skip(repo_root.iter().count()).collect())
+    } else {
+        Err(Error::InvalidPath(path.to_string_lossy().into_owned()))
+    }
+}
+
+pub fn authors_of_args(authors:&[&str])->Vec<BTreeMap<String,Value>> {
+    authors.iter().map(|x| {
+        let mut b=BTreeMap::new();
+        b.insert("name".to_string(),Value::String(x.to_string()));
+        b
+    }).collect()
 }
 
 pub fn run(args : &Params) -> Result<Option<()>, Error> {
@@ -87,13 +168,44 @@ pub fn run(args : &Params) -> Result<Option<()>, Error> {
         None => return Err(Error::NotInARepository),
         Some(ref r) =>
         {
+            try!(check_not_bare(r));
+            let _lock=try!(RepositoryLock::acquire(r));
             let repo_dir=pristine_dir(r);
             let t0=time::precise_time_s();
+            let mut prefixes=Vec::new();
+            for p in args.prefixes.iter() {
+                prefixes.push(try!(repo_relative_path(&wd,r,p)))
+            }
+            let mut edited=None;
+            let editor_command=args.editor.map(|e| e.to_string()).unwrap_or_else(editor::default_editor);
             let (changes,syncs)= {
                 let mut repo = try!(Repository::new(&repo_dir).map_err(Error::Repository));
-                let (changes,syncs)=try!(repo.record(&r).map_err(Error::Repository));
-                if !args.yes_to_all {
-                    let c=try!(super::ask::ask_record(&repo,&changes));
+                let (mut changes,mut files,syncs)=try!(repo.record_prefixes(&r,&prefixes).map_err(Error::Repository));
+                if args.edit && !changes.is_empty() {
+                    let meta=Meta::load(r).unwrap_or(Meta::new());
+                    let authors=match args.authors {
+                        Some(ref authors)=>authors_of_args(authors),
+                        None=>meta.default_authors.clone()
+                    };
+                    let description=args.description.or(meta.message_template.as_ref().map(|t| &t[..]));
+                    match try!(editor::edit_record(&editor_command,r,&repo,&changes,&files,args.patch_name,&authors,description)) {
+                        Some(e)=>{
+                            let selected =
+                                changes.into_iter()
+                                .enumerate()
+                                .filter(|&(i,_)| e.selected.contains(&i))
+                                .map(|(_,x)| x)
+                                .collect();
+                            edited=Some(e);
+                            (selected,syncs)
+                        },
+                        None=>{
+                            println!("Record cancelled");
+                            return Ok(None)
+                        }
+                    }
+                } else if !args.yes_to_all {
+                    let c=try!(super::ask::ask_record(&repo,&mut changes,&mut files));
                     let selected =
                         changes.into_iter()
                         .enumerate()
@@ -118,13 +230,12 @@ pub fn run(args : &Params) -> Result<Option<()>, Error> {
                     let mut save_meta=false;
                     let mut meta = match Meta::load(r) { Ok(m)=>m, Err(_)=> { save_meta=true; Meta::new() } };
                     debug!("meta:{:?}",meta);
+                    let edited_authors=edited.as_ref().map(|e| e.authors.clone()).unwrap_or(Vec::new());
                     let authors :Vec<BTreeMap<String,Value>>=
-                        if let Some(ref authors)=args.authors {
-                            let authors:Vec<BTreeMap<String,Value>>=authors.iter().map(|x| {
-                                let mut b=BTreeMap::new();
-                                b.insert("name".to_string(),Value::String(x.to_string()));
-                                b
-                            }).collect();
+                        if edited_authors.len()>0 {
+                            edited_authors
+                        } else if let Some(ref authors)=args.authors {
+                            let authors=authors_of_args(authors);
                             {
                                 if meta.default_authors.len()==0 {
                                     meta.default_authors=authors.clone();
@@ -143,11 +254,32 @@ pub fn run(args : &Params) -> Result<Option<()>, Error> {
                             }
                         };
                     debug!("authors:{:?}",authors);
-                    let patch_name=
-                        if let Some(ref m)=args.patch_name {
-                            m.to_string()
+                    let (patch_name,description)=
+                        if let Some(e)=edited {
+                            match e.name {
+                                Some(name)=>(name,e.description),
+                                None=>(try!(ask::ask_patch_name()),e.description)
+                            }
+                        } else if let Some(ref m)=args.patch_name {
+                            (m.to_string(),args.description.map(|d| d.to_string()))
                         } else {
-                            try!(ask::ask_patch_name())
+                            let message=
+                                if let Some(file)=args.message_file {
+                                    editor::parse_message(&try!(read_message_file(file)))
+                                } else if ask::stdin_is_terminal() {
+                                    try!(editor::edit_message(&editor_command,r,meta.message_template.as_ref().map(|t| &t[..])))
+                                } else {
+                                    Some((try!(ask::ask_patch_name()),None))
+                                };
+                            match message {
+                                // The description given on the command
+                                // line wins over that of the message.
+                                Some((name,description))=>(name,args.description.map(|d| d.to_string()).or(description)),
+                                None=>{
+                                    println!("Empty message, record cancelled");
+                                    return Ok(None)
+                                }
+                            }
                         };
                     debug!("patch_name:{:?}",patch_name);
                     if save_meta {
@@ -156,15 +288,17 @@ pub fn run(args : &Params) -> Result<Option<()>, Error> {
                     debug!("new");
                     Patch::new(authors,
                                patch_name,
-                               None,
+                               description,
                                self::time::now().to_timespec().sec,
                                changes)
                 };
+                try!(hooks::run(r,Hook::PreRecord,&[],hooks::summary(&patch).as_bytes()));
                 debug!("register_patch");
                 // save patch
                 let mut repo = try!(Repository::new(&repo_dir).map_err(Error::Repository));
-                let () = try!(repo.apply_local_patch(r, patch, &syncs).map_err(Error::Repository));
+                let hash = try!(repo.apply_local_patch(r, patch, &syncs).map_err(Error::Repository));
                 try!(repo.commit());
+                try!(hooks::run_with_hashes(r,Hook::PostRecord,[hash].iter()));
                 Ok(Some(()))
             }
         }
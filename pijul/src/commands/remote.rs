@@ -19,16 +19,16 @@
 extern crate clap;
 
 extern crate libpijul;
-use self::libpijul::{Repository,DEFAULT_BRANCH};
-use self::libpijul::patch::{read_changes_from_file,read_changes};
-use self::libpijul::fs_representation::{repo_dir, pristine_dir, patches_dir, branch_changes_base_path,branch_changes_file,PIJUL_DIR_NAME,PATCHES_DIR_NAME,patch_path,patch_path_iter};
+use self::libpijul::{Repository,Simulation};
+use self::libpijul::patch::{Patch,read_changes_from_file,read_changes,tmp_file_path,hash_patch_file};
+use self::libpijul::fs_representation::{repo_dir, pristine_dir, patches_dir, branch_changes_base_path,branch_changes_file,PIJUL_DIR_NAME,PATCHES_DIR_NAME,patch_path,patch_path_iter,patch_hash_of_file_name,parse_patch_file_name,list_branches};
 use std::path::{Path,PathBuf};
 use std::io::{BufWriter};
 use std::collections::hash_set::{HashSet};
-use std::fs::{File,hard_link,copy,metadata};
+use std::fs::{File,hard_link,copy,metadata,rename,remove_file,create_dir_all,remove_dir_all};
+use std::io;
 
 use super::error::Error;
-use std::str::{from_utf8,from_utf8_unchecked};
 extern crate ssh;
 use self::ssh::Channel;
 use std::io::prelude::*;
@@ -41,12 +41,33 @@ use self::rustc_serialize::hex::{ToHex};
 use super::escape::unix::escape;
 use std::borrow::Cow;
 use super::init;
+use super::apply;
+use super::protocol;
+use super::http;
+use super::ask;
+use super::hooks;
+use super::hooks::Hook;
+use std::env;
+use super::protocol::{Request,Response};
 use std::collections::hash_set::Iter;
-use std::fmt::Debug;
+use std::thread;
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{channel,Receiver};
+use std::thread::JoinHandle;
+use std::collections::VecDeque;
+use std::cmp::max;
 extern crate hyper;
+extern crate cbor;
 
 const HTTP_MAX_ATTEMPTS:usize=3;
 
+/// Default number of parallel downloads.
+pub const DEFAULT_JOBS:usize=4;
+
+/// Environment variable that, when set (to anything but "no" or "0"),
+/// refuses unknown SSH hosts instead of asking whether to trust them.
+pub const STRICT_HOST_KEY_CHECKING_VAR:&'static str="PIJUL_STRICT_HOST_KEY_CHECKING";
+
 #[derive(Debug)]
 pub enum Remote<'a> {
     Ssh { user:Option<&'a str>, host:&'a str, port:Option<u64>, path:&'a Path, id:&'a str },
@@ -61,35 +82,329 @@ pub enum Session<'a> {
         session:ssh::Session
     },
     Uri { uri:&'a str,
-          client:hyper::Client },
+          client:http::Client },
     Local{path:&'a Path},
 }
 
 
-fn ssh_recv_file<'b,P:AsRef<Path>>(s:&'b mut ssh::Session,p:P)->Result<Option<ssh::Scp<'b>>,ssh::Error> where P:Debug {
-    debug!("recv_file {:?}",p);
-    let mut scp=s.scp_new(ssh::READ,p.as_ref()).unwrap();
-    scp.init().unwrap();
-    loop {
-        let req=scp.pull_request();
-        debug!("req={:?}",req);
-        match req {
-            Ok(ssh::Request::NEWFILE)=>{
-                scp.accept_request().unwrap();
-                scp.reader();
-                return Ok(Some(scp))
-            },
-            Ok(ssh::Request::WARNING)=>{
-                info!("Warning: {}",from_utf8(scp.request_get_warning().unwrap()).unwrap());
+/// A run of `pijul protocol` in the repository of an SSH remote. The
+/// responses to the requests sent are read one at a time, in the
+/// order of the requests.
+struct SshProtocol<'b> {
+    channel:Channel<'b>,
+    id:&'b str
+}
+
+impl<'b> SshProtocol<'b> {
+    /// Run `pijul protocol` in the remote repository at `path`.
+    fn start(session:&'b mut ssh::Session, path:&Path, id:&'b str)->Result<SshProtocol<'b>,Error> {
+        let mut channel=try!(session.channel_new());
+        try!(channel.open_session());
+        let esc_path=escape(Cow::Borrowed(path.to_str().unwrap()));
+        try!(channel.request_exec(format!("cd \"{}\"; pijul protocol",esc_path).as_bytes()));
+        Ok(SshProtocol { channel:channel, id:id })
+    }
+
+    fn send(&mut self, request:&Request)->Result<(),Error> {
+        let buf=try!(protocol::to_cbor(request));
+        try!(self.channel.stdin().write_all(&buf));
+        Ok(())
+    }
+
+    /// The response to the oldest request not answered yet. A
+    /// `Response::Error` is returned as an error for that request
+    /// only, the following responses can still be read.
+    fn receive(&mut self)->Result<Response,Error> {
+        match protocol::read_response(&mut self.channel.stdout()) {
+            Ok(Some(Response::Error(msg)))=>Err(Error::RemoteProtocol(self.id.to_string(),msg)),
+            Ok(Some(response))=>Ok(response),
+            Ok(None)=>{
+                // The server stopped, its standard error tells why.
+                let mut msg=String::new();
+                try!(self.channel.stderr().read_to_string(&mut msg));
+                if msg.len()==0 {
+                    msg="connection closed before the end of the protocol".to_string()
+                }
+                Err(Error::RemoteProtocol(self.id.to_string(),msg))
             },
-            Ok(ssh::Request::EOF)=>{
-                return Ok(None)
+            Err(e)=>Err(e)
+        }
+    }
+
+    /// End the protocol.
+    fn finish(mut self)->Result<(),Error> {
+        try!(self.send(&Request::Quit));
+        try!(self.channel.send_eof());
+        Ok(())
+    }
+}
+
+/// Send `request` to `pijul protocol` in the remote repository at
+/// `path`, and return its response.
+fn ssh_request(session:&mut ssh::Session, path:&Path, id:&str, request:&Request)->Result<Response,Error> {
+    let mut protocol=try!(SshProtocol::start(session,path,id));
+    try!(protocol.send(request));
+    let response=try!(protocol.receive());
+    try!(protocol.finish());
+    Ok(response)
+}
+
+/// Store a patch file received from a remote under the name `name`
+/// in the patches directory of `repo_root`. `write` writes the
+/// contents to a temporary file, which is then moved to its final
+/// name only if the patch it contains hashes to the hash in `name`.
+fn store_patch_with<F>(repo_root:&Path, name:&str, write:F)->Result<PathBuf,Error>
+    where F:FnOnce(&Path)->Result<(),io::Error> {
+    let (hash,extension)=match parse_patch_file_name(name) {
+        Some(x)=>x,
+        None=>return Err(Error::InvalidPath(name.to_string()))
+    };
+    let patches=patches_dir(repo_root);
+    let tmp=tmp_file_path(&patches);
+    try!(write(&tmp));
+    match hash_patch_file(&tmp,extension) {
+        Ok(ref h) if *h==hash => {
+            let file=patches.join(name);
+            try!(rename(&tmp,&file));
+            Ok(file)
+        },
+        Ok(h)=>{
+            try!(remove_file(&tmp));
+            Err(Error::PatchHashMismatch(name.to_string(),h.to_hex()))
+        },
+        Err(e)=>{
+            try!(remove_file(&tmp));
+            Err(Error::Repository(e))
+        }
+    }
+}
+
+/// Store the patch file with name `name` and contents `contents` in
+/// the patches directory of `repo_root`, checking its hash (see
+/// `store_patch_with`).
+pub fn store_patch(repo_root:&Path, name:&str, contents:&[u8])->Result<PathBuf,Error> {
+    store_patch_with(repo_root,name,|tmp| {
+        let mut w=BufWriter::new(try!(File::create(tmp)));
+        try!(w.write_all(contents));
+        w.flush()
+    })
+}
+
+#[derive(Clone)]
+enum DownloadSource {
+    Local(PathBuf),
+    Http(String,http::Config)
+}
+
+fn download_local(path:&Path, repo_root:&Path, patch_hash:&[u8])->Result<PathBuf,Error> {
+    debug!("local downloading {:?}",patch_hash.to_hex());
+    if let Some(local_file)=patch_path(repo_root,patch_hash) {
+        Ok(local_file)
+    } else {
+        if let Some(remote_file)=patch_path(path,patch_hash) {
+            let name=remote_file.file_name().unwrap().to_string_lossy().into_owned();
+            debug!("hard linking {:?} to {:?}",remote_file,repo_root);
+            store_patch_with(repo_root,&name,|tmp| {
+                hard_link(&remote_file,tmp).or_else(|_|{
+                    copy(&remote_file, tmp).and_then(|_| Ok(()))
+                })
+            })
+        } else {
+            Err(Error::PatchNotFound(path.to_path_buf().to_string_lossy().into_owned(),
+                                     patch_hash.to_hex()))
+        }
+    }
+}
+
+fn download_http(client:&http::Client, uri:&str, repo_root:&Path, patch_hash:&[u8])->Result<PathBuf,Error> {
+    if let Some(local_file)=patch_path(repo_root,patch_hash) { // If we don't have it yet
+        Ok(local_file)
+    } else {
+        for remote_file in patch_path_iter(patch_hash,'/') {
+            let name=Path::new(&remote_file).file_name().unwrap().to_string_lossy().into_owned();
+            let uri = uri.to_string() + "/" + &remote_file;
+            debug!("downloading uri {:?}",uri);
+            let mut attempts=0;
+            while attempts<HTTP_MAX_ATTEMPTS {
+                match client.get(&uri) {
+                    Ok(ref mut res) if res.status==hyper::status::StatusCode::Ok => {
+                        debug!("response={:?}",res);
+                        let mut body=Vec::new();
+                        try!(res.read_to_end(&mut body));
+                        debug!("patch downloaded through http: {:?}",body);
+                        return store_patch(repo_root,&name,&body)
+                    },
+                    Ok(_) => {
+                        break
+                    },
+                    Err(e@Error::HTTPUnauthorized(_)) | Err(e@Error::HTTPForbidden(_)) => {
+                        return Err(e)
+                    },
+                    Err(e)=>{
+                        debug!("error downloading : {:?}",e);
+                        attempts+=1;
+                    }
+                }
             }
-            Ok(e)=>{println!("e={:?}",e);panic!("")},
-            Err(e)=>{
-                return Err(e)
+        }
+        Err(Error::PatchNotFound(repo_root.to_str().unwrap().to_string(),
+                                 patch_hash.to_hex()))
+    }
+}
+
+/// Receive the response to `Request::GetPatch(hash)` from the SSH
+/// remote at `path`, and store the patch in `repo_root`.
+fn receive_patch(protocol:&mut SshProtocol, path:&Path, repo_root:&Path, hash:&[u8])->Result<PathBuf,Error> {
+    match try!(protocol.receive()) {
+        Response::Patch(Some((name,contents)))=>{
+            if patch_hash_of_file_name(&name).as_ref().map(|h| &h[..])!=Some(hash) {
+                return Err(Error::RemoteProtocol(protocol.id.to_string(),format!("invalid patch file name {:?}",name)))
             }
+            debug!(target:"pull","downloading {:?}",name);
+            store_patch(repo_root,&name,&contents)
+        },
+        Response::Patch(None)=>
+            Err(Error::PatchNotFound(path.to_path_buf().to_string_lossy().into_owned(),hash.to_hex())),
+        r=>Err(Error::RemoteProtocol(protocol.id.to_string(),format!("unexpected response {:?}",r)))
+    }
+}
+
+/// Download the patches in `hashes` from the SSH remote at `path`,
+/// in a single run of `pijul protocol`. Each patch is stored as soon
+/// as it is received, even if a later one fails.
+fn download_ssh(session:&mut ssh::Session, path:&Path, id:&str, repo_root:&Path, hashes:&[Vec<u8>])->Result<Vec<PathBuf>,Error> {
+    let mut files:Vec<Option<PathBuf>>=hashes.iter().map(|h| patch_path(repo_root,h)).collect();
+    let missing:Vec<usize>=(0..hashes.len()).filter(|&i| files[i].is_none()).collect();
+    if missing.len()>0 {
+        debug!("ssh: receiving {} patches",missing.len());
+        let mut protocol=try!(SshProtocol::start(session,path,id));
+        for &i in missing.iter() {
+            try!(protocol.send(&Request::GetPatch(hashes[i].clone())))
+        }
+        for &i in missing.iter() {
+            files[i]=Some(try!(receive_patch(&mut protocol,path,repo_root,&hashes[i])))
         }
+        try!(protocol.finish());
+    }
+    Ok(files.into_iter().map(|f| f.unwrap()).collect())
+}
+
+/// Number of patches requested from SSH remotes without waiting for
+/// the answers.
+const SSH_WINDOW:usize=32;
+
+/// Downloads from an SSH remote, in a single run of `pijul protocol`.
+/// Up to `SSH_WINDOW` requests are kept in flight, a new one being
+/// sent each time a patch is received.
+struct SshDownloads<'b> {
+    session:Option<&'b mut ssh::Session>,
+    protocol:Option<SshProtocol<'b>>,
+    path:&'b Path,
+    id:&'b str,
+    repo_root:&'b Path,
+    /// Patches that are already in the repository.
+    present:Vec<Vec<u8>>,
+    remaining:Vec<Vec<u8>>,
+    in_flight:VecDeque<Vec<u8>>,
+    failed:bool
+}
+
+impl<'b> SshDownloads<'b> {
+    /// Fill the window of requests, and receive the oldest one.
+    fn receive_next(&mut self)->Option<Result<Vec<u8>,Error>> {
+        if self.protocol.is_none() {
+            if self.remaining.is_empty() {
+                return None
+            }
+            let session=self.session.take().unwrap();
+            match SshProtocol::start(session,self.path,self.id) {
+                Ok(protocol)=>self.protocol=Some(protocol),
+                Err(e)=>return Some(Err(e))
+            }
+        }
+        let protocol=self.protocol.as_mut().unwrap();
+        while self.in_flight.len()<SSH_WINDOW {
+            match self.remaining.pop() {
+                Some(h)=>{
+                    if let Err(e)=protocol.send(&Request::GetPatch(h.clone())) {
+                        return Some(Err(e))
+                    }
+                    self.in_flight.push_back(h)
+                },
+                None=>break
+            }
+        }
+        match self.in_flight.pop_front() {
+            Some(h)=>Some(receive_patch(protocol,self.path,self.repo_root,&h).map(|_| h)),
+            None=>None
+        }
+    }
+}
+
+impl<'b> Iterator for SshDownloads<'b> {
+    type Item=Result<Vec<u8>,Error>;
+    fn next(&mut self)->Option<Result<Vec<u8>,Error>> {
+        if let Some(h)=self.present.pop() {
+            return Some(Ok(h))
+        }
+        if self.failed {
+            return None
+        }
+        let result=self.receive_next();
+        match result {
+            Some(Err(_))=>self.failed=true,
+            None=>if let Some(protocol)=self.protocol.take() {
+                if let Err(e)=protocol.finish() {
+                    self.failed=true;
+                    return Some(Err(e))
+                }
+            },
+            Some(Ok(_))=>{}
+        }
+        result
+    }
+}
+
+/// Downloads from HTTP and local remotes, by parallel threads. The
+/// threads are stopped and joined after the first error, or when this
+/// is dropped, so that none of them is still writing to the patches
+/// directory afterwards.
+struct ThreadDownloads {
+    queue:Arc<Mutex<Vec<Vec<u8>>>>,
+    receiver:Receiver<Result<Vec<u8>,Error>>,
+    threads:Vec<JoinHandle<()>>
+}
+
+impl ThreadDownloads {
+    fn stop(&mut self) {
+        self.queue.lock().unwrap().clear();
+        for t in self.threads.drain(..) {
+            let _=t.join();
+        }
+    }
+}
+
+impl Iterator for ThreadDownloads {
+    type Item=Result<Vec<u8>,Error>;
+    fn next(&mut self)->Option<Result<Vec<u8>,Error>> {
+        match self.receiver.recv() {
+            Ok(Err(e))=>{
+                self.stop();
+                Some(Err(e))
+            },
+            Ok(result)=>Some(result),
+            Err(_)=>{
+                // All the threads are done.
+                self.stop();
+                None
+            }
+        }
+    }
+}
+
+impl Drop for ThreadDownloads {
+    fn drop(&mut self) {
+        self.stop()
     }
 }
 
@@ -107,15 +422,12 @@ impl <'a> Drop for Session<'a> {
 impl<'a> Session<'a> {
     pub fn changes(&mut self,branch:&[u8]) -> Result<HashSet<Vec<u8>>,Error> {
         match *self {
-            Session::Ssh{ref path,ref mut session,..}=>{
-                let patches_path=branch_changes_file(path,branch);
+            Session::Ssh{ref path,ref mut session,ref id}=>{
                 debug!("ssh: receiving changes");
-                let remote_file = try!(ssh_recv_file(session,&patches_path));
-                let changes= match remote_file {
-                    Some(r)=>try!(read_changes(r,None)),
-                    None=>HashSet::new()
-                };
-                Ok(changes)
+                match try!(ssh_request(session,path,id,&Request::Changes(branch.to_vec()))) {
+                    Response::Changes(changes)=>Ok(changes.into_iter().collect()),
+                    r=>Err(Error::RemoteProtocol(id.to_string(),format!("unexpected response {:?}",r)))
+                }
             },
             Session::Local{path} =>{
                 let changes_file=branch_changes_file(path,branch);
@@ -123,119 +435,133 @@ impl<'a> Session<'a> {
             },
             Session::Uri {uri,ref mut client} =>{
                 let mut uri=uri.to_string();
-                uri = uri + "/" + PIJUL_DIR_NAME + "/" + &branch_changes_base_path(DEFAULT_BRANCH.as_bytes());
-                let mut res = try!(client.get(&uri)
-                                   .header(hyper::header::Connection::close())
-                                   .send());
-                let changes=read_changes(&mut res,None).unwrap_or(HashSet::new());
+                uri = uri + "/" + PIJUL_DIR_NAME + "/" + &branch_changes_base_path(branch);
+                let mut res = try!(client.get(&uri));
+                if res.status==hyper::status::StatusCode::NotFound {
+                    // The branch has no patches.
+                    return Ok(HashSet::new())
+                } else if res.status!=hyper::status::StatusCode::Ok {
+                    let mut msg=String::new();
+                    try!(res.read_to_string(&mut msg));
+                    return Err(Error::HTTPStatus(uri,res.status,msg))
+                }
+                let changes=try!(read_changes(&mut res,None));
                 debug!("http: {:?}",changes);
                 Ok(changes)
             },
         }
     }
-    pub fn download_patch(&mut self, repo_root:&Path, patch_hash:&[u8])->Result<PathBuf,Error>{
+    /// The branches of the remote.
+    pub fn branches(&mut self) -> Result<Vec<Vec<u8>>,Error> {
         match *self {
-            Session::Local{path}=>{
-                debug!("local downloading {:?}",patch_hash.to_hex());
-                if let Some(local_file)=patch_path(repo_root,patch_hash) {
-                    Ok(local_file)
-                } else {
-                    if let Some(remote_file)=patch_path(path,patch_hash) {
-                        let local_file=patches_dir(repo_root).join(remote_file.file_name().unwrap());
-                        debug!("hard linking {:?} to {:?}",remote_file,local_file);
-                        try!(hard_link(&remote_file,&local_file).or_else(|_|{
-                            copy(&remote_file, &local_file).and_then(|_| Ok(()))
-                        }));
-                        Ok(local_file)
-                    } else {
-                        Err(Error::PatchNotFound(path.to_path_buf().to_string_lossy().into_owned(),
-                                                 patch_hash.to_hex()))
-                    }
+            Session::Ssh{ref path,ref mut session,ref id}=>{
+                match try!(ssh_request(session,path,id,&Request::ListBranches)) {
+                    Response::Branches(branches)=>Ok(branches),
+                    r=>Err(Error::RemoteProtocol(id.to_string(),format!("unexpected response {:?}",r)))
                 }
             },
-            Session::Ssh{ref path,ref mut session,..}=>{
-                if let Some(local_file)=patch_path(repo_root,patch_hash) { // If we don't have it yet
-                    Ok(local_file)
-                } else {
-                    for remote_file in patch_path_iter(patch_hash,'/') {
-                        debug!("ssh: receiving patch {}",patch_hash.to_hex());
-                        if let Ok(Some(mut rem))=ssh_recv_file(session,&remote_file) {
-                            let local_file={
-                                let remote_path=Path::new(&remote_file);
-                                patches_dir(repo_root).join(remote_path.file_name().unwrap())
-                            };
-                            let mut contents = Vec::new();
-                            debug!(target:"pull","downloading file to {:?}",local_file);
-                            try!(rem.read_to_end(&mut contents));
-                            let mut w=BufWriter::new(try!(File::create(&local_file)));
-                            try!(w.write_all(&contents));
-                            return Ok(local_file)
-                        }
-                    }
-                    Err(Error::PatchNotFound(path.to_path_buf().to_string_lossy().into_owned(),
-                                             patch_hash.to_hex()))
+            Session::Local{path} => Ok(try!(list_branches(path))),
+            Session::Uri {uri,ref mut client} =>{
+                let uri = uri.to_string() + "/" + PIJUL_DIR_NAME + "/branches";
+                let mut res = try!(client.get(&uri));
+                let mut body=String::new();
+                try!(res.read_to_string(&mut body));
+                if res.status!=hyper::status::StatusCode::Ok {
+                    return Err(Error::HTTPStatus(uri,res.status,body))
                 }
+                Ok(body.lines().filter(|l| l.len()>0).map(|l| l.as_bytes().to_vec()).collect())
+            }
+        }
+    }
+    pub fn download_patch(&mut self, repo_root:&Path, patch_hash:&[u8])->Result<PathBuf,Error>{
+        match *self {
+            Session::Local{path}=>download_local(path,repo_root,patch_hash),
+            Session::Ssh{ref path,ref mut session,ref id}=>{
+                let mut files=try!(download_ssh(session,path,id,repo_root,&[patch_hash.to_vec()]));
+                Ok(files.pop().unwrap())
             },
-            Session::Uri{ref mut client,uri}=>{
-                if let Some(local_file)=patch_path(repo_root,patch_hash) { // If we don't have it yet
-                    Ok(local_file)
-                } else {
-                    for remote_file in patch_path_iter(patch_hash,'/') {
-                        let local_file={
-                            let remote_path=Path::new(&remote_file);
-                            patches_dir(repo_root).join(remote_path.file_name().unwrap())
-                        };
-                        let uri = uri.to_string() + "/" + &remote_file;
-                        debug!("downloading uri {:?}",uri);
-                        let mut attempts=0;
-                        while attempts<HTTP_MAX_ATTEMPTS {
-                            match client.get(&uri).header(hyper::header::Connection::close()).send() {
-                                Ok(ref mut res) if res.status==hyper::status::StatusCode::Ok => {
-                                    debug!("response={:?}",res);
-                                    let mut body=Vec::new();
-                                    try!(res.read_to_end(&mut body));
-                                    let mut f=try!(File::create(&local_file));
-                                    try!(f.write_all(&body));
-                                    debug!("patch downloaded through http: {:?}",body);
-                                    return Ok(local_file)
-                                },
-                                Ok(_) => {
-                                    break
-                                },
-                                Err(e)=>{
-                                    debug!("error downloading : {:?}",e);
-                                    attempts+=1;
-                                }
-                            }
-                        }
+            Session::Uri{ref client,uri}=>download_http(client,uri,repo_root,patch_hash)
+        }
+    }
+
+    /// Download the patches in `hashes` to `repo_root`, using `jobs`
+    /// parallel connections for HTTP and local remotes, and a window
+    /// of requests over a single connection for SSH remotes. The hash
+    /// of each patch is yielded once the patch has been downloaded.
+    pub fn download_patches<'b>(&'b mut self, repo_root:&'b Path, mut hashes:Vec<Vec<u8>>, jobs:usize)
+                                ->Box<Iterator<Item=Result<Vec<u8>,Error>>+'b> {
+        let source=match *self {
+            Session::Ssh{ref path,ref mut session,ref id}=>{
+                hashes.reverse();
+                let (present,remaining):(Vec<Vec<u8>>,Vec<Vec<u8>>)=hashes.into_iter().partition(|h| patch_path(repo_root,h).is_some());
+                return Box::new(SshDownloads { session:Some(session), protocol:None, path:*path, id:*id,
+                                               repo_root:repo_root, present:present, remaining:remaining,
+                                               in_flight:VecDeque::new(), failed:false })
+            },
+            Session::Local{path}=>DownloadSource::Local(path.to_path_buf()),
+            Session::Uri{uri,ref client}=>DownloadSource::Http(uri.to_string(),client.config().clone())
+        };
+        let queue=Arc::new(Mutex::new(hashes));
+        let (sender,receiver)=channel();
+        let mut threads=Vec::new();
+        for _ in 0..max(jobs,1) {
+            let queue=queue.clone();
+            let sender=sender.clone();
+            let source=source.clone();
+            let repo_root=repo_root.to_path_buf();
+            threads.push(thread::spawn(move || {
+                let client=match source {
+                    DownloadSource::Http(_,ref config)=>Some(config.client()),
+                    DownloadSource::Local(_)=>None
+                };
+                loop {
+                    let hash=match queue.lock().unwrap().pop() {
+                        Some(hash)=>hash,
+                        None=>break
+                    };
+                    let result=match source {
+                        DownloadSource::Local(ref path)=>download_local(path,&repo_root,&hash),
+                        DownloadSource::Http(ref uri,_)=>download_http(client.as_ref().unwrap(),uri,&repo_root,&hash)
+                    };
+                    if sender.send(result.map(|_| hash)).is_err() {
+                        break
                     }
-                    Err(Error::PatchNotFound(repo_root.to_str().unwrap().to_string(),
-                                             patch_hash.to_hex()))
                 }
-            }
+            }));
         }
+        Box::new(ThreadDownloads { queue:queue, receiver:receiver, threads:threads })
     }
     // patch hash in binary
     pub fn upload_patches(&mut self, repo_root:&Path, patch_hashes:&HashSet<Vec<u8>>)->Result<(),Error> {
         match *self {
-            Session::Ssh { ref mut session, ref path, .. }=> {
-                let remote_path=path.to_str().unwrap().to_string()+"/"+PIJUL_DIR_NAME+"/"+PATCHES_DIR_NAME;
-                let mut scp=try!(session.scp_new(ssh::WRITE,&remote_path));
-                try!(scp.init());
+            Session::Ssh { ref mut session, ref path, ref id }=> {
+                let mut files=Vec::new();
                 for hash in patch_hashes {
                     debug!("repo_root: {:?},hash:{:?}",repo_root,hash.to_hex());
                     if let Some(path)=patch_path(repo_root,hash) {
-                        let remote_file=remote_path.clone() + "/" + path.file_name().unwrap().to_str().unwrap();
+                        files.push(path)
+                    } else {
+                        return Err(Error::PatchNotFound(repo_root.to_str().unwrap().to_string(),hash.to_hex()))
+                    }
+                }
+                if files.len()>0 {
+                    // Only one patch is read in memory at a time. The
+                    // responses are short, and read once all the
+                    // patches are sent.
+                    let mut protocol=try!(SshProtocol::start(session,path,id));
+                    for path in files.iter() {
                         let mut buf = Vec::new();
                         {
                             let mut f = try!(File::open(&path));
                             try!(f.read_to_end(&mut buf));
                         }
-                        try!(scp.push_file(&remote_file,buf.len(),0o644));
-                        try!(scp.write(&buf));
-                    } else {
-                        return Err(Error::PatchNotFound(repo_root.to_str().unwrap().to_string(),hash.to_hex()))
+                        let name=path.file_name().unwrap().to_str().unwrap().to_string();
+                        try!(protocol.send(&Request::PutPatch(name,buf)));
                     }
+                    for _ in files.iter() {
+                        try!(protocol.receive());
+                    }
+                    try!(protocol.finish());
                 }
                 Ok(())
             },
@@ -254,65 +580,68 @@ impl<'a> Session<'a> {
                 }
                 Ok(())
             },
-            _=>{panic!("upload to URI impossible")}
+            Session::Uri{ref mut client,uri} =>{
+                for hash in patch_hashes {
+                    if let Some(local_file)=patch_path(repo_root,hash) {
+                        let mut buf = Vec::new();
+                        {
+                            let mut f = try!(File::open(&local_file));
+                            try!(f.read_to_end(&mut buf));
+                        }
+                        let remote_file = uri.to_string() + "/" + PIJUL_DIR_NAME + "/" + PATCHES_DIR_NAME
+                            + "/" + local_file.file_name().unwrap().to_str().unwrap();
+                        try!(client.post(&remote_file,&buf));
+                    } else {
+                        return Err(Error::PatchNotFound(repo_root.to_str().unwrap().to_string(),hash.to_hex()))
+                    }
+                }
+                Ok(())
+            }
         }
     }
 
     // patch hash in binary
-    /// Apply patches that have been uploaded.
-    pub fn remote_apply(&mut self, patch_hashes:&HashSet<Vec<u8>>)->Result<(),Error> {
+    /// Apply patches that have been uploaded to `branch`, which must
+    /// be the current branch of the remote.
+    pub fn remote_apply(&mut self, branch:&[u8], patch_hashes:&HashSet<Vec<u8>>)->Result<(),Error> {
         match *self {
-            Session::Ssh { ref mut session, ref path, ref id, .. }=> {
+            Session::Ssh { ref mut session, ref path, ref id }=> {
                 debug!("ssh: remote_apply");
-                let mut s  =try!(session.channel_new());
-                try!(s.open_session());
-                let esc_path=escape(Cow::Borrowed(path.to_str().unwrap()));
-                let mut patches="".to_string();
-                for i in patch_hashes {
-                    patches=patches + " " + &(i.to_hex());
-                }
-                if patches.len()>0 {
-                    let cmd=format!("cd \"{}\"; pijul apply{}",esc_path, &patches);
-                    debug!("command line:{:?}",cmd);
-                    try!(s.request_exec(cmd.as_bytes()));
-                }
-                try!(s.send_eof());
-                let exitcode=s.get_exit_status().unwrap();
-                if exitcode != 0 {
-                    let mut buf=Vec::new();
-                    try!(s.stdout().read_to_end(&mut buf));
-                    try!(s.stderr().read_to_end(&mut buf));
-                    let buf= unsafe { from_utf8_unchecked(&buf) }.to_string();
-                    return Err(Error::RemoteApplyFailed(id.to_string(),exitcode as i32,buf))
-                } else {
-                    let mut buf=Vec::new();
-                    try!(s.stdout().read_to_end(&mut buf));
-                    try!(s.stderr().read_to_end(&mut buf));
-                    let buf= unsafe { from_utf8_unchecked(&buf) }.to_string();
-                    if buf.len() > 0 {
-                        println!("{}",buf)
-                    }
-                    Ok(())
+                if patch_hashes.len()>0 {
+                    let hashes=patch_hashes.iter().cloned().collect();
+                    try!(ssh_request(session,path,id,&Request::Apply(branch.to_vec(),hashes)));
                 }
+                Ok(())
             },
             Session::Local{path} =>{
-                let applied_patches:HashSet<Vec<u8>>=try!(self.changes(DEFAULT_BRANCH.as_bytes()));
-                let repo_dir=pristine_dir(path);
-                let mut repo = try!(Repository::new(&repo_dir).map_err(Error::Repository));
-                try!(repo.apply_patches(path,&patch_hashes,&applied_patches));
-                try!(repo.commit());
+                apply::apply_patches(path,branch,patch_hashes)
+            },
+            Session::Uri{ref mut client,uri} =>{
+                // The server applies the patches whose hashes are
+                // listed in the body, one per line, in hexadecimal.
+                let mut patches=String::new();
+                for i in patch_hashes {
+                    patches.push_str(&i.to_hex());
+                    patches.push('\n');
+                }
+                let apply_uri = uri.to_string() + "/" + PIJUL_DIR_NAME + "/apply." + &branch.to_hex();
+                let response=try!(client.post(&apply_uri,patches.as_bytes()));
+                if response.len() > 0 {
+                    println!("{}",response)
+                }
                 Ok(())
             }
-            _=>{panic!("remote apply not possible")}
         }
     }
-    pub fn remote_init(&mut self)->Result<(),Error> {
+    /// Create a repository on the remote, bare if `bare` is true.
+    pub fn remote_init(&mut self,bare:bool)->Result<(),Error> {
         match *self {
             Session::Ssh { ref mut session, ref path, ref id, .. }=> {
                 let mut s : Channel =try!(session.channel_new());
                 try!(s.open_session());
                 let esc_path=escape(Cow::Borrowed(path.to_str().unwrap()));
-                try!(s.request_exec(format!("mkdir -p \"{}\"; cd \"{}\"; pijul init",esc_path,esc_path).as_bytes()));
+                let init= if bare { "pijul init --bare" } else { "pijul init" };
+                try!(s.request_exec(format!("mkdir -p \"{}\"; cd \"{}\"; {}",esc_path,esc_path,init).as_bytes()));
                 try!(s.send_eof());
                 let exitcode=s.get_exit_status().unwrap();
                 if exitcode != 0 {
@@ -331,51 +660,175 @@ impl<'a> Session<'a> {
                 }
             },
             Session::Local{path} =>{
-                try!(init::run(&init::Params { location:path, allow_nested:false }));
+                try!(init::run(&init::Params { location:path, allow_nested:false, bare:bare }));
                 Ok(())
             }
             _=>{panic!("remote init not possible")}
         }
     }
 
-    pub fn pullable_patches(&mut self,target:&Path) -> Result<Pullable, Error> {
-        let remote_patches:HashSet<Vec<u8>>=try!(self.changes(DEFAULT_BRANCH.as_bytes()));
+    /// The summary of `branch` on the remote (see module `protocol`),
+    /// or `None` if the remote cannot compute summaries. Local
+    /// remotes don't, since reading their changes file is cheap.
+    fn summary(&mut self,branch:&[u8]) -> Result<Option<protocol::Summary>,Error> {
+        match *self {
+            Session::Ssh{ref path,ref mut session,ref id}=>{
+                match ssh_request(session,path,id,&Request::Summary(branch.to_vec())) {
+                    Ok(Response::Summary(s))=>Ok(Some(s)),
+                    Ok(_)=>Ok(None),
+                    Err(Error::RemoteProtocol(_,_))=>Ok(None),
+                    Err(e)=>Err(e)
+                }
+            },
+            Session::Local{..} => Ok(None),
+            Session::Uri {uri,ref mut client} =>{
+                let uri = uri.to_string() + "/" + PIJUL_DIR_NAME + "/summary." + &branch.to_hex();
+                let res = try!(client.get(&uri));
+                if res.status!=hyper::status::StatusCode::Ok {
+                    // Probably a static file server.
+                    return Ok(None)
+                }
+                let mut d=cbor::Decoder::from_reader(res);
+                match d.decode().next() {
+                    Some(Ok(s))=>Ok(Some(s)),
+                    _=>Ok(None)
+                }
+            },
+        }
+    }
+
+    /// The patches of `branch` on the remote, in the given buckets only.
+    fn changes_in_buckets(&mut self,branch:&[u8],buckets:&[u8]) -> Result<HashSet<Vec<u8>>,Error> {
+        match *self {
+            Session::Ssh{ref path,ref mut session,ref id}=>{
+                match try!(ssh_request(session,path,id,&Request::Hashes(branch.to_vec(),buckets.to_vec()))) {
+                    Response::Changes(changes)=>Ok(changes.into_iter().collect()),
+                    r=>Err(Error::RemoteProtocol(id.to_string(),format!("unexpected response {:?}",r)))
+                }
+            },
+            Session::Uri {uri,ref mut client} =>{
+                let uri = uri.to_string() + "/" + PIJUL_DIR_NAME + "/hashes." + &branch.to_hex() + "." + &buckets.to_hex();
+                let mut res = try!(client.get(&uri));
+                if res.status!=hyper::status::StatusCode::Ok {
+                    let mut msg=String::new();
+                    try!(res.read_to_string(&mut msg));
+                    return Err(Error::HTTPStatus(uri,res.status,msg))
+                }
+                Ok(try!(read_changes(&mut res,None)))
+            },
+            Session::Local{..} => {
+                let changes=try!(self.changes(branch));
+                Ok(protocol::in_buckets(&changes,buckets))
+            }
+        }
+    }
+
+    /// Compare the patches of `branch` on the remote with `local`,
+    /// and return the remote and local patches that may differ, i.e.
+    /// everything except patches known to be on both sides.
+    pub fn negotiate(&mut self,branch:&[u8],local:HashSet<Vec<u8>>) -> Result<(HashSet<Vec<u8>>,HashSet<Vec<u8>>),Error> {
+        match try!(self.summary(branch)) {
+            Some(remote_summary)=>{
+                let buckets=protocol::differing_buckets(&remote_summary,&protocol::summarize(&local));
+                debug!("negotiate: {} differing buckets",buckets.len());
+                if buckets.len()==0 {
+                    Ok((HashSet::new(),HashSet::new()))
+                } else {
+                    let remote=try!(self.changes_in_buckets(branch,&buckets));
+                    Ok((remote,protocol::in_buckets(&local,&buckets)))
+                }
+            },
+            None=>{
+                let remote=try!(self.changes(branch));
+                Ok((remote,local))
+            }
+        }
+    }
+
+    /// The patches of `from_branch` on the remote that are not on
+    /// `to_branch` in `target`.
+    pub fn pullable_patches(&mut self,target:&Path,from_branch:&[u8],to_branch:&[u8]) -> Result<Pullable, Error> {
         let local_patches:HashSet<Vec<u8>>={
-            let changes_file=branch_changes_file(target,DEFAULT_BRANCH.as_bytes());
+            let changes_file=branch_changes_file(target,to_branch);
             read_changes_from_file(&changes_file).unwrap_or(HashSet::new())
         };
-        Ok(Pullable { local:local_patches, remote: remote_patches })
+        let (remote,local)=try!(self.negotiate(from_branch,local_patches));
+        Ok(Pullable { local:local, remote: remote })
     }
 
-    pub fn pull(&mut self,target:&Path,pullable:&Pullable) -> Result<(), Error> {
-        for i in pullable.iter() {
-            try!(self.download_patch(&target,i));
+    /// Download and apply the pullable patches, using `jobs`
+    /// parallel downloads (see `download_patches`).
+    pub fn pull(&mut self,target:&Path,pullable:&Pullable,jobs:usize) -> Result<(), Error> {
+        if pullable.iter().next().is_none() {
+            // Don't take the pristine's write lock if there is nothing to apply.
+            return Ok(())
         }
+        let hashes:Vec<Vec<u8>>=pullable.iter().cloned().collect();
+        try!(hooks::run_with_hashes(target,Hook::PreApply,hashes.iter()));
         let repo_dir=pristine_dir(target);
         let mut repo = try!(Repository::new(&repo_dir).map_err(Error::Repository));
-        try!(repo.apply_patches(target,&pullable.remote,&pullable.local));
+        {
+            let arrivals=self.download_patches(target,hashes.clone(),jobs);
+            try!(repo.apply_patches_as_available(target,&pullable.remote,&pullable.local,arrivals));
+        }
         try!(repo.commit());
+        try!(hooks::run_with_hashes(target,Hook::PostApply,hashes.iter()));
         Ok(())
     }
 
-    pub fn pushable_patches(&mut self, source:&Path) -> Result<HashSet<Vec<u8>>,Error> {
+    /// Download the patches of `pullable` to a temporary directory,
+    /// and compute what pulling them would change, without changing
+    /// the repository rooted at `target`. The downloaded patches are
+    /// returned along with the simulation. The simulation needs a
+    /// write transaction of the pristine (which is aborted), and hence
+    /// waits for other commands writing to it, such as a record.
+    pub fn simulate_pull(&mut self,target:&Path,pullable:&Pullable,jobs:usize)
+                         -> Result<(Vec<(Vec<u8>,Patch)>,Simulation), Error> {
+        let tmp=tmp_file_path(&env::temp_dir());
+        try!(create_dir_all(&patches_dir(&tmp)));
+        let result=self.simulate_pull_in(target,&tmp,pullable,jobs);
+        let _=remove_dir_all(&tmp);
+        result
+    }
+
+    fn simulate_pull_in(&mut self,target:&Path,tmp:&Path,pullable:&Pullable,jobs:usize)
+                        -> Result<(Vec<(Vec<u8>,Patch)>,Simulation), Error> {
+        let hashes:Vec<Vec<u8>>=pullable.iter().cloned().collect();
+        let mut patches=Vec::new();
+        for h in self.download_patches(tmp,hashes,jobs) {
+            let h=try!(h);
+            let patch=try!(Patch::from_repository(tmp,&h));
+            patches.push((h,patch))
+        }
+        let repo_dir=pristine_dir(target);
+        // LMDB only nests transactions in write transactions. `repo`
+        // is dropped without being committed, and the patches are
+        // read from `tmp`.
+        let mut repo = try!(Repository::new(&repo_dir).map_err(Error::Repository));
+        let simulation=try!(repo.simulate_apply(tmp,&pullable.remote,&pullable.local));
+        Ok((patches,simulation))
+    }
+
+    /// The patches of `from_branch` in `source` that are not on
+    /// `to_branch` on the remote.
+    pub fn pushable_patches(&mut self, source:&Path, from_branch:&[u8], to_branch:&[u8]) -> Result<HashSet<Vec<u8>>,Error> {
         debug!("source: {:?}",source);
-        let mut from_changes:HashSet<Vec<u8>>={
-            let changes_file=branch_changes_file(source,DEFAULT_BRANCH.as_bytes());
+        let from_changes:HashSet<Vec<u8>>={
+            let changes_file=branch_changes_file(source,from_branch);
             debug!("changes_file: {:?}",changes_file);
             read_changes_from_file(&changes_file).unwrap_or(HashSet::new()) // empty repositories don't have this file
         };
+        let (to_changes,mut from_changes)=try!(self.negotiate(to_branch,from_changes));
         debug!("pushing: {:?}",from_changes);
-        let to_changes=try!(self.changes(DEFAULT_BRANCH.as_bytes()));
         for i in to_changes.iter() {
             from_changes.remove(i);
         }
         Ok(from_changes)
     }
 
-    pub fn push(&mut self, source:&Path,pushable:&HashSet<Vec<u8>>) -> Result<(), Error> {
+    pub fn push(&mut self, source:&Path, branch:&[u8], pushable:&HashSet<Vec<u8>>) -> Result<(), Error> {
         try!(self.upload_patches(source,pushable));
-        try!(self.remote_apply(pushable));
+        try!(self.remote_apply(branch,pushable));
         Ok(())
     }
 }
@@ -389,7 +842,7 @@ impl <'a>Remote<'a> {
             Remote::Local{ref path} => Ok(Session::Local{path:path.as_path()}),
             Remote::Uri{uri} => Ok(Session::Uri {
                 uri:uri,
-                client:hyper::Client::new()
+                client:try!(http::Config::new(uri)).client()
             }),
             Remote::Ssh{ref user,ref host,ref port,ref path,ref id}=>{
                 let mut session = ssh::Session::new().unwrap();
@@ -400,15 +853,102 @@ impl <'a>Remote<'a> {
                 debug!("ssh: trying to connect");
                 try!(session.connect());
                 debug!("ssh: connected");
-                match try!(session.is_server_known()) {
-                    ssh::ServerKnown::Known=> {
-                        if session.userauth_publickey_auto(None).is_err() {
-                            try!(session.userauth_kbdint(None))
-                        }
-                        Ok(Session::Ssh { session:session, path:path, id:id })
-                    },
-                    other=>{Err(Error::SSHUnknownServer(other))}
+                try!(check_host_key(&session,host));
+                if session.userauth_publickey_auto(None).is_err() {
+                    try!(session.userauth_kbdint(None))
                 }
+                Ok(Session::Ssh { session:session, path:path, id:id })
+            }
+        }
+    }
+}
+
+/// Whether unknown SSH hosts must be refused instead of asking the
+/// user, as in `StrictHostKeyChecking=yes`.
+fn strict_host_key_checking()->bool {
+    parse_strict_host_key_checking(env::var(STRICT_HOST_KEY_CHECKING_VAR).ok().as_ref().map(|x| &x[..]))
+}
+
+/// Parse the value of `STRICT_HOST_KEY_CHECKING_VAR`, if it is set.
+pub fn parse_strict_host_key_checking(v:Option<&str>)->bool {
+    match v {
+        Some(v)=>v!="" && v!="no" && v!="0",
+        None=>false
+    }
+}
+
+/// Status of the key of an SSH server in known_hosts.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum HostKey {
+    Known,
+    /// Another key is known for this server.
+    Changed,
+    Unknown
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum HostKeyAction {
+    Accept,
+    RefuseChanged,
+    RefuseUnknown,
+    /// Ask the user whether to trust the key.
+    Ask
+}
+
+/// What to do with a server key whose status is `key`, when unknown
+/// hosts must be refused (`strict`), and when the user can be asked
+/// (`interactive`). Changed keys are always refused.
+pub fn host_key_action(key:HostKey,strict:bool,interactive:bool)->HostKeyAction {
+    match key {
+        HostKey::Known=>HostKeyAction::Accept,
+        HostKey::Changed=>HostKeyAction::RefuseChanged,
+        HostKey::Unknown if strict || !interactive=>HostKeyAction::RefuseUnknown,
+        HostKey::Unknown=>HostKeyAction::Ask
+    }
+}
+
+fn fingerprint(session:&ssh::Session)->Result<String,Error> {
+    let hash=try!(session.get_pubkey_hash());
+    let hex:Vec<String>=hash.iter().map(|b| format!("{:02x}",b)).collect();
+    Ok(format!("MD5:{}",hex.join(":")))
+}
+
+/// Check the key of the server `session` is connected to against
+/// known_hosts. Unknown keys are added to known_hosts if the user
+/// trusts them, and changed keys are always refused.
+fn check_host_key(session:&ssh::Session,host:&str)->Result<(),Error> {
+    let known=try!(session.is_server_known());
+    debug!("ssh: server known: {:?}",known);
+    let key=match known {
+        ssh::ServerKnown::Known=>HostKey::Known,
+        ssh::ServerKnown::Changed | ssh::ServerKnown::FoundOther=>HostKey::Changed,
+        _=>HostKey::Unknown
+    };
+    match host_key_action(key,strict_host_key_checking(),ask::stdin_is_terminal()) {
+        HostKeyAction::Accept=>Ok(()),
+        HostKeyAction::RefuseChanged=>{
+            let fingerprint=try!(fingerprint(session));
+            let mut stderr=io::stderr();
+            try!(writeln!(stderr,"@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@"));
+            try!(writeln!(stderr,"@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @"));
+            try!(writeln!(stderr,"@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@"));
+            try!(writeln!(stderr,"The key of {} does not match the one in known_hosts.",host));
+            try!(writeln!(stderr,"Someone could be eavesdropping on you right now (man-in-the-middle attack)."));
+            try!(writeln!(stderr,"It is also possible that the host key has just been changed."));
+            try!(writeln!(stderr,"The fingerprint of the key sent by the remote host is {}.",fingerprint));
+            Err(Error::SSHHostKeyChanged(host.to_string(),fingerprint))
+        },
+        HostKeyAction::RefuseUnknown=>{
+            let fingerprint=try!(fingerprint(session));
+            Err(Error::SSHUntrustedHost(host.to_string(),fingerprint))
+        },
+        HostKeyAction::Ask=>{
+            let fingerprint=try!(fingerprint(session));
+            if try!(ask::ask_trust_host(host,&fingerprint)) {
+                try!(session.write_knownhost());
+                Ok(())
+            } else {
+                Err(Error::SSHUntrustedHost(host.to_string(),fingerprint))
             }
         }
     }
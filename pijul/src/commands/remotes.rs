@@ -0,0 +1,185 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Named remotes, stored in the `[remotes]` section of `meta.toml`.
+//! Their names can be used instead of URLs in `pull`, `push` and
+//! `clone`. `pijul remote branches` lists the branches of a remote,
+//! given by its name or URL.
+
+extern crate clap;
+use clap::{SubCommand, ArgMatches, Arg};
+
+extern crate libpijul;
+use commands::StaticSubcommand;
+use self::libpijul::fs_representation::find_repo_root;
+
+use commands::error::Error;
+use std::path::Path;
+use std::collections::BTreeMap;
+use super::get_wd;
+use super::remote::parse_remote;
+use super::super::meta::{Meta,RemoteConfig};
+
+fn port_arg() -> Arg<'static,'static,'static,'static,'static,'static> {
+    Arg::with_name("port")
+        .short("p")
+        .long("port")
+        .help("Port of the remote ssh server.")
+        .takes_value(true)
+        .validator(|val| { let x:Result<u16,_>=val.parse();
+                           match x { Ok(_)=>Ok(()),
+                                     Err(_)=>Err(val) }
+        })
+}
+
+pub fn invocation() -> StaticSubcommand {
+    return
+        SubCommand::with_name("remote")
+        .about("Manage the named remotes of a repository")
+        .arg(Arg::with_name("repository")
+             .long("repository")
+             .help("Local repository.")
+             .takes_value(true))
+        .subcommand(SubCommand::with_name("add")
+                    .about("Add a named remote")
+                    .arg(Arg::with_name("name").required(true).index(1))
+                    .arg(Arg::with_name("url").required(true).index(2))
+                    .arg(port_arg())
+                    .arg(Arg::with_name("default-branch")
+                         .long("default-branch")
+                         .help("Branch to pull from and push to by default.")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("remove")
+                    .about("Remove a named remote")
+                    .arg(Arg::with_name("name").required(true).index(1)))
+        .subcommand(SubCommand::with_name("list")
+                    .about("List the named remotes (the default)"))
+        .subcommand(SubCommand::with_name("show")
+                    .about("Show the configuration of a named remote")
+                    .arg(Arg::with_name("name").required(true).index(1)))
+        .subcommand(SubCommand::with_name("branches")
+                    .about("List the branches of a remote repository")
+                    .arg(Arg::with_name("remote")
+                         .help("Name or URL of the remote.")
+                         .required(true)
+                         .index(1))
+                    .arg(port_arg()))
+}
+
+#[derive(Debug)]
+pub enum Action<'a> {
+    Add { name:&'a str, url:&'a str, port:Option<u64>, default_branch:Option<&'a str> },
+    Remove(&'a str),
+    List,
+    Show(&'a str),
+    Branches { remote:&'a str, port:Option<u64> }
+}
+
+#[derive(Debug)]
+pub struct Params<'a> {
+    pub repository : Option<&'a Path>,
+    pub action : Action<'a>
+}
+
+pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
+    let action=match args.subcommand() {
+        ("add",Some(args))=>Action::Add {
+            name:args.value_of("name").unwrap(),
+            url:args.value_of("url").unwrap(),
+            port:args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) }),
+            default_branch:args.value_of("default-branch")
+        },
+        ("remove",Some(args))=>Action::Remove(args.value_of("name").unwrap()),
+        ("show",Some(args))=>Action::Show(args.value_of("name").unwrap()),
+        ("branches",Some(args))=>Action::Branches {
+            remote:args.value_of("remote").unwrap(),
+            port:args.value_of("port").and_then(|x| { Some(x.parse().unwrap()) })
+        },
+        _=>Action::List
+    };
+    Params { repository : args.value_of("repository").and_then(|x| { Some(Path::new(x)) }),
+             action : action }
+}
+
+pub fn run(args : &Params) -> Result<(), Error> {
+    let wd=try!(get_wd(args.repository));
+    if let Action::Branches { remote, port }=args.action {
+        // Remotes given by their URL don't need a local repository.
+        let meta=match find_repo_root(&wd) {
+            Some(ref r)=>Meta::load(r).unwrap_or(Meta::new()),
+            None=>Meta::new()
+        };
+        let (url,port)=meta.resolve_remote(remote,port);
+        let remote=parse_remote(url,port,None);
+        let mut session=try!(remote.session());
+        for b in try!(session.branches()) {
+            println!("{}",String::from_utf8_lossy(&b))
+        }
+        return Ok(())
+    }
+    match find_repo_root(&wd){
+        None => return Err(Error::NotInARepository),
+        Some(ref r) => {
+            let mut meta = match Meta::load(r) { Ok(m)=>m, Err(_)=> { Meta::new() } };
+            match args.action {
+                Action::Add { name, url, port, default_branch }=>{
+                    let mut remotes=meta.remotes.take().unwrap_or(BTreeMap::new());
+                    if remotes.contains_key(name) {
+                        return Err(Error::RemoteExists(name.to_string()))
+                    }
+                    remotes.insert(name.to_string(),
+                                   RemoteConfig { url:url.to_string(),
+                                                  port:port,
+                                                  default_branch:default_branch.map(|x| x.to_string()) });
+                    meta.remotes=Some(remotes);
+                    meta.save(r)
+                },
+                Action::Remove(name)=>{
+                    if meta.remotes.as_mut().and_then(|remotes| remotes.remove(name)).is_none() {
+                        return Err(Error::UnknownRemote(name.to_string()))
+                    }
+                    meta.save(r)
+                },
+                Action::List=>{
+                    if let Some(ref remotes)=meta.remotes {
+                        for (name,remote) in remotes.iter() {
+                            println!("{}\t{}",name,remote.url)
+                        }
+                    }
+                    Ok(())
+                },
+                Action::Show(name)=>{
+                    match meta.remote(name) {
+                        Some(remote)=>{
+                            println!("url: {}",remote.url);
+                            if let Some(port)=remote.port {
+                                println!("port: {}",port)
+                            }
+                            if let Some(ref branch)=remote.default_branch {
+                                println!("default branch: {}",branch)
+                            }
+                            Ok(())
+                        },
+                        None=>Err(Error::UnknownRemote(name.to_string()))
+                    }
+                },
+                Action::Branches { .. }=>unreachable!()
+            }
+        }
+    }
+}
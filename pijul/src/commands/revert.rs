@@ -27,7 +27,8 @@ use self::libpijul::fs_representation::{repo_dir, pristine_dir, find_repo_root};
 use std::path::{Path};
 
 use commands::error;
-use super::get_wd;
+use super::{get_wd, check_not_bare};
+use super::lock::RepositoryLock;
 pub fn invocation() -> StaticSubcommand {
     return
         SubCommand::with_name("revert")
@@ -52,6 +53,8 @@ pub fn run<'a>(args : &Params<'a>) -> Result<(), error::Error> {
         None => return Err(error::Error::NotInARepository),
         Some(ref r) =>
         {
+            try!(check_not_bare(r));
+            let _lock=try!(RepositoryLock::acquire(r));
             let repo_dir=pristine_dir(r);
             let mut repo = try!(Repository::new(&repo_dir));
             try!(repo.output_repository(&r,&Patch::empty()));
@@ -0,0 +1,285 @@
+/*
+  Copyright Florent Becker and Pierre-Etienne Meunier 2015.
+
+  This file is part of Pijul.
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU Affero General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+  GNU Affero General Public License for more details.
+
+  You should have received a copy of the GNU Affero General Public License
+  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! HTTP server for repositories, in the layout expected by HTTP
+//! remotes:
+//!
+//! - `GET /.pijul/changes.<branch in hex>` and `GET /.pijul/patches/<patch file>`
+//!   are the files of the repository.
+//! - `GET /.pijul/branches` lists the branches, one per line.
+//! - `GET /.pijul/summary.<branch in hex>` is the `protocol::Summary`
+//!   of a branch, and `GET /.pijul/hashes.<branch in hex>.<buckets in hex>`
+//!   the hashes of its patches in the given buckets, both in CBOR.
+//! - If pushing is allowed, `POST /.pijul/patches/<patch file>`
+//!   uploads a patch, and `POST /.pijul/apply.<branch in hex>` applies
+//!   the uploaded patches whose hashes are listed in the body (one per
+//!   line, in hexadecimal) to a branch, which must be the current one
+//!   (`POST /.pijul/apply` applies them to the current branch). These
+//!   requests must carry an `Authorization: Bearer <token>` header.
+//!   Rejected patches (see module `policy`) are answered with status
+//!   400, and the reasons in the body.
+
+extern crate clap;
+use clap::{SubCommand, ArgMatches, Arg};
+
+extern crate libpijul;
+use commands::StaticSubcommand;
+use self::libpijul::patch::read_changes_from_file;
+use self::libpijul::fs_representation::{find_repo_root, repo_dir, patches_dir, patch_path, branch_changes_file,
+                                        list_branches, patch_hash_of_file_name,
+                                        PIJUL_DIR_NAME, PATCHES_DIR_NAME};
+
+use commands::error::Error;
+use std::path::{Path,PathBuf};
+use std::fs::{File};
+use std::io::{Read};
+use std::collections::HashSet;
+use super::get_wd;
+use super::apply;
+use super::protocol::{summarize,in_buckets,to_cbor};
+use super::remote::store_patch;
+
+extern crate rustc_serialize;
+use self::rustc_serialize::hex::{FromHex,ToHex};
+
+extern crate crypto;
+use self::crypto::util::fixed_time_eq;
+
+extern crate hyper;
+use self::hyper::server::{Handler,Request,Response,Listening};
+use self::hyper::status::StatusCode;
+use self::hyper::method::Method;
+use self::hyper::uri::RequestUri;
+
+pub fn invocation() -> StaticSubcommand {
+    return
+        SubCommand::with_name("serve")
+        .about("Serve a repository over HTTP")
+        .arg(Arg::with_name("repository")
+             .long("repository")
+             .help("The repository to serve, defaults to the current directory.")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("address")
+             .long("address")
+             .help("Address to listen on, defaults to 127.0.0.1:8080.")
+             .takes_value(true))
+        .arg(Arg::with_name("allow-push")
+             .long("allow-push")
+             .help("Accept patches pushed by clients presenting the token.")
+             .requires("token"))
+        .arg(Arg::with_name("token")
+             .long("token")
+             .help("Token clients must present to push (they read it from PIJUL_HTTP_TOKEN, sent to the URL in PIJUL_HTTP_TOKEN_URL).")
+             .takes_value(true))
+}
+
+pub struct Params<'a> {
+    pub repository : Option<&'a Path>,
+    pub address : &'a str,
+    pub allow_push : bool,
+    pub token : Option<&'a str>
+}
+
+pub fn parse_args<'a>(args: &'a ArgMatches) -> Params<'a> {
+    Params { repository : args.value_of("repository").and_then(|x| { Some(Path::new(x)) }),
+             address : args.value_of("address").unwrap_or("127.0.0.1:8080"),
+             allow_push : args.is_present("allow-push"),
+             token : args.value_of("token") }
+}
+
+/// Start serving the repository in background threads.
+pub fn start(args : &Params) -> Result<Listening, Error> {
+    let wd=try!(get_wd(args.repository));
+    match find_repo_root(&wd){
+        None => return Err(Error::NotInARepository),
+        Some(r) => {
+            let server=try!(hyper::Server::http(args.address));
+            let handler=Server {
+                root:r,
+                push_authorization:if args.allow_push {
+                    args.token.map(|x| format!("Bearer {}",x).into_bytes())
+                } else {
+                    None
+                }
+            };
+            Ok(try!(server.handle(handler)))
+        }
+    }
+}
+
+pub fn run(args : &Params) -> Result<(), Error> {
+    let listening=try!(start(args));
+    println!("Serving on http://{}",listening.socket);
+    // Dropping `listening` waits for the server threads, i.e. forever.
+    Ok(())
+}
+
+struct Server {
+    root:PathBuf,
+    /// The `Authorization` header of clients allowed to push.
+    push_authorization:Option<Vec<u8>>
+}
+
+type Reply=Result<Vec<u8>,(StatusCode,String)>;
+
+fn read_file(path:&Path)->Reply {
+    let mut body=Vec::new();
+    match File::open(path).and_then(|mut f| f.read_to_end(&mut body)) {
+        Ok(_)=>Ok(body),
+        Err(_)=>Err((StatusCode::NotFound,"Not found".to_string()))
+    }
+}
+
+fn from_hex(s:&str)->Result<Vec<u8>,(StatusCode,String)> {
+    s.from_hex().map_err(|_| (StatusCode::BadRequest,format!("Invalid hexadecimal {:?}",s)))
+}
+
+fn internal_error<E:ToString>(e:E)->(StatusCode,String) {
+    (StatusCode::InternalServerError,e.to_string())
+}
+
+impl Server {
+    fn get(&self,path:&str)->Reply {
+        let pijul=format!("/{}/",PIJUL_DIR_NAME);
+        if path==pijul.clone()+"branches" {
+            let mut body=Vec::new();
+            for b in try!(list_branches(&self.root).map_err(internal_error)) {
+                body.extend(String::from_utf8_lossy(&b).as_bytes());
+                body.push(b'\n')
+            }
+            Ok(body)
+        } else if path.starts_with(&(pijul.clone()+"changes.")) {
+            let name=&path[pijul.len()..];
+            if (&name["changes.".len()..]).from_hex().is_ok() {
+                read_file(&repo_dir(&self.root).join(name))
+            } else {
+                Err((StatusCode::NotFound,"Not found".to_string()))
+            }
+        } else if path.starts_with(&(pijul.clone()+"summary.")) {
+            let branch=try!(from_hex(&path[pijul.len()+"summary.".len()..]));
+            let changes=read_changes_from_file(&branch_changes_file(&self.root,&branch)).unwrap_or(HashSet::new());
+            to_cbor(&summarize(&changes)).map_err(internal_error)
+        } else if path.starts_with(&(pijul.clone()+"hashes.")) {
+            let mut args=(&path[pijul.len()+"hashes.".len()..]).split('.');
+            let branch=try!(from_hex(args.next().unwrap_or("")));
+            let buckets=try!(from_hex(args.next().unwrap_or("")));
+            let changes=read_changes_from_file(&branch_changes_file(&self.root,&branch)).unwrap_or(HashSet::new());
+            to_cbor(&in_buckets(&changes,&buckets)).map_err(internal_error)
+        } else if let Some(name)=self.patch_file_name(path) {
+            read_file(&patches_dir(&self.root).join(name))
+        } else {
+            Err((StatusCode::NotFound,"Not found".to_string()))
+        }
+    }
+
+    fn post(&self,path:&str,body:&[u8])->Reply {
+        let apply=format!("/{}/apply",PIJUL_DIR_NAME);
+        if path==apply || path.starts_with(&(apply.clone()+".")) {
+            let branch=if path==apply {
+                try!(apply::current_branch(&self.root).map_err(internal_error))
+            } else {
+                try!(from_hex(&path[apply.len()+1..]))
+            };
+            let mut patches=HashSet::new();
+            for h in String::from_utf8_lossy(body).lines() {
+                match h.from_hex() {
+                    Ok(h)=>{
+                        if patch_path(&self.root,&h).is_none() {
+                            return Err((StatusCode::BadRequest,format!("Patch {} was not uploaded",h.to_hex())))
+                        }
+                        patches.insert(h);
+                    },
+                    Err(_)=>return Err((StatusCode::BadRequest,format!("Invalid patch hash {:?}",h)))
+                }
+            }
+            try!(apply::apply_patches(&self.root,&branch,&patches).map_err(|e| match e {
+                Error::NotCurrentBranch(_,_) | Error::PatchesRejected(_)=>(StatusCode::BadRequest,e.to_string()),
+                e=>internal_error(e)
+            }));
+            Ok(Vec::new())
+        } else if let Some(name)=self.patch_file_name(path) {
+            try!(store_patch(&self.root,name,body).map_err(|e| match e {
+                Error::PatchHashMismatch(_,_)=>(StatusCode::BadRequest,e.to_string()),
+                e=>internal_error(e)
+            }));
+            Ok(Vec::new())
+        } else {
+            Err((StatusCode::NotFound,"Not found".to_string()))
+        }
+    }
+
+    /// If `path` is the path of a patch file, returns its file name.
+    fn patch_file_name<'a>(&self,path:&'a str)->Option<&'a str> {
+        let prefix=format!("/{}/{}/",PIJUL_DIR_NAME,PATCHES_DIR_NAME);
+        if path.starts_with(&prefix) {
+            let name=&path[prefix.len()..];
+            if patch_hash_of_file_name(name).is_some() {
+                return Some(name)
+            }
+        }
+        None
+    }
+
+    /// Whether `req` carries the push token. The comparison takes the
+    /// same time wherever the first difference is, so that the token
+    /// cannot be guessed byte by byte.
+    fn authorized(&self,req:&Request)->bool {
+        match (self.push_authorization.as_ref(),req.headers.get_raw("Authorization")) {
+            (Some(expected),Some(values)) =>
+                values.iter().any(|v| fixed_time_eq(&v[..],&expected[..])),
+            _=>false
+        }
+    }
+}
+
+impl Handler for Server {
+    fn handle<'a,'k>(&'a self, mut req:Request<'a,'k>, mut res:Response<'a>) {
+        let path=match req.uri {
+            RequestUri::AbsolutePath(ref p)=>p.split('?').next().unwrap_or("").to_string(),
+            _=>String::new()
+        };
+        debug!("serve: {:?} {:?}",req.method,path);
+        let reply=match req.method {
+            Method::Get=>self.get(&path),
+            Method::Post if self.push_authorization.is_none() =>
+                Err((StatusCode::Forbidden,"This server does not accept pushes".to_string())),
+            Method::Post if !self.authorized(&req) =>
+                Err((StatusCode::Unauthorized,"Invalid or missing token".to_string())),
+            Method::Post=>{
+                let mut body=Vec::new();
+                match req.read_to_end(&mut body) {
+                    Ok(_)=>self.post(&path,&body),
+                    Err(e)=>Err(internal_error(e))
+                }
+            },
+            _=>Err((StatusCode::MethodNotAllowed,"Method not allowed".to_string()))
+        };
+        let sent=match reply {
+            Ok(body)=>res.send(&body),
+            Err((status,msg))=>{
+                debug!("serve: {:?} {:?}: {}",status,path,msg);
+                *res.status_mut()=status;
+                res.send(msg.as_bytes())
+            }
+        };
+        if let Err(e)=sent {
+            debug!("serve: error sending response: {:?}",e)
+        }
+    }
+}
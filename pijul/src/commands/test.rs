@@ -1,14 +1,81 @@
 extern crate tempdir;
 
-use commands::{init, info, record, add, remove, pull, remote};
+use commands::{init, info, record, amend, add, remove, pull, push, clone, remote, gc, lock, serve, protocol, remotes, http, ask};
+use meta::{Meta, Policy, Repository as RemoteRepository};
 use commands::error;
 use std::fs;
+use std::iter;
+use std::path::Path;
+use std::collections::HashSet;
+extern crate libpijul;
+use self::libpijul::fs_representation::{patches_dir, journal_file, lock_file, meta_file, pristine_dir, hooks_dir};
+use self::libpijul::{journal, schema, Repository};
+extern crate rustc_serialize;
+use self::rustc_serialize::hex::ToHex;
+
+/// Parameters recording all the changes of the repository at `dir`,
+/// without asking anything, in a patch called `name`.
+fn record_params<'a>(dir: &'a Path, name: &'a str) -> record::Params<'a> {
+    record::Params { repository : Some(dir),
+                     yes_to_all : true,
+                     edit : false,
+                     editor : None,
+                     description : None,
+                     message_file : None,
+                     prefixes : vec![],
+                     authors : Some(vec![]),
+                     patch_name : Some(name)
+    }
+}
+
+/// Create a repository at `dir`, and the directory itself if needed.
+fn init_repo(dir: &Path, bare: bool) {
+    fs::create_dir_all(dir).unwrap();
+    init::run(&init::Params { location : dir, allow_nested : false, bare : bare }).unwrap();
+}
+
+/// Create the file `name` of the repository at `dir` with `contents`,
+/// add it, and record it in a patch called `name`.
+fn record_file(dir: &Path, name: &str, contents: &[u8]) {
+    use std::io::Write;
+    let fpath = dir.join(name);
+    fs::File::create(&fpath).unwrap().write_all(contents).unwrap();
+    add::run(&add::Params { repository : Some(dir), touched_files : vec![&fpath] }).unwrap();
+    record::run(&record_params(dir, name)).unwrap();
+}
+
+/// Parameters pulling all the patches of `remote` into the repository
+/// at `dir`, without asking anything.
+fn pull_params<'a>(dir: &'a Path, remote: &'a str) -> pull::Params<'a> {
+    pull::Params { repository : Some(dir),
+                   remote_id : Some(remote),
+                   set_default : false,
+                   dry_run : false,
+                   from_branch : None,
+                   to_branch : None,
+                   port : None,
+                   yes_to_all : true,
+                   jobs : 2 }
+}
+
+/// Parameters pushing all the patches of the repository at `dir` to
+/// `remote`, without asking anything.
+fn push_params<'a>(dir: &'a Path, remote: &'a str) -> push::Params<'a> {
+    push::Params { repository : Some(dir),
+                   remote_id : Some(remote),
+                   yes_to_all : true,
+                   set_default : false,
+                   dry_run : false,
+                   from_branch : None,
+                   to_branch : None,
+                   port : None }
+}
 
 #[test]
 fn init_creates_repo() -> ()
 {
     let dir = tempdir::TempDir::new("pijul").unwrap();
-    let init_params = init::Params { location : &dir.path(), allow_nested : false};
+    let init_params = init::Params { location : &dir.path(), allow_nested : false, bare : false};
     init::run(&init_params).unwrap();
     let info_params = info::Params { repository : Some(&dir.path()) };
     info::run(&info_params).unwrap();
@@ -17,11 +84,11 @@ fn init_creates_repo() -> ()
 #[test]
 fn init_nested_forbidden() {
     let dir = tempdir::TempDir::new("pijul").unwrap();
-    let init_params = init::Params { location : &dir.path(), allow_nested : false};
+    let init_params = init::Params { location : &dir.path(), allow_nested : false, bare : false};
     init::run(&init_params).unwrap();
     let subdir = dir.path().join("subdir");
     fs::create_dir(&subdir);
-    let sub_init_params = init::Params { location : &subdir, allow_nested : false};
+    let sub_init_params = init::Params { location : &subdir, allow_nested : false, bare : false};
     match init::run(&sub_init_params) {
         Ok(_) => panic!("Creating a forbidden nested repository"),
 
@@ -34,23 +101,20 @@ fn init_nested_forbidden() {
 #[test]
 fn init_nested_allowed() {
     let dir = tempdir::TempDir::new("pijul").unwrap();
-    let init_params = init::Params { location : &dir.path(), allow_nested : false};
+    let init_params = init::Params { location : &dir.path(), allow_nested : false, bare : false};
     init::run(&init_params).unwrap();
     let subdir = dir.path().join("subdir");
     fs::create_dir(&subdir);
-    let sub_init_params = init::Params { location : &subdir, allow_nested : true};
+    let sub_init_params = init::Params { location : &subdir, allow_nested : true, bare : false};
     init::run(&sub_init_params).unwrap()
 }
 
 #[test]
 fn in_empty_dir_nothing_to_record() {
     let dir = tempdir::TempDir::new("pijul").unwrap();
-    let init_params = init::Params { location : &dir.path(), allow_nested : false};
+    let init_params = init::Params { location : &dir.path(), allow_nested : false, bare : false};
     init::run(&init_params).unwrap();
-    let record_params = record::Params { repository : Some(&dir.path()),
-                                         yes_to_all : true,
-                                         patch_name : Some(""),
-                                         authors : Some(vec![]) };
+    let record_params = record_params(dir.path(), "");
     match record::run(&record_params).unwrap() {
         None => (),
         Some(()) => panic!("found something to record in an empty repository")
@@ -60,7 +124,7 @@ fn in_empty_dir_nothing_to_record() {
 #[test]
 fn with_changes_sth_to_record() {
     let dir = tempdir::TempDir::new("pijul").unwrap();
-    let init_params = init::Params { location : &dir.path(), allow_nested : false};
+    let init_params = init::Params { location : &dir.path(), allow_nested : false, bare : false};
     init::run(&init_params).unwrap();
     let fpath = &dir.path().join("toto");
     let file = fs::File::create(&fpath).unwrap();
@@ -69,11 +133,7 @@ fn with_changes_sth_to_record() {
         Some (()) => (),
         None => panic!("no file added")        
     };
-    let record_params = record::Params { repository : Some(&dir.path()),
-                                         yes_to_all : true,
-                                         patch_name : Some(""),
-                                         authors : Some(vec![])
-    };
+    let record_params = record_params(dir.path(), "");
     match record::run(&record_params).unwrap() {
         None => panic!("file add is not going to be recorded"),
         Some(()) => ()
@@ -84,7 +144,7 @@ fn with_changes_sth_to_record() {
 #[test]
 fn add_remove_nothing_to_record() {
     let dir = tempdir::TempDir::new("pijul").unwrap();
-    let init_params = init::Params { location : &dir.path(), allow_nested : false};
+    let init_params = init::Params { location : &dir.path(), allow_nested : false, bare : false};
     init::run(&init_params).unwrap();
     let fpath = &dir.path().join("toto");
     let file = fs::File::create(&fpath).unwrap();
@@ -98,11 +158,7 @@ fn add_remove_nothing_to_record() {
         None => panic!("no file removed")
     };
     
-    let record_params = record::Params { repository : Some(&dir.path()),
-                                         yes_to_all : true,
-                                         authors : Some(vec![]),
-                                         patch_name : Some("")
-    };
+    let record_params = record_params(dir.path(), "");
     match record::run(&record_params).unwrap() {
         None => (),
         Some(()) => panic!("add remove left a trace")
@@ -112,7 +168,7 @@ fn add_remove_nothing_to_record() {
 #[test]
 fn no_remove_without_add() {
     let dir = tempdir::TempDir::new("pijul").unwrap();
-    let init_params = init::Params { location : &dir.path(), allow_nested : false};
+    let init_params = init::Params { location : &dir.path(), allow_nested : false, bare : false};
     init::run(&init_params).unwrap();
     let fpath = &dir.path().join("toto");
     let file = fs::File::create(&fpath).unwrap();
@@ -131,8 +187,8 @@ fn add_record_pull() {
     let dir_b = &dir.path().join("b");
     fs::create_dir(dir_a);
     fs::create_dir(dir_b);
-    let init_params_a = init::Params { location : &dir_a, allow_nested : false};
-    let init_params_b = init::Params { location : &dir_b, allow_nested : false};
+    let init_params_a = init::Params { location : &dir_a, allow_nested : false, bare : false};
+    let init_params_b = init::Params { location : &dir_b, allow_nested : false, bare : false};
     init::run(&init_params_a).unwrap();
     init::run(&init_params_b).unwrap();
     let fpath = &dir_a.join("toto");
@@ -143,11 +199,7 @@ fn add_record_pull() {
         Some (()) => (),
         None => panic!("no file added")
     };
-    let record_params = record::Params { repository : Some(&dir_a),
-                                         yes_to_all : true,
-                                         authors : Some(vec![]),
-                                         patch_name : Some("nothing")
-    };
+    let record_params = record_params(&dir_a, "nothing");
     match record::run(&record_params).unwrap() {
         None => panic!("file add is not going to be recorded"),
         Some(()) => ()
@@ -155,10 +207,1105 @@ fn add_record_pull() {
     let pull_params = pull::Params { repository : Some(&dir_b),
                                      remote_id : Some(dir_a.to_str().unwrap()),
                                      set_default : false,
+                                     dry_run : false,
+                                     from_branch : None,
+                                     to_branch : None,
                                      port : None,
-                                     yes_to_all : true };
+                                     yes_to_all : true,
+                                     jobs : 2 };
     pull::run(&pull_params).unwrap();
     let fpath_b = &dir_b.join("toto");
     let metadata = fs::metadata(fpath_b).unwrap();
     assert!(metadata.is_file());
 }
+
+#[test]
+fn gc_removes_unreferenced_patches() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    record_file(dir.path(), "toto", b"");
+    let patches = patches_dir(&dir.path());
+    let tmp = patches.join("abcdefghijklmnopqrst");
+    fs::File::create(&tmp).unwrap();
+    let orphan_name:String = iter::repeat("ab").take(64).collect();
+    let orphan = patches.join(orphan_name + ".cbor");
+    fs::File::create(&orphan).unwrap();
+
+    let dry_params = gc::Params { repository : Some(&dir.path()), dry_run : true, grace : None };
+    gc::run(&dry_params).unwrap();
+    assert!(fs::metadata(&tmp).is_ok());
+    assert!(fs::metadata(&orphan).is_ok());
+
+    // By default, files that were just written are kept.
+    let gc_params = gc::Params { repository : Some(&dir.path()), dry_run : false, grace : None };
+    gc::run(&gc_params).unwrap();
+    assert!(fs::metadata(&tmp).is_ok());
+    assert!(fs::metadata(&orphan).is_ok());
+
+    let gc_params = gc::Params { repository : Some(&dir.path()), dry_run : false, grace : Some(0) };
+    gc::run(&gc_params).unwrap();
+    assert!(fs::metadata(&tmp).is_err());
+    assert!(fs::metadata(&orphan).is_err());
+    // The recorded patch is still there.
+    assert_eq!(fs::read_dir(&patches).unwrap().count(), 1);
+}
+
+#[test]
+fn concurrent_lock_refused() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    let first = lock::RepositoryLock::acquire_with_timeout(&dir.path(), 0).unwrap();
+    match lock::RepositoryLock::acquire_with_timeout(&dir.path(), 0) {
+        Ok(_) => panic!("two processes hold the lock at the same time"),
+        Err(error::Error::RepositoryLocked(_,_)) => (),
+        Err(_) => panic!("funky error when taking a held lock")
+    }
+    drop(first);
+    lock::RepositoryLock::acquire_with_timeout(&dir.path(), 0).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn stale_lock_is_broken() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    // The pid of a process that has exited.
+    let mut child = ::std::process::Command::new("true").spawn().unwrap();
+    child.wait().unwrap();
+    {
+        use std::io::Write;
+        let mut f = fs::File::create(&lock_file(&dir.path())).unwrap();
+        write!(f, "{}\npijul record\n", child.id()).unwrap();
+    }
+    let lock = lock::RepositoryLock::acquire_with_timeout(&dir.path(), 0).unwrap();
+    // The lock now belongs to this process, which is running.
+    match lock::RepositoryLock::acquire_with_timeout(&dir.path(), 0) {
+        Err(error::Error::RepositoryLocked(_,_)) => (),
+        _ => panic!("the lock of a running process was broken")
+    }
+    drop(lock);
+}
+
+#[test]
+fn interrupted_record_is_cancelled() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    // A patch saved by a record that crashed before committing.
+    let hash:Vec<u8> = iter::repeat(0xab).take(64).collect();
+    let orphan_name:String = iter::repeat("ab").take(64).collect();
+    let orphan = patches_dir(&dir.path()).join(orphan_name + ".cbor");
+    fs::File::create(&orphan).unwrap();
+    let j = journal::Journal { operation : journal::Operation::Record,
+                               branch : b"main".to_vec(),
+                               patches : vec![hash],
+                               only_local : vec![],
+                               pending : None };
+    journal::write(&dir.path(), &j).unwrap();
+    lock::RepositoryLock::acquire_with_timeout(&dir.path(), 0).unwrap();
+    assert!(fs::metadata(&orphan).is_err());
+    assert!(fs::metadata(&journal_file(&dir.path())).is_err());
+}
+
+#[test]
+fn interrupted_apply_is_finished() {
+    use self::libpijul::patch::Patch;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let hashes:Vec<Vec<u8>> = Repository::new_readonly(&pristine_dir(&dir_a)).unwrap()
+        .applied_patches().into_iter().collect();
+    // An apply that crashed after downloading the patch and writing
+    // its journal, before committing.
+    for f in fs::read_dir(&patches_dir(&dir_a)).unwrap() {
+        let f = f.unwrap();
+        fs::copy(&f.path(), &patches_dir(&dir_b).join(f.file_name())).unwrap();
+    }
+    let j = journal::Journal { operation : journal::Operation::Apply,
+                               branch : b"main".to_vec(),
+                               patches : hashes.clone(),
+                               only_local : vec![],
+                               pending : Some(Patch::empty()) };
+    journal::write(&dir_b, &j).unwrap();
+    lock::RepositoryLock::acquire_with_timeout(&dir_b, 0).unwrap();
+    assert!(fs::metadata(&journal_file(&dir_b)).is_err());
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+    let repo = Repository::new_readonly(&pristine_dir(&dir_b)).unwrap();
+    assert_eq!(repo.applied_patches().into_iter().collect::<Vec<_>>(), hashes);
+}
+
+#[test]
+fn new_pristine_has_current_schema() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    let repo = Repository::new_readonly(&pristine_dir(&dir.path())).unwrap();
+    assert_eq!(repo.schema_version().unwrap(), schema::SCHEMA_VERSION);
+}
+
+#[test]
+fn unversioned_pristine_is_migrated() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    {
+        let mut repo = Repository::new(&pristine_dir(&dir.path())).unwrap();
+        repo.set_schema_version(0).unwrap();
+        repo.commit().unwrap();
+    }
+    // Read-only opens leave the migration to write transactions.
+    match Repository::new_readonly(&pristine_dir(&dir.path())) {
+        Err(libpijul::error::Error::SchemaNeedsMigration(0)) => (),
+        _ => panic!("opened an unversioned pristine read-only")
+    }
+    Repository::new(&pristine_dir(&dir.path())).unwrap().commit().unwrap();
+    let repo = Repository::new_readonly(&pristine_dir(&dir.path())).unwrap();
+    assert_eq!(repo.schema_version().unwrap(), schema::SCHEMA_VERSION);
+}
+
+#[test]
+fn newer_pristine_is_refused() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    {
+        let mut repo = Repository::new(&pristine_dir(&dir.path())).unwrap();
+        repo.set_schema_version(schema::SCHEMA_VERSION + 1).unwrap();
+        repo.commit().unwrap();
+    }
+    match Repository::new_readonly(&pristine_dir(&dir.path())) {
+        Err(libpijul::error::Error::SchemaTooNew(v)) => assert_eq!(v, schema::SCHEMA_VERSION + 1),
+        _ => panic!("opened a pristine written by a newer version")
+    }
+    match Repository::new(&pristine_dir(&dir.path())) {
+        Err(libpijul::error::Error::SchemaTooNew(_)) => (),
+        _ => panic!("opened a pristine written by a newer version")
+    }
+}
+
+#[test]
+fn pull_from_http_server() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let serve_params = serve::Params { repository : Some(&dir_a),
+                                       address : "127.0.0.1:0",
+                                       allow_push : false,
+                                       token : None };
+    let mut listening = serve::start(&serve_params).unwrap();
+    let uri = format!("http://{}", listening.socket);
+    let pull_params = pull_params(dir_b, &uri[..]);
+    pull::run(&pull_params).unwrap();
+    listening.close().unwrap();
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+}
+
+#[test]
+fn protocol_over_a_pipe() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    record_file(dir.path(), "toto", b"");
+    let requests = vec![protocol::Request::ListBranches,
+                        protocol::Request::Changes(b"main".to_vec()),
+                        protocol::Request::GetPatch(vec![0;64])];
+    let input = protocol::encode_requests(&requests).unwrap();
+    let mut output = Vec::new();
+    protocol::serve(&dir.path(), &input[..], &mut output).unwrap();
+    let responses = protocol::decode_responses(&output).unwrap();
+    assert_eq!(responses.len(), 3);
+    match responses[0] {
+        protocol::Response::Branches(ref b) => assert_eq!(b, &vec![b"main".to_vec()]),
+        _ => panic!("unexpected response to ListBranches")
+    }
+    let hash = match responses[1] {
+        protocol::Response::Changes(ref c) if c.len() == 1 => c[0].clone(),
+        _ => panic!("unexpected response to Changes")
+    };
+    match responses[2] {
+        protocol::Response::Patch(None) => (),
+        _ => panic!("found a patch that does not exist")
+    }
+    let input = protocol::encode_requests(&[protocol::Request::GetPatch(hash)]).unwrap();
+    let mut output = Vec::new();
+    protocol::serve(&dir.path(), &input[..], &mut output).unwrap();
+    match protocol::decode_responses(&output).unwrap().pop() {
+        Some(protocol::Response::Patch(Some(_))) => (),
+        _ => panic!("recorded patch not found")
+    }
+}
+
+#[test]
+fn protocol_errors_are_per_request() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    let requests = vec![protocol::Request::PutPatch("invalid".to_string(), vec![]),
+                        protocol::Request::ListBranches];
+    let input = protocol::encode_requests(&requests).unwrap();
+    let mut output = Vec::new();
+    protocol::serve(&dir.path(), &input[..], &mut output).unwrap();
+    // Responses are read one at a time, and the error only concerns
+    // its own request.
+    let mut output = &output[..];
+    match protocol::read_response(&mut output).unwrap() {
+        Some(protocol::Response::Error(_)) => (),
+        r => panic!("unexpected response {:?}", r)
+    }
+    match protocol::read_response(&mut output).unwrap() {
+        Some(protocol::Response::Branches(_)) => (),
+        r => panic!("unexpected response {:?}", r)
+    }
+    assert!(protocol::read_response(&mut output).unwrap().is_none());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn hook_output_does_not_corrupt_protocol() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    // Hooks fail if their output would go to the standard output of
+    // `protocol::serve`'s process.
+    write_hook(&dir_b, "pre-apply", "#!/bin/sh\n[ /proc/$$/fd/1 -ef /proc/$PPID/fd/1 ] && exit 1\necho applying $@\n");
+    write_hook(&dir_b, "post-apply", "#!/bin/sh\necho applied $@\n");
+    let file = fs::read_dir(&patches_dir(&dir_a)).unwrap().next().unwrap().unwrap();
+    let mut contents = Vec::new();
+    {
+        use std::io::Read;
+        fs::File::open(&file.path()).unwrap().read_to_end(&mut contents).unwrap();
+    }
+    let hashes:Vec<Vec<u8>> = Repository::new_readonly(&pristine_dir(&dir_a)).unwrap()
+        .applied_patches().into_iter().collect();
+    let requests = vec![protocol::Request::PutPatch(file.file_name().into_string().unwrap(), contents),
+                        protocol::Request::Apply(b"main".to_vec(), hashes)];
+    let input = protocol::encode_requests(&requests).unwrap();
+    let mut output = Vec::new();
+    protocol::serve(&dir_b, &input[..], &mut output).unwrap();
+    let responses = protocol::decode_responses(&output).unwrap();
+    assert_eq!(responses.len(), 2);
+    for r in responses.iter() {
+        match *r {
+            protocol::Response::Ok => {},
+            _ => panic!("unexpected response")
+        }
+    }
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+}
+
+#[test]
+fn summaries_differ_on_missing_patches() {
+    let mut a = HashSet::new();
+    for i in 0..1000u32 {
+        let h:Vec<u8> = (0..64).map(|j| ((i * 7 + j) % 256) as u8).collect();
+        a.insert(h);
+    }
+    let mut b = a.clone();
+    assert!(protocol::differing_buckets(&protocol::summarize(&a), &protocol::summarize(&b)).is_empty());
+    let extra:Vec<u8> = iter::repeat(42).take(64).collect();
+    b.insert(extra.clone());
+    let buckets = protocol::differing_buckets(&protocol::summarize(&a), &protocol::summarize(&b));
+    assert_eq!(buckets, vec![42]);
+    let only_b = protocol::in_buckets(&b, &buckets);
+    assert!(only_b.contains(&extra));
+    assert!(only_b.len() < b.len());
+}
+
+#[test]
+fn parallel_pull_applies_dependencies_first() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    let fpath = &dir_a.join("toto");
+    fs::File::create(&fpath).unwrap();
+    add::run(&add::Params { repository : Some(&dir_a), touched_files : vec![&fpath] }).unwrap();
+    for (i, name) in ["first", "second", "third"].iter().enumerate() {
+        {
+            use std::io::Write;
+            let mut f = fs::OpenOptions::new().append(true).open(&fpath).unwrap();
+            writeln!(f, "line {}", i).unwrap();
+        }
+        let record_params = record_params(&dir_a, name);
+        record::run(&record_params).unwrap();
+    }
+    let mut pull_params = pull_params(dir_b, dir_a.to_str().unwrap());
+    pull_params.jobs = 4;
+    pull::run(&pull_params).unwrap();
+    let mut contents = String::new();
+    {
+        use std::io::Read;
+        fs::File::open(&dir_b.join("toto")).unwrap().read_to_string(&mut contents).unwrap();
+    }
+    assert_eq!(contents, "line 0\nline 1\nline 2\n");
+}
+
+#[test]
+fn tampered_patch_is_rejected() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    for entry in fs::read_dir(patches_dir(&dir_a)).unwrap() {
+        use std::io::Write;
+        let mut f = fs::OpenOptions::new().append(true).open(entry.unwrap().path()).unwrap();
+        f.write_all(b"garbage").unwrap();
+    }
+    let mut pull_params = pull_params(dir_b, dir_a.to_str().unwrap());
+    pull_params.jobs = 1;
+    match pull::run(&pull_params) {
+        Err(error::Error::PatchHashMismatch(_,_)) => (),
+        Ok(_) => panic!("pulled a patch that does not match its hash"),
+        Err(e) => panic!("funky error when pulling a tampered patch: {}", e)
+    }
+    assert_eq!(fs::read_dir(patches_dir(&dir_b)).unwrap().count(), 0);
+}
+
+#[test]
+fn pull_from_named_remote() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let url = dir_a.to_str().unwrap();
+    let add_params = remotes::Params { repository : Some(&dir_b),
+                                       action : remotes::Action::Add { name : "origin",
+                                                                       url : url,
+                                                                       port : None,
+                                                                       default_branch : None } };
+    remotes::run(&add_params).unwrap();
+    assert!(remotes::run(&add_params).is_err());
+    let pull_params = pull_params(dir_b, "origin");
+    pull::run(&pull_params).unwrap();
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+    let remove_params = remotes::Params { repository : Some(&dir_b),
+                                          action : remotes::Action::Remove("origin") };
+    remotes::run(&remove_params).unwrap();
+    assert!(remotes::run(&remove_params).is_err());
+}
+
+#[test]
+fn dry_run_pull_changes_nothing() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"line\n");
+    let source = remote::parse_remote(dir_a.to_str().unwrap(), None, None);
+    let mut session = source.session().unwrap();
+    let pullable = session.pullable_patches(&dir_b, b"main", b"main").unwrap();
+    let (patches, simulation) = session.simulate_pull(&dir_b, &pullable, 2).unwrap();
+    assert_eq!(patches.len(), 1);
+    assert_eq!(simulation.changed, vec![Path::new("toto").to_path_buf()]);
+    assert!(simulation.conflicts.is_empty());
+    assert!(fs::metadata(&dir_b.join("toto")).is_err());
+    assert_eq!(fs::read_dir(&patches_dir(&dir_b)).unwrap().count(), 0);
+    let repo = Repository::new_readonly(&pristine_dir(&dir_b)).unwrap();
+    assert!(repo.applied_patches().is_empty());
+}
+
+#[test]
+fn push_only_to_current_branch() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let mut push_params = push_params(dir_a, dir_b.to_str().unwrap());
+    push_params.from_branch = Some("main");
+    push_params.to_branch = Some("other");
+    match push::run(&push_params) {
+        Err(error::Error::NotCurrentBranch(_, _)) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+    assert!(fs::metadata(&dir_b.join("toto")).is_err());
+    push_params.to_branch = Some("main");
+    push::run(&push_params).unwrap();
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+}
+
+#[test]
+fn dry_run_push_changes_nothing() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let mut push_params = push_params(dir_a, dir_b.to_str().unwrap());
+    push_params.set_default = true;
+    push_params.dry_run = true;
+    push::run(&push_params).unwrap();
+    assert!(fs::metadata(&dir_b.join("toto")).is_err());
+    assert_eq!(fs::read_dir(&patches_dir(&dir_b)).unwrap().count(), 0);
+    let repo = Repository::new_readonly(&pristine_dir(&dir_b)).unwrap();
+    assert!(repo.applied_patches().is_empty());
+    // The remote is not saved either.
+    assert!(Meta::load(&dir_a).map(|m| m.push.is_none()).unwrap_or(true));
+}
+
+#[test]
+fn pull_only_to_current_branch() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let source = remote::parse_remote(dir_a.to_str().unwrap(), None, None);
+    assert_eq!(source.session().unwrap().branches().unwrap(), vec![b"main".to_vec()]);
+    let mut pull_params = pull_params(dir_b, dir_a.to_str().unwrap());
+    pull_params.from_branch = Some("main");
+    pull_params.to_branch = Some("other");
+    match pull::run(&pull_params) {
+        Err(error::Error::NotCurrentBranch(_, _)) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+    pull_params.to_branch = Some("main");
+    pull::run(&pull_params).unwrap();
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+}
+
+#[test]
+fn push_without_credentials_is_unauthorized() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let serve_params = serve::Params { repository : Some(&dir_b),
+                                       address : "127.0.0.1:0",
+                                       allow_push : true,
+                                       token : Some("secret") };
+    let mut listening = serve::start(&serve_params).unwrap();
+    let uri = format!("http://{}", listening.socket);
+    let target = remote::parse_remote(&uri, None, None);
+    let result = {
+        let mut session = target.session().unwrap();
+        let pushable = session.pushable_patches(&dir_a, b"main", b"main").unwrap();
+        assert_eq!(pushable.len(), 1);
+        session.push(&dir_a, b"main", &pushable)
+    };
+    listening.close().unwrap();
+    match result {
+        Err(error::Error::HTTPUnauthorized(_)) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+}
+
+#[test]
+fn http_upload_and_apply() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let serve_params = serve::Params { repository : Some(&dir_b),
+                                       address : "127.0.0.1:0",
+                                       allow_push : true,
+                                       token : Some("secret") };
+    let mut listening = serve::start(&serve_params).unwrap();
+    let uri = format!("http://{}", listening.socket);
+    let config = http::Config::with_token(&uri, "secret").unwrap();
+    {
+        // A patch file whose name is not its hash.
+        let wrong_name = format!("{}/.pijul/patches/{}.cbor", uri, iter::repeat("00").take(64).collect::<String>());
+        match config.client().post(&wrong_name, b"not a patch") {
+            Err(error::Error::HTTPStatus(_, _, _)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        let mut session = remote::Session::Uri { uri : &uri, client : config.client() };
+        let pushable = session.pushable_patches(&dir_a, b"main", b"main").unwrap();
+        session.upload_patches(&dir_a, &pushable).unwrap();
+        // Uploaded, but not applied yet.
+        assert_eq!(fs::read_dir(&patches_dir(&dir_b)).unwrap().count(), 1);
+        assert!(fs::metadata(&dir_b.join("toto")).is_err());
+        let unknown:HashSet<Vec<u8>> = vec![vec![0;64]].into_iter().collect();
+        match session.remote_apply(b"main", &unknown) {
+            Err(error::Error::HTTPStatus(_, _, _)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        session.remote_apply(b"main", &pushable).unwrap();
+    }
+    listening.close().unwrap();
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+}
+
+#[test]
+fn push_to_http_server_with_token() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"");
+    let serve_params = serve::Params { repository : Some(&dir_b),
+                                       address : "127.0.0.1:0",
+                                       allow_push : true,
+                                       token : Some("secret") };
+    let mut listening = serve::start(&serve_params).unwrap();
+    let uri = format!("http://{}", listening.socket);
+    let push_with = |token:&str| {
+        let mut session = remote::Session::Uri { uri : &uri,
+                                                 client : http::Config::with_token(&uri, token).unwrap().client() };
+        let pushable = session.pushable_patches(&dir_a, b"main", b"main").unwrap();
+        session.push(&dir_a, b"main", &pushable)
+    };
+    let wrong = push_with("secreT");
+    let right = push_with("secret");
+    listening.close().unwrap();
+    match wrong {
+        Err(error::Error::HTTPUnauthorized(_)) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+    right.unwrap();
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+}
+
+#[test]
+fn clone_from_http_server() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    record_file(dir_a, "toto", b"");
+    let serve_params = serve::Params { repository : Some(&dir_a),
+                                       address : "127.0.0.1:0",
+                                       allow_push : false,
+                                       token : None };
+    let mut listening = serve::start(&serve_params).unwrap();
+    let uri = format!("http://{}", listening.socket);
+    let clone_params = clone::Params { from : &uri,
+                                       to : dir_b.to_str(),
+                                       port : None,
+                                       jobs : 2,
+                                       bare : false };
+    let result = clone::run(&clone_params);
+    listening.close().unwrap();
+    result.unwrap();
+    assert!(fs::metadata(&dir_b.join("toto")).unwrap().is_file());
+}
+
+#[test]
+fn host_key_checking() {
+    assert!(!remote::parse_strict_host_key_checking(None));
+    assert!(!remote::parse_strict_host_key_checking(Some("")));
+    assert!(!remote::parse_strict_host_key_checking(Some("no")));
+    assert!(!remote::parse_strict_host_key_checking(Some("0")));
+    assert!(remote::parse_strict_host_key_checking(Some("yes")));
+    assert!(remote::parse_strict_host_key_checking(Some("1")));
+    for &strict in [false, true].iter() {
+        for &interactive in [false, true].iter() {
+            assert_eq!(remote::host_key_action(remote::HostKey::Changed, strict, interactive),
+                       remote::HostKeyAction::RefuseChanged);
+            assert_eq!(remote::host_key_action(remote::HostKey::Known, strict, interactive),
+                       remote::HostKeyAction::Accept);
+        }
+    }
+    assert_eq!(remote::host_key_action(remote::HostKey::Unknown, true, true), remote::HostKeyAction::RefuseUnknown);
+    assert_eq!(remote::host_key_action(remote::HostKey::Unknown, false, false), remote::HostKeyAction::RefuseUnknown);
+    assert_eq!(remote::host_key_action(remote::HostKey::Unknown, false, true), remote::HostKeyAction::Ask);
+}
+
+#[test]
+fn proxy_settings() {
+    assert_eq!(http::parse_proxy("http://proxy.example.com:3128/"),
+               Some(http::Proxy { host : "proxy.example.com".to_string(), port : 3128, authorization : None }));
+    assert_eq!(http::parse_proxy("user:pw@proxy"),
+               Some(http::Proxy { host : "proxy".to_string(), port : 80, authorization : Some("Basic dXNlcjpwdw==".to_string()) }));
+    assert!(http::no_proxy_matches("localhost, .example.com", "pijul.example.com"));
+    assert!(http::no_proxy_matches("*", "pijul.org"));
+    assert!(!http::no_proxy_matches("example.com", "notexample.com"));
+}
+
+#[test]
+fn credentials_match_hosts() {
+    assert!(http::url_matches("https://example.com", "https://example.com/repo"));
+    assert!(http::url_matches("https://example.com/repo", "https://example.com:443/repo/.pijul"));
+    assert!(!http::url_matches("https://example.com", "https://example.com.evil.org/repo"));
+    assert!(!http::url_matches("https://example.com", "https://example.com@evil/repo"));
+    assert!(!http::url_matches("https://example.com", "http://example.com/repo"));
+    assert!(!http::url_matches("https://example.com", "https://example.com:8443/repo"));
+    assert!(!http::url_matches("https://example.com/repo", "https://example.com/repository"));
+    assert!(!http::url_matches("not a url", "https://example.com/repo"));
+}
+
+#[test]
+fn view_patch_shows_deleted_lines() {
+    use std::io::Write;
+    use std::collections::HashMap;
+    use self::libpijul::patch::Patch;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"a\nb\n");
+    let first = Repository::new_readonly(&pristine_dir(&dir_a)).unwrap().applied_patches();
+    fs::File::create(&dir_a.join("toto")).unwrap().write_all(b"a\n").unwrap();
+    record::run(&record_params(&dir_a, "second")).unwrap();
+    let hash = Repository::new_readonly(&pristine_dir(&dir_a)).unwrap().applied_patches()
+        .difference(&first).next().unwrap().clone();
+    let patch = Patch::from_repository(&dir_a, &hash).unwrap();
+    // The deleted line is found in the pristine.
+    let repo = Repository::new_readonly(&pristine_dir(&dir_a)).unwrap();
+    let text = String::from_utf8(ask::patch_text(&repo, &HashMap::new(), &hash, &patch).unwrap()).unwrap();
+    assert!(text.contains("  * second"));
+    assert!(text.contains("- b"));
+    // A repository without the first patch shows where the line was.
+    let repo = Repository::new_readonly(&pristine_dir(&dir_b)).unwrap();
+    let text = String::from_utf8(ask::patch_text(&repo, &HashMap::new(), &hash, &patch).unwrap()).unwrap();
+    assert!(text.contains(&format!("of patch {})", first.iter().next().unwrap().to_hex())));
+}
+
+#[test]
+fn split_change_depends_on_first_half() {
+    use std::io::{Read, Write};
+    use self::libpijul::patch::{Patch, Change};
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    let fpath = &dir_a.join("toto");
+    fs::File::create(&fpath).unwrap().write_all(b"a\nb\nc\nd\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir_a), touched_files : vec![&fpath] }).unwrap();
+    let mut repo = Repository::new(&pristine_dir(&dir_a)).unwrap();
+    let (mut changes, syncs) = repo.record(&dir_a).unwrap();
+    let i = changes.iter().position(|c| ask::split_change(c).is_some()).unwrap();
+    let (first, second) = ask::split_change(&changes.remove(i)).unwrap();
+    match first {
+        Change::NewNodes { ref down_context, .. } => assert!(down_context.is_empty()),
+        _ => panic!("split_change returned {:?}", first)
+    }
+    let second_nodes = match second {
+        Change::NewNodes { ref nodes, .. } => nodes.clone(),
+        _ => panic!("split_change returned {:?}", second)
+    };
+    changes.insert(i, first);
+    changes.insert(i + 1, second);
+    let (deps, _) = ask::record_dependencies(&changes);
+    assert!(deps.get(&(i + 1)).unwrap().contains(&i));
+    // Recording only the first half gives a valid patch, after which
+    // the second half is all that is left to record.
+    changes.remove(i + 1);
+    let patch = Patch::new(vec![], "first half".to_string(), None, 0, changes);
+    repo.apply_local_patch(&dir_a, patch, &syncs).unwrap();
+    repo.commit().unwrap();
+    let mut repo = Repository::new(&pristine_dir(&dir_a)).unwrap();
+    let (changes, syncs) = repo.record(&dir_a).unwrap();
+    assert_eq!(changes.len(), 1);
+    match changes[0] {
+        Change::NewNodes { ref nodes, .. } => assert_eq!(nodes, &second_nodes),
+        ref c => panic!("unexpected change {:?}", c)
+    }
+    // Recording the second half leaves nothing to record, and both
+    // halves give the whole file back.
+    let patch = Patch::new(vec![], "second half".to_string(), None, 0, changes);
+    repo.apply_local_patch(&dir_a, patch, &syncs).unwrap();
+    repo.commit().unwrap();
+    let mut repo = Repository::new(&pristine_dir(&dir_a)).unwrap();
+    let (changes, _) = repo.record(&dir_a).unwrap();
+    assert!(changes.is_empty());
+    let pull_params = pull_params(dir_b, dir_a.to_str().unwrap());
+    pull::run(&pull_params).unwrap();
+    let mut contents = String::new();
+    fs::File::open(&dir_b.join("toto")).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "a\nb\nc\nd\n");
+    let mut repo = Repository::new(&pristine_dir(&dir_b)).unwrap();
+    let (changes, _) = repo.record(&dir_b).unwrap();
+    assert!(changes.is_empty());
+}
+
+#[cfg(not(windows))]
+fn write_hook(repo: &Path, name: &str, script: &str) {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    let dir = hooks_dir(repo);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::File::create(&path).unwrap().write_all(script.as_bytes()).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+#[cfg(not(windows))]
+fn failing_pre_record_hook_cancels_record() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    let fpath = &dir.path().join("toto");
+    fs::File::create(&fpath).unwrap();
+    add::run(&add::Params { repository : Some(&dir.path()), touched_files : vec![&fpath] }).unwrap();
+    write_hook(&dir.path(), "pre-record", "#!/bin/sh\nexit 1\n");
+    write_hook(&dir.path(), "post-record", "#!/bin/sh\necho $1 > recorded\n");
+    let record_params = record_params(dir.path(), "toto");
+    match record::run(&record_params) {
+        Err(error::Error::HookFailed(_, Some(1))) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+    assert_eq!(fs::read_dir(&patches_dir(&dir.path())).unwrap().count(), 0);
+    assert!(fs::metadata(&dir.path().join("recorded")).is_err());
+    write_hook(&dir.path(), "pre-record", "#!/bin/sh\ngrep -q 'new file toto'\n");
+    record::run(&record_params).unwrap();
+    assert!(fs::metadata(&dir.path().join("recorded")).unwrap().len() > 0);
+}
+
+#[test]
+fn push_with_unreadable_policy_is_rejected() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    {
+        use std::io::Write;
+        let mut f = fs::File::create(&meta_file(&dir_b)).unwrap();
+        writeln!(f, "[policy\nrequire_signature = true").unwrap();
+    }
+    record_file(dir_a, "toto", b"");
+    let push_params = push_params(dir_a, dir_b.to_str().unwrap());
+    match push::run(&push_params) {
+        Err(error::Error::MetaDecoding) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+    assert!(fs::metadata(&dir_b.join("toto")).is_err());
+}
+
+#[test]
+fn push_to_protected_path_is_rejected() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    let mut meta = Meta::new();
+    meta.policy = Some(Policy { require_signature : None,
+                                allowed_signers : None,
+                                protected_paths : Some(vec!["secret".to_string()]),
+                                max_patch_size : None });
+    meta.save(&dir_b).unwrap();
+    record_file(dir_a, "secret", b"");
+    let push_params = push_params(dir_a, dir_b.to_str().unwrap());
+    match push::run(&push_params) {
+        Err(error::Error::PatchesRejected(ref reasons)) => assert_eq!(reasons.len(), 1),
+        r => panic!("unexpected result {:?}", r)
+    }
+    assert!(fs::metadata(&dir_b.join("secret")).is_err());
+}
+
+#[test]
+fn push_to_bare_repository() {
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, true);
+    record_file(dir_a, "toto", b"");
+    let push_params = push_params(dir_a, dir_b.to_str().unwrap());
+    push::run(&push_params).unwrap();
+    // The patch is applied to the pristine only.
+    assert!(fs::metadata(&dir_b.join("toto")).is_err());
+    let repo = Repository::new_readonly(&pristine_dir(&dir_b)).unwrap();
+    assert_eq!(repo.applied_patches().len(), 1);
+    let record_params = record_params(&dir_b, "nothing");
+    match record::run(&record_params) {
+        Err(error::Error::BareRepository(_)) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+}
+
+#[test]
+fn record_files_gives_the_file_of_each_change() {
+    use std::io::Write;
+    use std::path::PathBuf;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    fs::create_dir(&dir.path().join("d")).unwrap();
+    let fpath_a = &dir.path().join("a");
+    let fpath_b = &dir.path().join("d").join("b");
+    fs::File::create(&fpath_a).unwrap().write_all(b"a\n").unwrap();
+    fs::File::create(&fpath_b).unwrap().write_all(b"b\nb\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir.path()),
+                            touched_files : vec![&fpath_a, &dir.path().join("d"), &fpath_b] }).unwrap();
+    let mut repo = Repository::new(&pristine_dir(&dir.path())).unwrap();
+    let (changes, files, _) = repo.record_files(&dir.path()).unwrap();
+    assert_eq!(changes.len(), files.len());
+    let files: HashSet<PathBuf> = files.into_iter().collect();
+    let expected: HashSet<PathBuf> = vec![PathBuf::from("a"), PathBuf::from("d"), Path::new("d").join("b")].into_iter().collect();
+    assert_eq!(files, expected);
+}
+
+#[test]
+#[cfg(not(windows))]
+fn record_with_editor() {
+    use std::io::Write;
+    use self::libpijul::patch::{Patch, Value};
+    use self::libpijul::fs_representation::patch_hash_of_file_name;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    let fpath = &dir.path().join("toto");
+    fs::File::create(&fpath).unwrap().write_all(b"toto\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir.path()), touched_files : vec![&fpath] }).unwrap();
+    let script = dir.path().join("edit.sh");
+    fs::File::create(&script).unwrap()
+        .write_all(b"sed -e 's/^name:.*/name: edited/' -e 's/^description:.*/description: fixed it/' \"$1\" > \"$1.new\" && mv \"$1.new\" \"$1\"\n")
+        .unwrap();
+    let editor = format!("sh {}", script.display());
+    // The author's email is not shown in the editor, but is kept.
+    let mut meta = Meta::new();
+    let mut author = ::std::collections::BTreeMap::new();
+    author.insert("name".to_string(), Value::String("me".to_string()));
+    author.insert("email".to_string(), Value::String("me@example.com".to_string()));
+    meta.default_authors = vec![author.clone()];
+    meta.save(&dir.path()).unwrap();
+    let mut record_params = record_params(dir.path(), "toto");
+    record_params.yes_to_all = false;
+    record_params.edit = true;
+    record_params.editor = Some(&editor[..]);
+    record_params.authors = None;
+    record::run(&record_params).unwrap();
+    let hash = fs::read_dir(&patches_dir(&dir.path())).unwrap()
+        .filter_map(|f| patch_hash_of_file_name(f.unwrap().file_name().to_str().unwrap()))
+        .next().unwrap();
+    let patch = Patch::from_repository(&dir.path(), &hash).unwrap();
+    assert_eq!(patch.name, "edited");
+    assert_eq!(patch.description, Some("fixed it".to_string()));
+    assert_eq!(patch.authors, vec![author]);
+    assert!(patch.changes.len() > 0);
+}
+
+#[test]
+fn record_with_message_file() {
+    use std::io::Write;
+    use self::libpijul::patch::Patch;
+    use self::libpijul::fs_representation::patch_hash_of_file_name;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    let fpath = &dir.path().join("toto");
+    fs::File::create(&fpath).unwrap().write_all(b"toto\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir.path()), touched_files : vec![&fpath] }).unwrap();
+    let message = dir.path().join("message");
+    fs::File::create(&message).unwrap()
+        .write_all(b"# a comment\n\nAdd toto\n\nThe first file\nof the repository.\n")
+        .unwrap();
+    let mut record_params = record_params(dir.path(), "");
+    record_params.patch_name = None;
+    record_params.message_file = Some(message.to_str().unwrap());
+    record_params.authors = Some(vec!["me"]);
+    record::run(&record_params).unwrap();
+    let hash = fs::read_dir(&patches_dir(&dir.path())).unwrap()
+        .filter_map(|f| patch_hash_of_file_name(f.unwrap().file_name().to_str().unwrap()))
+        .next().unwrap();
+    let patch = Patch::from_repository(&dir.path(), &hash).unwrap();
+    assert_eq!(patch.name, "Add toto");
+    assert_eq!(patch.description, Some("The first file\nof the repository.".to_string()));
+}
+
+#[test]
+fn record_only_the_given_paths() {
+    use std::io::Write;
+    use std::path::PathBuf;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    fs::create_dir(&dir.path().join("d")).unwrap();
+    let fpath_a = &dir.path().join("a");
+    let fpath_b = &dir.path().join("d").join("b");
+    fs::File::create(&fpath_a).unwrap().write_all(b"a\n").unwrap();
+    fs::File::create(&fpath_b).unwrap().write_all(b"b\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir.path()),
+                            touched_files : vec![&fpath_a, &dir.path().join("d"), &fpath_b] }).unwrap();
+    let mut record_params = record_params(dir.path(), "d");
+    record_params.prefixes = vec![Path::new("d")];
+    record_params.authors = Some(vec!["me"]);
+    record::run(&record_params).unwrap();
+    let mut repo = Repository::new(&pristine_dir(&dir.path())).unwrap();
+    let (_, files, _) = repo.record_files(&dir.path()).unwrap();
+    let files: HashSet<PathBuf> = files.into_iter().collect();
+    let expected: HashSet<PathBuf> = vec![PathBuf::from("a")].into_iter().collect();
+    assert_eq!(files, expected);
+}
+
+#[test]
+fn amend_last_patch() {
+    use std::io::{Read, Write};
+    use self::libpijul::patch::Patch;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    let fpath = &dir.path().join("toto");
+    fs::File::create(&fpath).unwrap().write_all(b"a\nb\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir.path()), touched_files : vec![&fpath] }).unwrap();
+    let mut record_params = record_params(dir.path(), "first");
+    record_params.authors = Some(vec!["me"]);
+    record::run(&record_params).unwrap();
+    let old = Repository::new_readonly(&pristine_dir(&dir.path())).unwrap().applied_patches();
+    // Change a line of the patch, and add a file.
+    fs::File::create(&fpath).unwrap().write_all(b"a\nc\n").unwrap();
+    let fpath_ = &dir.path().join("titi");
+    fs::File::create(&fpath_).unwrap().write_all(b"d\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir.path()), touched_files : vec![&fpath_] }).unwrap();
+    amend::run(&amend::Params { repository : Some(&dir.path()),
+                                patch_name : Some("second"),
+                                description : None,
+                                authors : None,
+                                force : false }).unwrap();
+    let mut repo = Repository::new(&pristine_dir(&dir.path())).unwrap();
+    let applied = repo.applied_patches();
+    assert_eq!(applied.len(), 1);
+    assert!(applied.is_disjoint(&old));
+    let hash = applied.iter().next().unwrap();
+    let patch = Patch::from_repository(&dir.path(), hash).unwrap();
+    assert_eq!(patch.name, "second");
+    assert_eq!(patch.authors.len(), 1);
+    // The pristine now has the changes of the working copy.
+    let (changes, _) = repo.record(&dir.path()).unwrap();
+    assert!(changes.is_empty());
+    let mut contents = String::new();
+    fs::File::open(&fpath).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "a\nc\n");
+}
+
+#[test]
+fn amend_picks_last_applied_patch() {
+    use std::io::Write;
+    use self::libpijul::patch::Patch;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    record_file(dir.path(), "toto", b"a\n");
+    let first = Repository::new_readonly(&pristine_dir(&dir.path())).unwrap().applied_patches();
+    // The second patch is older than the first one, but applied after it.
+    let fpath_ = &dir.path().join("titi");
+    fs::File::create(&fpath_).unwrap().write_all(b"b\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir.path()), touched_files : vec![&fpath_] }).unwrap();
+    {
+        let mut repo = Repository::new(&pristine_dir(&dir.path())).unwrap();
+        let (changes, syncs) = repo.record(&dir.path()).unwrap();
+        let patch = Patch::new(vec![], "second".to_string(), None, 0, changes);
+        repo.apply_local_patch(&dir.path(), patch, &syncs).unwrap();
+        repo.commit().unwrap();
+    }
+    amend::run(&amend::Params { repository : Some(&dir.path()),
+                                patch_name : Some("amended"),
+                                description : None,
+                                authors : None,
+                                force : false }).unwrap();
+    let repo = Repository::new_readonly(&pristine_dir(&dir.path())).unwrap();
+    let applied = repo.applied_patches_in_order().unwrap();
+    assert_eq!(applied.len(), 2);
+    assert!(first.contains(&applied[0]));
+    assert_eq!(Patch::from_repository(&dir.path(), &applied[1]).unwrap().name, "amended");
+}
+
+#[test]
+fn amend_fails_on_unreachable_remotes() {
+    use std::io::Write;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    init_repo(dir.path(), false);
+    record_file(dir.path(), "toto", b"a\n");
+    let fpath = &dir.path().join("toto");
+    let mut meta = Meta::load(&dir.path()).unwrap_or(Meta::new());
+    meta.pull = Some(RemoteRepository::String("http://127.0.0.1:1/".to_string()));
+    meta.save(&dir.path()).unwrap();
+    fs::File::create(&fpath).unwrap().write_all(b"b\n").unwrap();
+    match amend::run(&amend::Params { repository : Some(&dir.path()),
+                                      patch_name : None,
+                                      description : None,
+                                      authors : None,
+                                      force : false }) {
+        Err(error::Error::CannotAmend(_)) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+    amend::run(&amend::Params { repository : Some(&dir.path()),
+                                patch_name : None,
+                                description : None,
+                                authors : None,
+                                force : true }).unwrap();
+    let mut repo = Repository::new(&pristine_dir(&dir.path())).unwrap();
+    let (changes, _) = repo.record(&dir.path()).unwrap();
+    assert!(changes.is_empty());
+}
+
+#[test]
+fn pull_amended_patch() {
+    use std::io::{Read, Write};
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    record_file(dir_a, "toto", b"a\nb\n");
+    let fpath = &dir_a.join("toto");
+    // The amended patch deletes one of its own lines, and adds a file.
+    fs::File::create(&fpath).unwrap().write_all(b"a\nc\n").unwrap();
+    let fpath_ = &dir_a.join("titi");
+    fs::File::create(&fpath_).unwrap().write_all(b"d\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir_a), touched_files : vec![&fpath_] }).unwrap();
+    amend::run(&amend::Params { repository : Some(&dir_a),
+                                patch_name : None,
+                                description : None,
+                                authors : None,
+                                force : false }).unwrap();
+    let pull_params = pull_params(dir_b, dir_a.to_str().unwrap());
+    pull::run(&pull_params).unwrap();
+    assert_eq!(Repository::new_readonly(&pristine_dir(&dir_b)).unwrap().applied_patches(),
+               Repository::new_readonly(&pristine_dir(&dir_a)).unwrap().applied_patches());
+    let mut contents = String::new();
+    fs::File::open(&dir_b.join("toto")).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "a\nc\n");
+    let mut contents = String::new();
+    fs::File::open(&dir_b.join("titi")).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "d\n");
+    {
+        let mut repo = Repository::new(&pristine_dir(&dir_b)).unwrap();
+        let (changes, _) = repo.record(&dir_b).unwrap();
+        assert!(changes.is_empty());
+    }
+    // The pulled patch was not recorded in dir_b.
+    match amend::run(&amend::Params { repository : Some(&dir_b),
+                                      patch_name : Some("pulled"),
+                                      description : None,
+                                      authors : None,
+                                      force : true }) {
+        Err(error::Error::CannotAmend(_)) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+}
+
+#[test]
+fn amend_refuses_pushed_patch() {
+    use std::io::Write;
+    let dir = tempdir::TempDir::new("pijul").unwrap();
+    let dir_a = &dir.path().join("a");
+    let dir_b = &dir.path().join("b");
+    init_repo(dir_a, false);
+    init_repo(dir_b, false);
+    let fpath = &dir_a.join("toto");
+    fs::File::create(&fpath).unwrap().write_all(b"a\n").unwrap();
+    add::run(&add::Params { repository : Some(&dir_a), touched_files : vec![&fpath] }).unwrap();
+    let mut record_params = record_params(&dir_a, "toto");
+    record_params.authors = Some(vec!["me"]);
+    record::run(&record_params).unwrap();
+    let mut push_params = push_params(dir_a, dir_b.to_str().unwrap());
+    push_params.set_default = true;
+    push::run(&push_params).unwrap();
+    fs::File::create(&fpath).unwrap().write_all(b"b\n").unwrap();
+    match amend::run(&amend::Params { repository : Some(&dir_a),
+                                      patch_name : None,
+                                      description : None,
+                                      authors : None,
+                                      force : false }) {
+        Err(error::Error::CannotAmend(_)) => {},
+        r => panic!("unexpected result {:?}", r)
+    }
+}
@@ -70,6 +70,7 @@ fn main() {
                                "init" => init,
                                "add" => add,
                                "record" => record,
+                               "amend" => amend,
                                "pull" => pull,
                                "push" => push,
                                "apply" => apply,
@@ -77,7 +78,11 @@ fn main() {
                                "remove" => remove,
                                "mv" => mv,
                                "ls" => ls,
-                               "revert" => revert
+                               "revert" => revert,
+                               "gc" => gc,
+                               "serve" => serve,
+                               "protocol" => protocol,
+                               "remote" => remotes
                                );
     let time1=time::precise_time_s();
     info!(target:"pijul","whole command took: {}", time1-time0);
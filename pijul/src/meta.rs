@@ -24,7 +24,7 @@ use std::path::Path;
 use std::collections::BTreeMap;
 use commands::error::Error;
 use std::fs::File;
-use std::io::{Read,Write};
+use std::io::{Read,Write,ErrorKind};
 extern crate rustc_serialize;
 use self::rustc_serialize::Encodable;
 #[derive(Debug,RustcEncodable,RustcDecodable)]
@@ -34,11 +34,43 @@ pub enum Repository {
 }
 
 
+/// A named remote, as in the `[remotes.<name>]` sections of `meta.toml`.
+#[derive(Debug,Clone,RustcEncodable,RustcDecodable)]
+pub struct RemoteConfig {
+    pub url:String,
+    /// Port of the SSH server, if not the default one.
+    pub port:Option<u64>,
+    /// Branch to pull from and push to, if not the current one.
+    pub default_branch:Option<String>
+}
+
+/// Checks on the patches pushed to this repository, in the
+/// `[policy]` section of `meta.toml`.
+#[derive(Debug,Clone,RustcEncodable,RustcDecodable)]
+pub struct Policy {
+    /// Reject patches that are not signed with gpg, or whose signature
+    /// cannot be checked with the keyring of the user applying them.
+    pub require_signature:Option<bool>,
+    /// Fingerprints of the gpg keys whose signatures are accepted.
+    /// Without this list, a signature by any key of the keyring is
+    /// accepted. Implies `require_signature`.
+    pub allowed_signers:Option<Vec<String>>,
+    /// Reject patches changing files under these paths, relative to
+    /// the root of the repository.
+    pub protected_paths:Option<Vec<String>>,
+    /// Reject patch files larger than this, in bytes.
+    pub max_patch_size:Option<u64>
+}
+
 #[derive(Debug,RustcEncodable,RustcDecodable)]
 pub struct Meta {
     pub default_authors:Vec<BTreeMap<String,Value>>,
     pub pull:Option<Repository>,
-    pub push:Option<Repository>
+    pub push:Option<Repository>,
+    pub remotes:Option<BTreeMap<String,RemoteConfig>>,
+    pub policy:Option<Policy>,
+    /// Initial text of the messages of new patches, edited by `record`.
+    pub message_template:Option<String>
 }
 
 impl Meta {
@@ -53,8 +85,32 @@ impl Meta {
             None=>Err(Error::MetaDecoding)
         }
     }
+    /// Same as `load`, but `None` if the repository has no meta.toml.
+    pub fn load_if_exists(r:&Path) -> Result<Option<Meta>,Error> {
+        match Meta::load(r) {
+            Ok(m)=>Ok(Some(m)),
+            Err(Error::IO(ref e)) if e.kind()==ErrorKind::NotFound=>Ok(None),
+            Err(e)=>Err(e)
+        }
+    }
     pub fn new()->Meta {
-        Meta { default_authors:Vec::new(),pull:None,push:None }
+        Meta { default_authors:Vec::new(),pull:None,push:None,remotes:None,policy:None,message_template:None }
+    }
+    /// The remote named `name`, if there is one.
+    pub fn remote(&self,name:&str)->Option<&RemoteConfig> {
+        self.remotes.as_ref().and_then(|r| r.get(name))
+    }
+    /// The default branch of the remote named `remote_id`, if any.
+    pub fn default_branch(&self,remote_id:&str)->Option<&str> {
+        self.remote(remote_id).and_then(|r| r.default_branch.as_ref()).map(|b| &b[..])
+    }
+    /// If `remote_id` is the name of a remote, its URL and port (unless
+    /// `port` is given), else `remote_id` and `port`.
+    pub fn resolve_remote<'a>(&'a self,remote_id:&'a str,port:Option<u64>)->(&'a str,Option<u64>) {
+        match self.remote(remote_id) {
+            Some(remote)=>(&remote.url,port.or(remote.port)),
+            None=>(remote_id,port)
+        }
     }
     pub fn save(self,r:&Path)->Result<(),Error> {
         let mut f=try!(File::create(meta_file(r)));